@@ -2,6 +2,7 @@ use anyhow::Result;
 use clap::{Arg, Command};
 // If you have a lib target (src/lib.rs with `pub mod csv_cluster;`)
 use candy_picker_rs::csv_cluster::cluster_csv_multi;
+use candy_picker_rs::provenance::provenance_line;
 // If you *don’t* have src/lib.rs, instead do:
 // use crate::csv_cluster::cluster_csv_multi;
 
@@ -19,6 +20,17 @@ fn main() -> Result<()> {
              .help("Disable harmonic matching"))
         .arg(Arg::new("tobs").long("tobs").help("Optional TOBS (s) for acceleration correction"))
         .arg(Arg::new("source_col").long("source-col").help("Append a column with the source filename"))
+        .arg(Arg::new("group_col").long("group-col")
+             .help("Cluster rows separately per value of this column; the --source-col name groups by source file"))
+        .arg(Arg::new("rank_col").long("rank-col").help("Rank pivots by this column instead of the detected SNR column"))
+        .arg(Arg::new("period_split").long("period-split").action(clap::ArgAction::SetTrue)
+             .help("Split the output by period decade into <stem>_1ms-10ms.csv etc. (lower bound inclusive)"))
+        .arg(Arg::new("read_retries").long("read-retries").default_value("0")
+             .help("Retry input reads up to N times with exponential backoff on transient IO errors"))
+        .arg(Arg::new("strict_csv").long("strict-csv").action(clap::ArgAction::SetTrue)
+             .help("Fail when an input's header differs from the first file's (names or order)"))
+        .arg(Arg::new("embed_provenance").long("embed-provenance").action(clap::ArgAction::SetTrue)
+             .help("Write a '# provenance:' comment line (tool, version, UTC time, command line) above the output header"))
         .get_matches();
 
     let inputs: Vec<String> = matches
@@ -33,6 +45,12 @@ fn main() -> Result<()> {
     let allow_harmonics = !matches.get_flag("no_harmonics");
     let tobs = matches.get_one::<String>("tobs").and_then(|s| s.parse::<f64>().ok());
     let source_col = matches.get_one::<String>("source_col").map(|s| s.as_str());
+    let group_col = matches.get_one::<String>("group_col").map(|s| s.as_str());
+    let rank_col = matches.get_one::<String>("rank_col").map(|s| s.as_str());
+    let period_split = matches.get_flag("period_split");
+    let strict_csv = matches.get_flag("strict_csv");
+    let read_retries = matches.get_one::<String>("read_retries").unwrap().parse::<u32>()?;
+    let provenance = matches.get_flag("embed_provenance").then(|| provenance_line("csv_candypicker"));
 
     cluster_csv_multi(
         &inputs,
@@ -43,5 +61,11 @@ fn main() -> Result<()> {
         allow_harmonics,
         tobs,
         source_col,
+        group_col,
+        rank_col,
+        period_split,
+        read_retries,
+        strict_csv,
+        provenance.as_deref(),
     )
 }
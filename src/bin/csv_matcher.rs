@@ -1,8 +1,16 @@
 use anyhow::{anyhow, Context, Result};
+use candy_picker_rs::csv_cluster::snr_column;
+use candy_picker_rs::provenance::{provenance_line, skip_provenance};
+use candy_picker_rs::retry::{open_with_retries, read_with_retries};
 use clap::{Arg, ArgAction, Command};
 use csv::{ReaderBuilder, StringRecord, WriterBuilder};
 use std::collections::HashMap;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const PERIOD_COLS: [&str; 7] = ["p0_new","period","p0","p","p_sec","per","per_s"];
+const F0_COLS: [&str; 5] = ["f0_opt","f0_new","f0","freq","frequency_hz"];
 
 /// Extract numeric with tolerant parsing (empty -> None).
 fn parse_f64_opt(s: &str) -> Option<f64> {
@@ -27,7 +35,7 @@ fn header_index_map(header: &StringRecord) -> HashMap<String, usize> {
 }
 
 /// Find first present header among candidates (case-insensitive)
-fn find_col<'a>(hmap: &'a HashMap<String, usize>, candidates: &[&str]) -> Option<usize> {
+fn find_col(hmap: &HashMap<String, usize>, candidates: &[&str]) -> Option<usize> {
     for c in candidates {
         if let Some(&idx) = hmap.get(&c.to_ascii_lowercase()) {
             return Some(idx);
@@ -40,15 +48,13 @@ fn find_col<'a>(hmap: &'a HashMap<String, usize>, candidates: &[&str]) -> Option
 /// Returns (period_seconds, index_of_source_column_used) if found.
 fn extract_period_indices(hmap: &HashMap<String, usize>, row: &StringRecord) -> Option<(f64, usize)> {
     // Try period-like columns
-    let period_cols = ["p0_new","period","p0","p","p_sec","per","per_s"];
-    if let Some(idx) = find_col(hmap, &period_cols) {
+    if let Some(idx) = find_col(hmap, &PERIOD_COLS) {
         if let Some(v) = row.get(idx).and_then(parse_f64_opt) {
             return Some((v, idx));
         }
     }
     // Try f0 columns (frequency Hz) -> period = 1/f0
-    let f0_cols = ["f0_opt","f0_new","f0","freq","frequency_hz"];
-    if let Some(idx) = find_col(hmap, &f0_cols) {
+    if let Some(idx) = find_col(hmap, &F0_COLS) {
         if let Some(v) = row.get(idx).and_then(parse_f64_opt) {
             if v != 0.0 { return Some((1.0 / v, idx)); }
         }
@@ -56,6 +62,17 @@ fn extract_period_indices(hmap: &HashMap<String, usize>, row: &StringRecord) ->
     None
 }
 
+/// True when a row carries both a period and an f0 column and they disagree by more than
+/// `rel_tol` (relative to the period). Rows missing either value are never flagged.
+fn period_f0_inconsistent(hmap: &HashMap<String, usize>, row: &StringRecord, rel_tol: f64) -> bool {
+    let period = find_col(hmap, &PERIOD_COLS).and_then(|i| row.get(i)).and_then(parse_f64_opt);
+    let f0 = find_col(hmap, &F0_COLS).and_then(|i| row.get(i)).and_then(parse_f64_opt);
+    match (period, f0) {
+        (Some(p), Some(f)) if p != 0.0 && f != 0.0 => ((p - 1.0 / f) / p).abs() > rel_tol,
+        _ => false,
+    }
+}
+
 /// DM extraction: return (dm, idx) if present
 fn extract_dm(hmap: &HashMap<String, usize>, row: &StringRecord) -> Option<(f64, usize)> {
     let dm_cols = ["dm_new","dm_opt","dm","refdm"];
@@ -92,12 +109,15 @@ struct FileData {
     hmap: HashMap<String, usize>,
 }
 
-fn read_csv(path: &Path) -> Result<FileData> {
+fn read_csv(path: &Path, read_retries: u32) -> Result<FileData> {
+    let bytes = read_with_retries(path, read_retries)
+        .with_context(|| format!("opening CSV {}", path.display()))?;
+    let mut data = bytes.as_slice();
+    skip_provenance(&mut data)?;
     let mut rdr = ReaderBuilder::new()
         .has_headers(true)
         .flexible(true) // tolerate different row lengths
-        .from_path(path)
-        .with_context(|| format!("opening CSV {}", path.display()))?;
+        .from_reader(data);
 
     let header = rdr
         .headers()
@@ -120,40 +140,477 @@ fn read_csv(path: &Path) -> Result<FileData> {
 }
 
 /// Absolute tolerance check with optional harmonics.
-/// Returns true if |p1 - p2| <= ptol OR there exists k in [2..=hmax] with
-/// |p1 - k*p2| <= ptol OR |p2 - k*p1| <= ptol (when harmonics=true).
-fn periods_match_abs(p1: f64, p2: f64, ptol: f64, harmonics: bool, hmax: u32) -> bool {
+/// Returns Some(1) if |p1 - p2| <= ptol, else (when harmonics=true) the smallest k in
+/// [2..=hmax] with |p1 - k*p2| <= ptol OR |p2 - k*p1| <= ptol, else None.
+fn periods_match_abs(p1: f64, p2: f64, ptol: f64, harmonics: bool, hmax: u32) -> Option<u32> {
     if (p1 - p2).abs() <= ptol {
-        return true;
+        return Some(1);
     }
     if harmonics {
         for k in 2..=hmax {
             let kf = k as f64;
-            if (p1 - kf * p2).abs() <= ptol { return true; }
-            if (p2 - kf * p1).abs() <= ptol { return true; }
+            if (p1 - kf * p2).abs() <= ptol { return Some(k); }
+            if (p2 - kf * p1).abs() <= ptol { return Some(k); }
         }
     }
-    false
+    None
 }
 
 /// DM/ACC absolute tolerance check; if tol None -> ignore dimension.
-/// If tol Some(t), both sides must be present and |Δ| <= t.
-fn dim_match_abs(a: Option<f64>, b: Option<f64>, tol: &Option<f64>) -> bool {
+/// If tol Some(t) and both sides are present, |Δ| <= t. If either side is missing the gate
+/// fails (strict) or passes (lenient).
+fn dim_match_abs(a: Option<f64>, b: Option<f64>, tol: &Option<f64>, lenient: bool) -> bool {
     match tol {
         None => true,
         Some(t) => match (a, b) {
             (Some(x), Some(y)) => (x - y).abs() <= *t,
-            _ => false,
+            _ => lenient,
         },
     }
 }
 
+/// Collapse rows whose requested keys agree after quantizing to multiples of `tol`,
+/// keeping the first occurrence. Returns the number of rows dropped.
+fn dedup_rows(selected: &mut Vec<usize>, all_rows: &[RowRef], keys: &[String], tol: f64) -> usize {
+    let q = |v: Option<f64>| v.map(|x| (x / tol).round() as i64);
+    let mut seen = std::collections::HashSet::new();
+    let before = selected.len();
+    selected.retain(|&gidx| {
+        let rr = &all_rows[gidx];
+        let key: Vec<Option<i64>> = keys
+            .iter()
+            .map(|k| match k.as_str() {
+                "period" => q(rr.period),
+                "dm" => q(rr.dm),
+                _ => q(rr.acc),
+            })
+            .collect();
+        seen.insert(key)
+    });
+    before - selected.len()
+}
+
+/// Keep at most `max` of a file's selected rows: the highest by `rank_idx` (unparsable values
+/// rank last), or the first `max` when there is no ranking column. The kept rows stay in file
+/// order. Returns the number of rows dropped.
+fn cap_rows(selected: &mut Vec<usize>, all_rows: &[RowRef], f: &FileData, rank_idx: Option<usize>, max: usize) -> usize {
+    let before = selected.len();
+    if before <= max {
+        return 0;
+    }
+    if let Some(idx) = rank_idx {
+        let rank = |gidx: usize| {
+            f.rows[all_rows[gidx].row_idx].get(idx).and_then(parse_f64_opt).unwrap_or(f64::NEG_INFINITY)
+        };
+        let mut by_rank = selected.clone();
+        // Stable, so equal ranks keep file order.
+        by_rank.sort_by(|&a, &b| rank(b).total_cmp(&rank(a)));
+        let keep: std::collections::HashSet<usize> = by_rank[..max].iter().copied().collect();
+        selected.retain(|g| keep.contains(g));
+    } else {
+        selected.truncate(max);
+    }
+    before - selected.len()
+}
+
+/// Parse `--ptol-per-file` entries `name=tol,name=tol`. A name matches an input either by the
+/// path as given on the command line or by its file name.
+fn parse_ptol_per_file(spec: &str, inputs: &[String]) -> Result<Vec<Option<f64>>> {
+    let mut out = vec![None; inputs.len()];
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, tol) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("--ptol-per-file entry '{}' is not name=tol", entry))?;
+        let tol: f64 = tol.trim().parse().with_context(|| format!("parsing --ptol-per-file entry '{}'", entry))?;
+        let name = name.trim();
+        let mut hit = false;
+        for (i, p) in inputs.iter().enumerate() {
+            let file_name = Path::new(p).file_name().map(|f| f.to_string_lossy().into_owned());
+            if p == name || file_name.as_deref() == Some(name) {
+                out[i] = Some(tol);
+                hit = true;
+            }
+        }
+        if !hit {
+            return Err(anyhow!("--ptol-per-file entry '{}' matches no input", entry));
+        }
+    }
+    Ok(out)
+}
+
+/// Throttled scan progress on stderr: checked every PROGRESS_EVERY comparisons, printed at
+/// most once per PROGRESS_INTERVAL.
+struct Progress {
+    unit: &'static str,
+    total_rows: usize,
+    rows_done: usize,
+    comparisons: u64,
+    start: Instant,
+    last_print: Instant,
+    quiet: bool,
+}
+
+const PROGRESS_EVERY: u64 = 250_000;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+impl Progress {
+    fn new(unit: &'static str, total_rows: usize, quiet: bool) -> Self {
+        let now = Instant::now();
+        Progress { unit, total_rows, rows_done: 0, comparisons: 0, start: now, last_print: now, quiet }
+    }
+
+    fn row(&mut self, gidx: usize) {
+        self.rows_done = gidx;
+    }
+
+    fn comparison(&mut self) {
+        self.comparisons += 1;
+        if self.quiet || !self.comparisons.is_multiple_of(PROGRESS_EVERY) || self.last_print.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_print = Instant::now();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let frac = self.rows_done as f64 / self.total_rows.max(1) as f64;
+        let eta = if frac > 0.0 { elapsed * (1.0 - frac) / frac } else { f64::NAN };
+        eprintln!(
+            "[PROGRESS] {}/{} {} ({:.1}%), {} comparisons, elapsed {:.0}s, ETA {:.0}s",
+            self.rows_done,
+            self.total_rows,
+            self.unit,
+            100.0 * frac,
+            self.comparisons,
+            elapsed,
+            eta
+        );
+    }
+
+    fn finish(&self) {
+        if !self.quiet {
+            eprintln!(
+                "[PROGRESS] scanned {} {}, {} comparisons in {:.1}s",
+                self.total_rows,
+                self.unit,
+                self.comparisons,
+                self.start.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Output path next to the input: `suffix` is inserted before the input's extension.
+fn suffixed_path(p: &Path, suffix: &str) -> PathBuf {
+    let stem = p.file_name().unwrap_or_else(|| std::ffi::OsStr::new("out.csv")).to_string_lossy();
+    let stem_s = stem.to_string();
+    // naive suffix add before extension
+    let out = if let Some((base, ext)) = stem_s.rsplit_once('.') {
+        format!("{}{}.{ext}", base, suffix)
+    } else {
+        format!("{}{}", stem_s, suffix)
+    };
+    p.with_file_name(out)
+}
+
+/// Write the header of `f` followed by the given rows (global indices) of `f`. With
+/// `harmonic_k`, a `harmonic_k` column is appended; with `provenance`, that comment line
+/// comes first.
+fn write_rows(
+    out_path: &Path,
+    f: &FileData,
+    all_rows: &[RowRef],
+    gidxs: &[usize],
+    harmonic_k: Option<&[u32]>,
+    provenance: Option<&str>,
+) -> Result<()> {
+    let mut file = std::fs::File::create(out_path)
+        .with_context(|| format!("creating {}", out_path.display()))?;
+    if let Some(line) = provenance {
+        writeln!(file, "{line}")?;
+    }
+    let mut w = WriterBuilder::new().from_writer(file);
+    match harmonic_k {
+        None => {
+            w.write_record(&f.header)?;
+            for &gidx in gidxs {
+                w.write_record(&f.rows[all_rows[gidx].row_idx])?;
+            }
+        }
+        Some(ks) => {
+            let mut hdr = f.header.clone();
+            hdr.push_field("harmonic_k");
+            w.write_record(&hdr)?;
+            for &gidx in gidxs {
+                let mut rec = f.rows[all_rows[gidx].row_idx].clone();
+                let k = ks[gidx];
+                rec.push_field(&if k == 0 { String::new() } else { k.to_string() });
+                w.write_record(&rec)?;
+            }
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
 /// Build buckets for absolute tolerance to prune comparisons.
 /// bucket = floor(p / ptol)
 fn bucket_abs(p: f64, ptol: f64) -> i64 {
     (p / ptol).floor() as i64
 }
 
+/// Largest |p / ptol| bucketed; beyond 2^53 bucket numbers are no longer exact and the
+/// harmonic neighbours (up to hmax times larger) would saturate the i64 cast.
+const MAX_BUCKET: f64 = 9_007_199_254_740_992.0;
+
+/// Whether `p` can be bucketed: finite (a malformed f0 such as 1e-300 gives 1/f0 = 1e300 or
+/// inf) and within MAX_BUCKET buckets of zero.
+fn bucketable(p: f64, ptol: f64) -> bool {
+    p.is_finite() && (p / ptol).abs() < MAX_BUCKET
+}
+
+/// Matching settings shared by the in-memory and streaming scans.
+struct MatchOpts {
+    file_ptol: Vec<f64>, // per input, indexed by file_id
+    bucket_w: f64,
+    dmtol: Option<f64>,
+    acctol: Option<f64>,
+    lenient_dims: bool,
+    harmonics: bool,
+    hmax: u32,
+}
+
+impl MatchOpts {
+    /// Smallest harmonic factor linking two rows (of different files), or None if they do not match.
+    fn match_k(&self, a: &RowRef, b: &RowRef) -> Option<u32> {
+        let pair_ptol = self.file_ptol[a.file_id].max(self.file_ptol[b.file_id]);
+        let k = periods_match_abs(a.period?, b.period?, pair_ptol, self.harmonics, self.hmax)?;
+        if !dim_match_abs(a.dm, b.dm, &self.dmtol, self.lenient_dims) { return None; }
+        if !dim_match_abs(a.acc, b.acc, &self.acctol, self.lenient_dims) { return None; }
+        Some(k)
+    }
+
+    /// Buckets that may hold a match for period `p`: its own bucket +/- 1 and, with harmonics,
+    /// the buckets around k*p and p/k for k = 2..=hmax. Computed from `p` itself rather than
+    /// its bucket, so the set is complete from either side of a pair (--stream looks up each
+    /// pair from the streamed row only).
+    fn neighbor_buckets(&self, p: f64) -> Vec<i64> {
+        let b0 = bucket_abs(p, self.bucket_w);
+        let mut out = vec![b0 - 1, b0, b0 + 1]; // same bucket +/- 1 for boundary effects
+        if self.harmonics {
+            for k in 2..=self.hmax {
+                let kf = k as f64;
+                for hk_b in [bucket_abs(p * kf, self.bucket_w), bucket_abs(p / kf, self.bucket_w)] {
+                    out.extend_from_slice(&[hk_b - 1, hk_b, hk_b + 1]);
+                }
+            }
+        }
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+}
+
+/// Record a match of row `gidx` with harmonic factor `k`, keeping the smallest factor.
+fn mark_match(matched: &mut [bool], harmonic_k: &mut [u32], gidx: usize, k: u32) {
+    matched[gidx] = true;
+    if harmonic_k[gidx] == 0 || k < harmonic_k[gidx] {
+        harmonic_k[gidx] = k;
+    }
+}
+
+/// Cached numeric fields of one row. Unusable periods (see `bucketable`) are dropped and
+/// counted in `unbucketable`: such rows are kept for output but never matched.
+fn row_ref(hmap: &HashMap<String, usize>, rec: &StringRecord, file_id: usize, row_idx: usize, bucket_w: f64, unbucketable: &mut usize) -> RowRef {
+    let period = extract_period_indices(hmap, rec).map(|(v, _)| v).filter(|&v| {
+        let ok = bucketable(v, bucket_w);
+        *unbucketable += usize::from(!ok);
+        ok
+    });
+    RowRef {
+        file_id,
+        row_idx,
+        period,
+        dm: extract_dm(hmap, rec).map(|(v, _)| v),
+        acc: extract_acc(hmap, rec).map(|(v, _)| v),
+    }
+}
+
+/// Row refs for all rows of input `fid`, warning about rows with unusable periods.
+fn index_rows(f: &FileData, fid: usize, bucket_w: f64) -> Vec<RowRef> {
+    let mut unbucketable = 0usize;
+    let rows: Vec<RowRef> = f
+        .rows
+        .iter()
+        .enumerate()
+        .map(|(idx, rec)| row_ref(&f.hmap, rec, fid, idx, bucket_w, &mut unbucketable))
+        .collect();
+    if unbucketable > 0 {
+        eprintln!(
+            "[WARN] Skipped {} rows with non-finite or out-of-range periods in {}",
+            unbucketable,
+            f.path.display()
+        );
+    }
+    rows
+}
+
+/// Bucket index: bucket -> list of indices into `rows`. Rows without a period are not indexed.
+fn build_buckets(rows: &[RowRef], bucket_w: f64) -> HashMap<i64, Vec<usize>> {
+    let mut buckets: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (gidx, rr) in rows.iter().enumerate() {
+        if let Some(p) = rr.period {
+            buckets.entry(bucket_abs(p, bucket_w)).or_default().push(gidx);
+        }
+    }
+    buckets
+}
+
+/// Read an input and run the optional period/f0 consistency check on it.
+fn load_input(path: &Path, read_retries: u32, period_f0_tol: Option<f64>, strict: bool) -> Result<FileData> {
+    let fd = read_csv(path, read_retries)?;
+    println!("[INFO] Loaded {} rows from {}", fd.rows.len(), path.display());
+    if let Some(tol) = period_f0_tol {
+        let bad = fd.rows.iter().filter(|r| period_f0_inconsistent(&fd.hmap, r, tol)).count();
+        if bad > 0 {
+            let msg = format!(
+                "{} rows in {} have inconsistent period and f0 (rel tol {})",
+                bad,
+                path.display(),
+                tol
+            );
+            if strict {
+                return Err(anyhow!(msg));
+            }
+            eprintln!("[WARN] {}", msg);
+        } else {
+            println!("[INFO] period/f0 consistent in {}", path.display());
+        }
+    }
+    Ok(fd)
+}
+
+/// What to write for each input once its matches are final.
+struct OutputOpts<'a> {
+    out_suffix: &'a str,
+    annotate: bool,
+    emit_unmatched: bool,
+    unmatched_suffix: &'a str,
+    dedup_keys: Option<(&'a [String], f64)>, // --dedup-output keys and tolerance
+    max_out_per_file: Option<usize>,
+    rank_col: Option<&'a str>,
+    provenance: Option<&'a str>,
+}
+
+/// Write the matched (and with --emit-unmatched, unmatched) rows of input `fid`. `all_rows`
+/// may hold rows of other inputs too; `matched` and `harmonic_k` are indexed like it.
+fn write_outputs(f: &FileData, fid: usize, all_rows: &[RowRef], matched: &[bool], harmonic_k: &[u32], out: &OutputOpts) -> Result<()> {
+    let mut selected: Vec<usize> = all_rows
+        .iter()
+        .enumerate()
+        .filter(|(gidx, rr)| rr.file_id == fid && matched[*gidx])
+        .map(|(gidx, _)| gidx)
+        .collect();
+
+    // Dedup is output tidiness only: `matched` is already final at this point.
+    if let Some((keys, tol)) = out.dedup_keys {
+        let dropped = dedup_rows(&mut selected, all_rows, keys, tol);
+        println!("[INFO] Collapsed {} duplicate matched rows in {}", dropped, f.path.display());
+    }
+
+    if let Some(max) = out.max_out_per_file {
+        let rank_idx = match out.rank_col {
+            Some(name) => find_col(&f.hmap, &[name]),
+            None => snr_column(&f.header),
+        };
+        if rank_idx.is_none() {
+            eprintln!(
+                "[WARN] No ranking column in {}; --max-out-per-file keeps the first {} matched rows",
+                f.path.display(),
+                max
+            );
+        }
+        let dropped = cap_rows(&mut selected, all_rows, f, rank_idx, max);
+        if dropped > 0 {
+            eprintln!(
+                "[WARN] Truncated {} of {} matched rows in {} (--max-out-per-file {})",
+                dropped,
+                dropped + selected.len(),
+                f.path.display(),
+                max
+            );
+        }
+    }
+
+    let out_path = suffixed_path(&f.path, out.out_suffix);
+    let annot = if out.annotate { Some(harmonic_k) } else { None };
+    write_rows(&out_path, f, all_rows, &selected, annot, out.provenance)?;
+    println!(
+        "[INFO] Wrote {} matched rows -> {}",
+        selected.len(),
+        out_path.display()
+    );
+
+    if out.emit_unmatched {
+        // Complement of `matched` (before any --dedup-output collapsing).
+        let unmatched: Vec<usize> = all_rows
+            .iter()
+            .enumerate()
+            .filter(|(gidx, rr)| rr.file_id == fid && !matched[*gidx])
+            .map(|(gidx, _)| gidx)
+            .collect();
+        let un_path = suffixed_path(&f.path, out.unmatched_suffix);
+        write_rows(&un_path, f, all_rows, &unmatched, None, out.provenance)?;
+        println!(
+            "[INFO] Wrote {} unmatched rows -> {}",
+            unmatched.len(),
+            un_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// --stream: each input in turn is the probe. Only the probe is held in memory (rows plus
+/// bucket index); every other input is read record by record and compared against it. When
+/// a pass ends the probe's matches are final and its outputs are written. Peak memory is
+/// set by the largest single input; the price is reading every input once per other input.
+/// Matching is symmetric, so the outputs equal those of the in-memory scan.
+fn stream_scan(inputs: &[String], m: &MatchOpts, read_retries: u32, load: impl Fn(&Path) -> Result<FileData>, out: &OutputOpts, quiet: bool) -> Result<()> {
+    let mut progress = Progress::new("probe files", inputs.len(), quiet);
+    for (fid, p) in inputs.iter().enumerate() {
+        progress.row(fid);
+        let probe = load(Path::new(p))?;
+        let rows = index_rows(&probe, fid, m.bucket_w);
+        let buckets = build_buckets(&rows, m.bucket_w);
+        let mut matched = vec![false; rows.len()];
+        let mut harmonic_k = vec![0u32; rows.len()];
+
+        for (oid, other) in inputs.iter().enumerate() {
+            if oid == fid { continue; }
+            let file = open_with_retries(other, read_retries)
+                .with_context(|| format!("opening CSV {}", other))?;
+            let mut reader = std::io::BufReader::new(file);
+            skip_provenance(&mut reader).with_context(|| format!("reading {}", other))?;
+            let mut rdr = ReaderBuilder::new().has_headers(true).flexible(true).from_reader(reader);
+            let hmap = header_index_map(rdr.headers().with_context(|| format!("reading header from {}", other))?);
+            let mut rec = StringRecord::new();
+            let mut unbucketable = 0usize; // reported when `other` is the probe
+            while rdr.read_record(&mut rec).with_context(|| format!("reading {}", other))? {
+                let sr = row_ref(&hmap, &rec, oid, 0, m.bucket_w, &mut unbucketable);
+                let Some(p2) = sr.period else { continue; };
+                for nb in m.neighbor_buckets(p2) {
+                    for &gidx in buckets.get(&nb).into_iter().flatten() {
+                        progress.comparison();
+                        if let Some(k) = m.match_k(&rows[gidx], &sr) {
+                            mark_match(&mut matched, &mut harmonic_k, gidx, k);
+                        }
+                    }
+                }
+            }
+        }
+        write_outputs(&probe, fid, &rows, &matched, &harmonic_k, out)?;
+    }
+    progress.finish();
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let matches = Command::new("csv_matcher")
         .about("Find rows that match ACROSS CSV files (period/DM/ACC, optional harmonics), and write only matched rows per input, preserving original headers/columns.")
@@ -172,6 +629,12 @@ fn main() -> Result<()> {
                 .required(true)
                 .help("Absolute period tolerance in seconds (e.g., 1e-6)."),
         )
+        .arg(
+            Arg::new("ptol_per_file")
+                .long("ptol-per-file")
+                .num_args(1)
+                .help("Per-file period tolerances, e.g. f1.csv=1e-7,f2.csv=1e-6. A pair of files is compared with the LOOSER (larger) of their two tolerances; files not listed use --ptol."),
+        )
         .arg(
             Arg::new("dmtol")
                 .long("dmtol")
@@ -186,6 +649,14 @@ fn main() -> Result<()> {
                 .required(false)
                 .help("Absolute acceleration tolerance (optional). If not set, acc is ignored."),
         )
+        .arg(
+            Arg::new("missing_dim")
+                .long("missing-dim")
+                .num_args(1)
+                .value_parser(["strict", "lenient"])
+                .default_value("strict")
+                .help("When --dmtol/--acctol is set and a row lacks that value: strict = no match, lenient = the gate passes."),
+        )
         .arg(
             Arg::new("harmonics")
                 .long("harmonics")
@@ -199,6 +670,12 @@ fn main() -> Result<()> {
                 .default_value("8")
                 .help("Max harmonic factor k when --harmonics is enabled (default 8)."),
         )
+        .arg(
+            Arg::new("annotate")
+                .long("annotate")
+                .action(ArgAction::SetTrue)
+                .help("Append a harmonic_k column to matched outputs: the smallest harmonic factor linking the row to a partner (1 = fundamental)."),
+        )
         .arg(
             Arg::new("out_suffix")
                 .long("out-suffix")
@@ -206,6 +683,97 @@ fn main() -> Result<()> {
                 .default_value("_matched.csv")
                 .help("Suffix appended to each input filename for its matched output."),
         )
+        .arg(
+            Arg::new("emit_unmatched")
+                .long("emit-unmatched")
+                .action(ArgAction::SetTrue)
+                .help("Also write the rows of each input that matched nothing in other files."),
+        )
+        .arg(
+            Arg::new("unmatched_suffix")
+                .long("unmatched-suffix")
+                .num_args(1)
+                .default_value("_unmatched")
+                .help("Suffix inserted before the extension for --emit-unmatched outputs."),
+        )
+        .arg(
+            Arg::new("dedup_output")
+                .long("dedup-output")
+                .action(ArgAction::SetTrue)
+                .help("Collapse near-identical matched rows within each output file, keeping the first."),
+        )
+        .arg(
+            Arg::new("dedup_keys")
+                .long("dedup-keys")
+                .num_args(1)
+                .default_value("period,dm,acc")
+                .help("Comma-separated keys compared by --dedup-output (period, dm, acc)."),
+        )
+        .arg(
+            Arg::new("dedup_tol")
+                .long("dedup-tol")
+                .num_args(1)
+                .default_value("1e-9")
+                .help("Values are quantized to multiples of this before comparing in --dedup-output."),
+        )
+        .arg(
+            Arg::new("max_out_per_file")
+                .long("max-out-per-file")
+                .num_args(1)
+                .value_name("N")
+                .help("Write at most N matched rows per input, the top N by --rank-col (default: the SNR column)."),
+        )
+        .arg(
+            Arg::new("rank_col")
+                .long("rank-col")
+                .num_args(1)
+                .requires("max_out_per_file")
+                .help("Column ranking rows for --max-out-per-file (default: detected S/N_new, sn_fold or snr)."),
+        )
+        .arg(
+            Arg::new("check_period_f0")
+                .long("check-period-f0")
+                .action(ArgAction::SetTrue)
+                .help("Warn about rows whose period and f0 columns disagree (when both exist)."),
+        )
+        .arg(
+            Arg::new("period_f0_tol")
+                .long("period-f0-tol")
+                .num_args(1)
+                .default_value("1e-6")
+                .help("Relative tolerance |P - 1/f0| / P for --check-period-f0 (default 1e-6)."),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .help("Do not print scan progress to stderr."),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .action(ArgAction::SetTrue)
+                .help("Turn data consistency warnings (e.g. --check-period-f0) into errors."),
+        )
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .action(ArgAction::SetTrue)
+                .help("Hold only one input in memory at a time and stream the others past it; for inputs too large to load together. Slower: every input is read once per other input."),
+        )
+        .arg(
+            Arg::new("embed_provenance")
+                .long("embed-provenance")
+                .action(ArgAction::SetTrue)
+                .help("Write a '# provenance:' comment line (tool, version, UTC time, command line) above the header of every output CSV"),
+        )
+        .arg(
+            Arg::new("read_retries")
+                .long("read-retries")
+                .value_name("N")
+                .default_value("0")
+                .help("Retry input reads up to N times with exponential backoff on transient IO errors."),
+        )
         .get_matches();
 
     let inputs: Vec<String> = matches
@@ -230,6 +798,7 @@ fn main() -> Result<()> {
         .map(|s| s.parse().context("parsing --acctol"))
         .transpose()?;
 
+    let lenient_dims = matches.get_one::<String>("missing_dim").unwrap() == "lenient";
     let harmonics = matches.get_flag("harmonics");
     let hmax: u32 = matches
         .get_one::<String>("hmax")
@@ -238,6 +807,51 @@ fn main() -> Result<()> {
         .context("parsing --hmax")?;
 
     let out_suffix = matches.get_one::<String>("out_suffix").unwrap();
+    let annotate = matches.get_flag("annotate");
+    let check_period_f0 = matches.get_flag("check_period_f0");
+    let period_f0_tol: f64 = matches
+        .get_one::<String>("period_f0_tol")
+        .unwrap()
+        .parse()
+        .context("parsing --period-f0-tol")?;
+    let strict = matches.get_flag("strict");
+    let read_retries: u32 = matches.get_one::<String>("read_retries").unwrap().parse()?;
+    let quiet = matches.get_flag("quiet");
+    let stream = matches.get_flag("stream");
+    let provenance = matches.get_flag("embed_provenance").then(|| provenance_line("csv_matcher"));
+    let emit_unmatched = matches.get_flag("emit_unmatched");
+    let unmatched_suffix = matches.get_one::<String>("unmatched_suffix").unwrap();
+    let dedup_output = matches.get_flag("dedup_output");
+    let dedup_keys: Vec<String> = matches
+        .get_one::<String>("dedup_keys")
+        .unwrap()
+        .split(',')
+        .map(|k| k.trim().to_ascii_lowercase())
+        .collect();
+    if let Some(bad) = dedup_keys.iter().find(|k| !["period", "dm", "acc"].contains(&k.as_str())) {
+        return Err(anyhow!("Unknown --dedup-keys entry '{}' (expected period, dm, acc)", bad));
+    }
+    let dedup_tol: f64 = matches
+        .get_one::<String>("dedup_tol")
+        .unwrap()
+        .parse()
+        .context("parsing --dedup-tol")?;
+    let max_out_per_file: Option<usize> = matches
+        .get_one::<String>("max_out_per_file")
+        .map(|s| s.parse())
+        .transpose()
+        .context("parsing --max-out-per-file")?;
+    let rank_col: Option<&str> = matches.get_one::<String>("rank_col").map(|s| s.as_str());
+
+    let file_ptol: Vec<f64> = match matches.get_one::<String>("ptol_per_file") {
+        Some(spec) => parse_ptol_per_file(spec, &inputs)?
+            .into_iter()
+            .map(|t| t.unwrap_or(ptol))
+            .collect(),
+        None => vec![ptol; inputs.len()],
+    };
+    // Buckets must be as wide as the loosest tolerance so the +/-1 neighbours stay sufficient.
+    let bucket_w = file_ptol.iter().copied().fold(ptol, f64::max);
 
     if inputs.len() < 2 {
         return Err(anyhow!(
@@ -245,143 +859,94 @@ fn main() -> Result<()> {
         ));
     }
 
+    let m = MatchOpts { file_ptol, bucket_w, dmtol, acctol, lenient_dims, harmonics, hmax };
+    let out = OutputOpts {
+        out_suffix,
+        annotate,
+        emit_unmatched,
+        unmatched_suffix,
+        dedup_keys: if dedup_output { Some((dedup_keys.as_slice(), dedup_tol)) } else { None },
+        max_out_per_file,
+        rank_col,
+        provenance: provenance.as_deref(),
+    };
+    let load = |path: &Path| load_input(path, read_retries, check_period_f0.then_some(period_f0_tol), strict);
+
+    if stream {
+        return stream_scan(&inputs, &m, read_retries, load, &out, quiet);
+    }
+
     // Read all files
     let mut files = Vec::<FileData>::new();
     for p in &inputs {
-        let path = Path::new(p);
-        let fd = read_csv(path)?;
-        println!("[INFO] Loaded {} rows from {}", fd.rows.len(), path.display());
-        files.push(fd);
+        files.push(load(Path::new(p))?);
     }
 
     // Build global list of row refs + bucket index on period to limit comparisons.
     // We only index rows that have a valid period value.
     let mut all_rows = Vec::<RowRef>::new();
     for (fid, f) in files.iter().enumerate() {
-        for (idx, rec) in f.rows.iter().enumerate() {
-            let (period_opt, _, dm_opt, acc_opt) = {
-                let p = extract_period_indices(&f.hmap, rec).map(|(v, _)| v);
-                let d = extract_dm(&f.hmap, rec).map(|(v, _)| v);
-                let a = extract_acc(&f.hmap, rec).map(|(v, _)| v);
-                (p, (), d, a)
-            };
-            all_rows.push(RowRef {
-                file_id: fid,
-                row_idx: idx,
-                period: period_opt,
-                dm: dm_opt,
-                acc: acc_opt,
-            });
-        }
-    }
-
-    // Bucket index: bucket -> list of global indices
-    let mut buckets: HashMap<i64, Vec<usize>> = HashMap::new();
-    for (gidx, rr) in all_rows.iter().enumerate() {
-        if let Some(p) = rr.period {
-            let b = bucket_abs(p, ptol);
-            buckets.entry(b).or_default().push(gidx);
-        }
+        all_rows.extend(index_rows(f, fid, bucket_w));
     }
+    let buckets = build_buckets(&all_rows, bucket_w);
 
     // For each row, test against candidates from other files in relevant buckets.
     // Mark rows that have at least one match with a row from a DIFFERENT file.
     let mut matched: Vec<bool> = vec![false; all_rows.len()];
+    // Smallest harmonic factor linking each row to any partner (0 = unmatched).
+    let mut harmonic_k: Vec<u32> = vec![0; all_rows.len()];
 
-    // Helper to gather plausible neighbor indices for an absolute-ptol + harmonics scenario
-    let mut neighbor_cache: HashMap<(i64, u32, bool), Vec<i64>> = HashMap::new();
-    let mut neighbors_for = |b0: i64, hmax: u32, harmonics: bool| -> Vec<i64> {
-        // Cache by (bucket, hmax, harmonics)
-        if let Some(v) = neighbor_cache.get(&(b0, hmax, harmonics)) {
-            return v.clone();
-        }
-        let mut out = vec![b0 - 1, b0, b0 + 1]; // same bucket +/- 1 for boundary effects
-        if harmonics {
-            for k in 2..=hmax {
-                let kf = k as f64;
-                // buckets for b0*k and b0/k are not strictly integer transforms,
-                // so compute representative centers:
-                // center period ≈ (b0 + 0.5) * ptol
-                let center = (b0 as f64 + 0.5) * ptol;
-                let hk = center * kf;
-                let hk_b = bucket_abs(hk, ptol);
-                out.extend_from_slice(&[hk_b - 1, hk_b, hk_b + 1]);
-
-                let hk_div = center / kf;
-                let hk_div_b = bucket_abs(hk_div, ptol);
-                out.extend_from_slice(&[hk_div_b - 1, hk_div_b, hk_div_b + 1]);
-            }
-        }
-        out.sort_unstable();
-        out.dedup();
-        neighbor_cache.insert((b0, hmax, harmonics), out.clone());
-        out
-    };
-
+    let mut progress = Progress::new("rows", all_rows.len(), quiet);
     for (gidx, rr) in all_rows.iter().enumerate() {
+        progress.row(gidx);
         let Some(p1) = rr.period else { continue; };
-        let b0 = bucket_abs(p1, ptol);
-        let neigh = neighbors_for(b0, hmax, harmonics);
-        for nb in neigh {
-            if let Some(list) = buckets.get(&nb) {
-                for &other_gidx in list {
-                    if other_gidx == gidx { continue; }
-                    let oo = &all_rows[other_gidx];
-                    if oo.file_id == rr.file_id { continue; } // only across files
-                    if let Some(p2) = oo.period {
-                        if !periods_match_abs(p1, p2, ptol, harmonics, hmax) {
-                            continue;
-                        }
-                        if !dim_match_abs(rr.dm,  oo.dm,  &dmtol)  { continue; }
-                        if !dim_match_abs(rr.acc, oo.acc, &acctol) { continue; }
-                        matched[gidx] = true;
-                        matched[other_gidx] = true;
-                        // keep scanning to mark more matches for the same rr;
-                        // If you want to stop after first, uncomment next line:
-                        // break 'outer;
-                    }
+        for nb in m.neighbor_buckets(p1) {
+            for &other_gidx in buckets.get(&nb).into_iter().flatten() {
+                if other_gidx == gidx { continue; }
+                let oo = &all_rows[other_gidx];
+                if oo.file_id == rr.file_id { continue; } // only across files
+                progress.comparison();
+                // keep scanning to mark every match of rr, not just the first
+                if let Some(k) = m.match_k(rr, oo) {
+                    mark_match(&mut matched, &mut harmonic_k, gidx, k);
+                    mark_match(&mut matched, &mut harmonic_k, other_gidx, k);
                 }
             }
         }
     }
 
-    // Collect matched rows per file and write outputs preserving headers and column order.
-    let mut per_file_selected: Vec<Vec<usize>> = vec![Vec::new(); files.len()];
-    for (gidx, rr) in all_rows.iter().enumerate() {
-        if matched[gidx] {
-            per_file_selected[rr.file_id].push(rr.row_idx);
-        }
-    }
+    progress.finish();
 
+    // Write outputs preserving headers and column order.
     for (fid, f) in files.iter().enumerate() {
-        let count = per_file_selected[fid].len();
-        let out_path = {
-            let p = &f.path;
-            let stem = p.file_name().unwrap_or_else(|| std::ffi::OsStr::new("out.csv")).to_string_lossy();
-            let stem_s = stem.to_string();
-            // naive suffix add before extension
-            let out = if let Some((base, ext)) = stem_s.rsplit_once('.') {
-                format!("{}{}.{ext}", base, out_suffix)
-            } else {
-                format!("{}{}", stem_s, out_suffix)
-            };
-            p.with_file_name(out)
-        };
-
-        let mut w = WriterBuilder::new().from_path(&out_path)
-            .with_context(|| format!("creating {}", out_path.display()))?;
-        w.write_record(&f.header)?;
-        for &rid in &per_file_selected[fid] {
-            w.write_record(&f.rows[rid])?;
-        }
-        w.flush()?;
-        println!(
-            "[INFO] Wrote {} matched rows -> {}",
-            count,
-            out_path.display()
-        );
+        write_outputs(f, fid, &all_rows, &matched, &harmonic_k, &out)?;
     }
 
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_f0_is_left_out_of_the_buckets() {
+        let header = StringRecord::from(vec!["f0_opt", "dm_opt"]);
+        let f = FileData {
+            path: PathBuf::from("tiny_f0.csv"),
+            hmap: header_index_map(&header),
+            header,
+            rows: vec![StringRecord::from(vec!["1e-300", "10.0"]), StringRecord::from(vec!["100.0", "10.0"])],
+        };
+        // 1 / 1e-300 = 1e300 s: finite, but far past any exact i64 bucket.
+        let huge = extract_period_indices(&f.hmap, &f.rows[0]).unwrap().0;
+        assert!(huge.is_finite() && huge > 9e299);
+        assert!(!bucketable(huge, 1e-6));
+
+        let rows = index_rows(&f, 0, 1e-6);
+        assert_eq!(rows[0].period, None);
+        assert!((rows[1].period.unwrap() - 0.01).abs() < 1e-15);
+        let buckets = build_buckets(&rows, 1e-6);
+        assert_eq!(buckets.values().flatten().copied().collect::<Vec<_>>(), [1]);
+    }
+}
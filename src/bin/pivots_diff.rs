@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Context, Result};
+use candy_picker_rs::provenance::open_csv;
+use clap::{Arg, Command};
+use csv::ReaderBuilder;
+use std::collections::HashMap;
+
+/// One pivot row of a `pivots.csv`, keyed by uuid (or `xml_file_candidate_id` when
+/// the uuid is empty, as in `Candidate::id_string`).
+struct PivotRow {
+    id: String,
+    snr: f64,
+}
+
+fn read_pivots(path: &str) -> Result<Vec<PivotRow>> {
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(open_csv(path).with_context(|| format!("opening {path}"))?);
+    let headers = rdr.headers()?.clone();
+    let col = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| anyhow!("{path}: missing column '{name}'"))
+    };
+    let (i_snr, i_uuid, i_file, i_id) = (col("snr")?, col("uuid")?, col("xml_file")?, col("candidate_id")?);
+
+    let mut out = Vec::new();
+    for (ri, rec) in rdr.records().enumerate() {
+        let rec = rec.with_context(|| format!("{path}: reading row {}", ri + 1))?;
+        let field = |i: usize| rec.get(i).unwrap_or("").trim();
+        let id = if field(i_uuid).is_empty() {
+            format!("{}_{}", field(i_file), field(i_id))
+        } else {
+            field(i_uuid).to_string()
+        };
+        let snr = field(i_snr)
+            .parse()
+            .with_context(|| format!("{path}: bad snr '{}' in row {}", field(i_snr), ri + 1))?;
+        out.push(PivotRow { id, snr });
+    }
+    Ok(out)
+}
+
+fn main() -> Result<()> {
+    let matches = Command::new("pivots_diff")
+        .version("0.3.0")
+        .about("Compare the pivots of two candy_picker runs (pivots.csv), matched by uuid")
+        .arg(Arg::new("a").required(true).help("pivots.csv of the first run"))
+        .arg(Arg::new("b").required(true).help("pivots.csv of the second run"))
+        .arg(Arg::new("summary").long("summary").action(clap::ArgAction::SetTrue)
+             .help("Only print the added/removed/common counts"))
+        .get_matches();
+
+    let path_a = matches.get_one::<String>("a").unwrap();
+    let path_b = matches.get_one::<String>("b").unwrap();
+    let summary_only = matches.get_flag("summary");
+
+    let a = read_pivots(path_a)?;
+    let b = read_pivots(path_b)?;
+    let snr_a: HashMap<&str, f64> = a.iter().map(|p| (p.id.as_str(), p.snr)).collect();
+    let snr_b: HashMap<&str, f64> = b.iter().map(|p| (p.id.as_str(), p.snr)).collect();
+
+    let removed: Vec<&PivotRow> = a.iter().filter(|p| !snr_b.contains_key(p.id.as_str())).collect();
+    let added: Vec<&PivotRow> = b.iter().filter(|p| !snr_a.contains_key(p.id.as_str())).collect();
+    let common: Vec<(&str, f64, f64)> = a
+        .iter()
+        .filter_map(|p| snr_b.get(p.id.as_str()).map(|&s| (p.id.as_str(), p.snr, s)))
+        .collect();
+
+    println!("[INFO] {path_a}: {} pivots, {path_b}: {} pivots", a.len(), b.len());
+    println!("[INFO] added: {}, removed: {}, common: {}", added.len(), removed.len(), common.len());
+    if summary_only {
+        return Ok(());
+    }
+
+    for p in &added {
+        println!("+ {} snr={}", p.id, p.snr);
+    }
+    for p in &removed {
+        println!("- {} snr={}", p.id, p.snr);
+    }
+    for (id, sa, sb) in &common {
+        if sa != sb {
+            println!("~ {id} snr {sa} -> {sb} ({:+})", sb - sa);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,22 @@
+// src/cancel.rs
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+// Exit code for a run stopped by SIGINT/SIGTERM (128 + SIGINT, as shells report it).
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+// Installs a SIGINT/SIGTERM handler that only sets the cancel flag, so long loops can stop
+// at a safe point instead of dying mid-write. A second signal exits immediately.
+pub fn install_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            std::process::exit(CANCELLED_EXIT_CODE);
+        }
+        eprintln!("[WARN] Cancelling at the next safe point; signal again to exit immediately");
+    })
+}
+
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,2677 @@
+// src/candidates.rs
+use anyhow::{anyhow, Result};
+use crate::cancel::cancelled;
+use crate::provenance::open_csv;
+use crate::retry::read_with_retries;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use xmltree::{Element, EmitterConfig};
+
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+/// `nassoc` of a candidate whose XML has no `<nassoc>`.
+pub const MISSING_NASSOC: i32 = -1;
+
+/// A candidate's `<candidate>` block as written to the picked/rejected XMLs.
+#[derive(Debug, Clone)]
+pub enum RawXml {
+    Owned(String),
+    /// A byte range of an input mapped with `--mmap`; the map stays alive while any candidate
+    /// of the file holds it, so the block is never copied.
+    Mapped(std::sync::Arc<memmap2::Mmap>, std::ops::Range<usize>),
+}
+
+impl RawXml {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RawXml::Owned(s) => s,
+            // parse_xml_file only maps files that are valid UTF-8, and the range starts and
+            // ends at ASCII '<' / '>'.
+            RawXml::Mapped(map, range) => std::str::from_utf8(&map[range.clone()]).expect("mapped XML changed during the run"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub snr: f64,
+    pub period: f64,
+    pub f0: f64,
+    pub dm: f64,
+    pub acc: f64,
+    pub nh: i32,
+    // NaN when absent from the XML (older files).
+    pub ddm_count_ratio: f32,
+    pub ddm_snr_ratio: f32,
+    // MISSING_NASSOC when absent from the XML.
+    pub nassoc: i32,
+    pub period_ms: f64,
+    pub pulse_width: f64,
+    pub uuid: Option<String>,
+    // uuid as read, when --normalize-uuid changed it; `uuid` then holds the normalized form.
+    pub uuid_raw: Option<String>,
+    pub label: String,
+    pub xml_file: String,
+    pub candidate_id: i32,
+    pub raw_xml: RawXml,
+    // Per-file DM gate from --dm-tol-steps; None falls back to the global dm_thresh.
+    pub dm_tol: Option<f64>,
+    // Effective observation time (s) of the candidate's file, set by parse_inputs (the
+    // given tobs for JSON inputs); ranks pivots under PivotPolicy::LongestTobs.
+    pub tobs: Option<f64>,
+    // uuid prefix from --group-by-uuid-prefix; only candidates with equal groups can relate.
+    pub uuid_group: Option<String>,
+    // Output cluster (a pivot and its related list), numbered by each cluster's earliest
+    // member; set by assign_cluster_ids at the end of picking, None outside every cluster.
+    // merge_connected_components (via shortlist_candidates) sets it per component first.
+    pub cluster_id: Option<usize>,
+    pub related: Vec<usize>,
+    pub is_pivot: bool,
+}
+
+impl Candidate {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        snr: f64,
+        period: f64,
+        dm: f64,
+        acc: f64,
+        nh: i32,
+        ddm_count_ratio: f32,
+        ddm_snr_ratio: f32,
+        nassoc: i32,
+        uuid: Option<String>,
+        label: String,
+        xml_file: String,
+        candidate_id: i32,
+        raw_xml: String,
+    ) -> Self {
+        let f0 = 1.0 / period;
+        let period_ms = period * 1000.0;
+        let pulse_width = period / 2.0f64.powi(nh);
+        Self {
+            snr,
+            period,
+            f0,
+            dm,
+            acc,
+            nh,
+            ddm_count_ratio,
+            ddm_snr_ratio,
+            nassoc,
+            period_ms,
+            pulse_width,
+            uuid,
+            uuid_raw: None,
+            label,
+            xml_file,
+            candidate_id,
+            raw_xml: RawXml::Owned(raw_xml),
+            dm_tol: None,
+            tobs: None,
+            uuid_group: None,
+            cluster_id: None,
+            related: Vec::new(),
+            is_pivot: false,
+        }
+    }
+
+    // The acceleration correction moves `other` into `self`'s frame:
+    //   P_other' = 1 / (f0_other - acc_sign * (acc_other - acc_self) * f0_other * tobs / c)
+    // acc_sign = +1 is the peasoup convention; use -1 for inputs that define acc with the
+    // opposite sign. With acc_ref Zero or Mean both periods are instead corrected to
+    // acc = 0 or to the mean acc of the pair, which makes the distance symmetric.
+    // Returns (modulo period difference, absolute period difference) in seconds.
+    pub fn period_distance(&self, other: &Candidate, tobs_over_c: f64, acc_sign: f64, acc_ref: AccReference) -> (f64, f64) {
+        let (p_self, p_other) = self.frame_periods(other, tobs_over_c, acc_sign, acc_ref);
+        let true_period_difference = if (p_self / p_other) > 1.0 {
+            p_self % p_other
+        } else {
+            p_other % p_self
+        };
+        (true_period_difference, (p_self - p_other).abs())
+    }
+
+    // Periods of `self` and `other` in the common acceleration frame chosen by acc_ref.
+    fn frame_periods(&self, other: &Candidate, tobs_over_c: f64, acc_sign: f64, acc_ref: AccReference) -> (f64, f64) {
+        let to_frame = |c: &Candidate, acc: f64| 1.0 / (c.f0 - acc_sign * (c.acc - acc) * c.f0 * tobs_over_c);
+        match acc_ref {
+            AccReference::SelfFrame => (self.period, to_frame(other, self.acc)),
+            AccReference::Zero => (to_frame(self, 0.0), to_frame(other, 0.0)),
+            AccReference::Mean => {
+                let mean = 0.5 * (self.acc + other.acc);
+                (to_frame(self, mean), to_frame(other, mean))
+            }
+        }
+    }
+
+    // max_modulo_harmonic rejects modulo matches whose implied integer period ratio
+    // round(longer / shorter) exceeds it; the absolute match is unaffected. max_snr_ratio
+    // rejects pairs whose SNRs differ by more than that factor (skipped unless both SNRs
+    // are positive and finite). `domain` picks the period or frequency form of the period
+    // tests (see RelateDomain). With `harmonics`, a pair failing those tests is still related
+    // when |P_self - k P_other| or |k P_self - P_other| is within period_thresh for some k in
+    // 2..=hmax, in the same acceleration frame; the fundamental tests are the k = 1 case.
+    #[allow(clippy::too_many_arguments)]
+    pub fn is_related(
+        &self,
+        other: &Candidate,
+        period_thresh: f64,
+        dm_thresh: Option<f64>,
+        tobs_over_c: f64,
+        acc_sign: f64,
+        acc_ref: AccReference,
+        max_modulo_harmonic: Option<u32>,
+        max_snr_ratio: Option<f64>,
+        domain: RelateDomain,
+        harmonics: bool,
+        hmax: u32,
+    ) -> bool {
+        if self.uuid_group != other.uuid_group {
+            return false;
+        }
+        if let Some(r) = max_snr_ratio {
+            let (lo, hi) = (self.snr.min(other.snr), self.snr.max(other.snr));
+            if lo > 0.0 && hi.is_finite() && hi / lo > r {
+                return false;
+            }
+        }
+        if let Some(dmth) = self.dm_gate(other, dm_thresh) {
+            if (self.dm - other.dm).abs() > dmth {
+                return false;
+            }
+        }
+        let (p_self, p_other) = self.frame_periods(other, tobs_over_c, acc_sign, acc_ref);
+        let fundamental = if domain == RelateDomain::Frequency {
+            related_in_frequency(1.0 / p_self, 1.0 / p_other, period_thresh, max_modulo_harmonic)
+        } else {
+            let (true_period_difference, abs_period_difference) = self.period_distance(other, tobs_over_c, acc_sign, acc_ref);
+            abs_period_difference <= period_thresh
+                || (true_period_difference <= period_thresh
+                    && max_modulo_harmonic.is_none_or(|max| {
+                        let ratio = (p_self.max(p_other) / p_self.min(p_other)).round();
+                        ratio <= max as f64
+                    }))
+        };
+        fundamental
+            || (harmonics
+                && (2..=hmax).any(|k| {
+                    let k = k as f64;
+                    (p_self - k * p_other).abs() <= period_thresh || (k * p_self - p_other).abs() <= period_thresh
+                }))
+    }
+
+    pub fn set_period(&mut self, period: f64) {
+        self.period = period;
+        self.f0 = 1.0 / period;
+        self.period_ms = period * 1000.0;
+    }
+
+    /// DM gate between two candidates: the looser of their gates (`dm_tol`, else
+    /// `dm_thresh`); a candidate without any gate disables it.
+    pub fn dm_gate(&self, other: &Candidate, dm_thresh: Option<f64>) -> Option<f64> {
+        match (self.dm_tol.or(dm_thresh), other.dm_tol.or(dm_thresh)) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        }
+    }
+
+    // uuid when present, else "<xml_file>_<candidate_id>".
+    pub fn id_string(&self) -> String {
+        self.uuid.clone().unwrap_or_else(|| format!("{}_{}", self.xml_file, self.candidate_id))
+    }
+}
+
+// Acceleration frame in which two candidates' periods are compared (--acc-reference).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccReference {
+    // Correct `other` into the frame of `self` (order-dependent).
+    #[default]
+    SelfFrame,
+    // Correct both to acc = 0.
+    Zero,
+    // Correct both to the mean acc of the pair.
+    Mean,
+}
+
+// Domain of the period tests in is_related (--relate-on).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelateDomain {
+    // |P_a - P_b| <= thresh, else (P_long mod P_short) <= thresh.
+    #[default]
+    Period,
+    // |f_hi - k * f_lo| <= thresh * f_hi * f_lo with k = round(f_hi / f_lo) >= 1.
+    Frequency,
+}
+
+// Frequency form of the period tests. |1/f_a - 1/f_b| = |f_a - f_b| / (f_a f_b), so a
+// period tolerance becomes thresh * f_hi * f_lo and k = 1 is exactly the absolute period
+// match. For k > 1 it is |P_long - k P_short| <= thresh with the nearest k, where the
+// period modulo only catches P_long just above a multiple of P_short.
+fn related_in_frequency(f_a: f64, f_b: f64, period_thresh: f64, max_modulo_harmonic: Option<u32>) -> bool {
+    let (f_hi, f_lo) = (f_a.max(f_b), f_a.min(f_b));
+    let k = (f_hi / f_lo).round().max(1.0);
+    if (f_hi - k * f_lo).abs() > period_thresh * f_hi * f_lo {
+        return false;
+    }
+    k == 1.0 || max_modulo_harmonic.is_none_or(|max| k <= max as f64)
+}
+
+// Row order of the pivots in the outputs (--sort-by).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PivotOrder {
+    // Input order (file, then position in the file).
+    #[default]
+    KeepOrder,
+    // SNR descending.
+    Snr,
+    // Period ascending.
+    Period,
+    // Number of related candidates descending, then SNR descending.
+    ClusterSize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Birdie {
+    pub freq: f64, // Hz
+    pub width: f64, // Hz (half width)
+}
+
+pub fn parse_birdies<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Vec<Birdie>> {
+    let txt = std::fs::read_to_string(&path)?;
+    let mut out = Vec::new();
+    for (lineno, line) in txt.lines().enumerate() {
+        let t = line.trim();
+        if t.is_empty() || t.starts_with('#') { continue; }
+        let cols: Vec<&str> = t.split_whitespace().collect();
+        if cols.len() < 2 {
+            return Err(anyhow::anyhow!(
+                "bidies file {} line {}: expected 'freq width'",
+                path.as_ref().display(),
+                lineno + 1
+                ));
+        }
+        let freq: f64 = cols[0].parse()?;
+        let width: f64 = cols[1].parse()?;
+        if freq > 0.0 && width > 0.0 {
+            out.push(Birdie { freq, width });
+        }
+    }
+    Ok(out)
+}
+
+// Build [f_lo and f_hi] windows for each birdie and its harmonics.
+// if scale_width is true, the k-th harmonic gets widht * k ; else width is constant. 
+fn build_birdie_windows(birds: &[Birdie], hmax: u32, scale_width: bool) -> Vec<(f64, f64)> {
+    let mut wins = Vec::with_capacity(birds.len() * (hmax as usize));
+    for b in birds {
+        for k in 1..=hmax {
+            let kf = (k as f64) * b.freq;
+            if kf <= 0.0 { continue; }
+            let full_w = if scale_width { (k as f64) * b.width } else { b.width };
+            let half = 0.5 * full_w;
+            wins.push((kf - half, kf + half));
+        }
+    }
+    // Optional: sort for cache-friendliness (not strictly needed) // suggested by chatgpt 
+    wins.sort_by(|a,b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    wins
+} 
+
+#[derive(Debug, Clone)]
+pub struct RfiLine {
+    pub value: f64,      // period (s) if is_period, else frequency (Hz)
+    pub tol: f64,        // same units as value
+    pub is_period: bool,
+}
+
+// CSV with a header; each row gives either `period` (s) or `freq` (Hz), plus `tol` in the same units.
+pub fn parse_rfi_lines<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Vec<RfiLine>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(true).trim(csv::Trim::All).from_path(&path)?;
+    let hdr = rdr.headers()?.clone();
+    let col = |name: &str| hdr.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let (i_period, i_freq) = (col("period"), col("freq"));
+    let i_tol = col("tol").ok_or_else(|| anyhow!("rfi lines file {}: missing 'tol' column", path.as_ref().display()))?;
+    if i_period.is_none() && i_freq.is_none() {
+        return Err(anyhow!("rfi lines file {}: expected a 'period' or 'freq' column", path.as_ref().display()));
+    }
+    let mut out = Vec::new();
+    for (lineno, rec) in rdr.records().enumerate() {
+        let rec = rec?;
+        let field = |i: Option<usize>| i.and_then(|i| rec.get(i)).filter(|v| !v.is_empty());
+        let tol: f64 = rec.get(i_tol).unwrap_or("").parse()?;
+        let line = if let Some(p) = field(i_period) {
+            RfiLine { value: p.parse()?, tol, is_period: true }
+        } else if let Some(f) = field(i_freq) {
+            RfiLine { value: f.parse()?, tol, is_period: false }
+        } else {
+            return Err(anyhow!(
+                "rfi lines file {} line {}: no period or freq value",
+                path.as_ref().display(),
+                lineno + 2
+            ));
+        };
+        out.push(line);
+    }
+    Ok(out)
+}
+
+// CSV with a header naming `xml_file` and `candidate_id` columns; one vetoed candidate per row.
+pub fn parse_veto<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Vec<(String, i32)>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(true).trim(csv::Trim::All).from_path(&path)?;
+    let hdr = rdr.headers()?.clone();
+    let col = |name: &str| {
+        hdr.iter()
+            .position(|h| h.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("veto file {}: missing '{name}' column", path.as_ref().display()))
+    };
+    let (i_file, i_id) = (col("xml_file")?, col("candidate_id")?);
+    let mut out = Vec::new();
+    for (lineno, rec) in rdr.records().enumerate() {
+        let rec = rec?;
+        let id = rec.get(i_id).unwrap_or("").parse().map_err(|e| {
+            anyhow!("veto file {} line {}: bad candidate_id: {e}", path.as_ref().display(), lineno + 2)
+        })?;
+        out.push((rec.get(i_file).unwrap_or("").to_string(), id));
+    }
+    Ok(out)
+}
+
+// A veto entry names a file either by the path it was given on the command line or by its
+// bare file name.
+fn veto_names_file(entry: &str, filename: &str) -> bool {
+    entry == filename || std::path::Path::new(filename).file_name().is_some_and(|n| n.to_string_lossy() == entry)
+}
+
+// Drop the candidates of `filename` listed in `veto`, marking the entries that matched in `hits`.
+fn apply_veto(cands: &mut Vec<Candidate>, filename: &str, veto: &[(String, i32)], hits: &mut [bool]) -> usize {
+    let ids: HashMap<i32, Vec<usize>> = veto
+        .iter()
+        .enumerate()
+        .filter(|(_, (f, _))| veto_names_file(f, filename))
+        .fold(HashMap::new(), |mut m, (k, (_, id))| {
+            m.entry(*id).or_default().push(k);
+            m
+        });
+    let before = cands.len();
+    cands.retain(|c| match ids.get(&c.candidate_id) {
+        Some(entries) => {
+            entries.iter().for_each(|&k| hits[k] = true);
+            false
+        }
+        None => true,
+    });
+    before - cands.len()
+}
+
+fn report_veto(veto: &[(String, i32)], hits: &[bool], vetoed: usize) {
+    println!("[INFO] Vetoed {vetoed} candidates");
+    for ((f, id), _) in veto.iter().zip(hits).filter(|(_, &hit)| !hit) {
+        eprintln!("[WARN] Stale veto entry: no candidate {id} in {f}");
+    }
+}
+
+// Index of the first RFI line the candidate falls within, if any.
+pub fn matches_rfi_line(c: &Candidate, lines: &[RfiLine]) -> Option<usize> {
+    lines.iter().position(|l| {
+        let v = if l.is_period { c.period } else { c.f0 };
+        (v - l.value).abs() <= l.tol
+    })
+}
+
+// Vec::retain for the pre-filters: the dropped candidates move to `removed` (and from there
+// to the rejected XMLs and per-file outputs) instead of being freed.
+fn retain_or_remove(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, mut keep: impl FnMut(&Candidate) -> bool) {
+    let (kept, dropped): (Vec<Candidate>, Vec<Candidate>) = std::mem::take(cands).into_iter().partition(|c| keep(c));
+    *cands = kept;
+    removed.extend(dropped);
+}
+
+// Drops candidates on a known RFI line from the clustering set; they never become
+// pivots, so they end up in the rejected XML.
+fn remove_rfi_line_candidates(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, lines: &[RfiLine]) {
+    let mut hits = vec![0usize; lines.len()];
+    retain_or_remove(cands, removed, |c| match matches_rfi_line(c, lines) {
+        Some(k) => {
+            hits[k] += 1;
+            false
+        }
+        None => true,
+    });
+    for (l, n) in lines.iter().zip(&hits) {
+        let unit = if l.is_period { "s" } else { "Hz" };
+        println!("[INFO] RFI line {} {unit} (tol {}): {n} candidates rejected", l.value, l.tol);
+    }
+    println!("[INFO] RFI lines removed {} candidates in total.", hits.iter().sum::<usize>());
+}
+
+// Parse an inclusive candidate id range "LO-HI".
+pub fn parse_id_range(s: &str) -> Result<(i32, i32)> {
+    let (lo, hi) = s.split_once('-').ok_or_else(|| anyhow!("id range '{s}' is not of the form LO-HI"))?;
+    let lo: i32 = lo.trim().parse().map_err(|_| anyhow!("invalid lower bound in id range '{s}'"))?;
+    let hi: i32 = hi.trim().parse().map_err(|_| anyhow!("invalid upper bound in id range '{s}'"))?;
+    if lo > hi {
+        return Err(anyhow!("id range '{s}' is empty (LO > HI)"));
+    }
+    Ok((lo, hi))
+}
+
+// Cheap per-file pre-reduction: bucket each file's candidates by round(period_ms / res_ms)
+// and keep only the highest-SNR one per bucket. No acceleration correction is involved.
+fn collapse_by_period_ms(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, res_ms: f64) {
+    let before = cands.len();
+    let mut best: HashMap<(&str, i64), usize> = HashMap::new();
+    for (i, c) in cands.iter().enumerate() {
+        let key = (c.xml_file.as_str(), (c.period_ms / res_ms).round() as i64);
+        best.entry(key)
+            .and_modify(|b| {
+                if compare_pivot_priority(c, &cands[*b], None).is_lt() {
+                    *b = i;
+                }
+            })
+            .or_insert(i);
+    }
+    let mut keep = vec![false; before];
+    for &i in best.values() {
+        keep[i] = true;
+    }
+    let mut k = keep.iter();
+    retain_or_remove(cands, removed, |_| *k.next().unwrap());
+    println!("[INFO] Collapsed {} candidates sharing a {res_ms} ms period bucket within their file.", before - cands.len());
+}
+
+// Files with fewer candidates than this keep them all under --snr-percentile; a
+// percentile of a handful of values says little about the file's noise level.
+const MIN_PERCENTILE_CANDIDATES: usize = 10;
+
+// The `p`-th percentile (0-100) of `values`, interpolating linearly between the
+// closest ranks (numpy's default). `values` must be non-empty.
+fn percentile(values: &mut [f64], p: f64) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let pos = p / 100.0 * (values.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    values[lo] + (values[hi] - values[lo]) * (pos - lo as f64)
+}
+
+// Per-file adaptive SNR cut: drop candidates below their own file's p-th SNR percentile.
+fn apply_snr_percentile(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, p: f64) {
+    let mut order: Vec<&str> = Vec::new();
+    let mut snrs: HashMap<&str, Vec<f64>> = HashMap::new();
+    for c in cands.iter() {
+        snrs.entry(c.xml_file.as_str())
+            .or_insert_with(|| {
+                order.push(c.xml_file.as_str());
+                Vec::new()
+            })
+            .push(c.snr);
+    }
+    let mut thresholds: HashMap<String, f64> = HashMap::new();
+    for file in order {
+        let v = snrs.get_mut(file).unwrap();
+        if v.len() < MIN_PERCENTILE_CANDIDATES {
+            eprintln!("[WARN] {file}: only {} candidates, fewer than {MIN_PERCENTILE_CANDIDATES}; keeping all", v.len());
+            continue;
+        }
+        let n = v.len();
+        let t = percentile(v, p);
+        let kept = v.iter().filter(|&&s| s >= t).count();
+        println!("[INFO] {file}: SNR percentile {p} = {t}; kept {kept} of {n} candidates");
+        thresholds.insert(file.to_string(), t);
+    }
+    retain_or_remove(cands, removed, |c| thresholds.get(&c.xml_file).is_none_or(|&t| c.snr >= t));
+}
+
+/// A `--filter` boolean expression over candidate fields, e.g.
+/// `snr > 9 && nh < 4 && ddm_snr_ratio < 1.2`. Available names (as on [`Candidate`]):
+/// floats `snr`, `period`, `f0`, `dm`, `acc`, `ddm_count_ratio`, `ddm_snr_ratio`,
+/// `period_ms`; integers `nh`, `nassoc`, `candidate_id`; string `label`.
+#[derive(Debug, Clone)]
+pub struct CandidateFilter {
+    expr: String,
+    node: evalexpr::Node,
+}
+
+impl CandidateFilter {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let node = evalexpr::build_operator_tree(expr).map_err(|e| anyhow!("Invalid --filter '{}': {}", expr, e))?;
+        Ok(Self { expr: expr.to_string(), node })
+    }
+
+    pub fn matches(&self, c: &Candidate) -> Result<bool> {
+        use evalexpr::{ContextWithMutableVariables, HashMapContext, Value};
+        let mut ctx = HashMapContext::new();
+        let vars = [
+            ("snr", Value::Float(c.snr)),
+            ("period", Value::Float(c.period)),
+            ("f0", Value::Float(c.f0)),
+            ("dm", Value::Float(c.dm)),
+            ("acc", Value::Float(c.acc)),
+            ("ddm_count_ratio", Value::Float(c.ddm_count_ratio as f64)),
+            ("ddm_snr_ratio", Value::Float(c.ddm_snr_ratio as f64)),
+            ("period_ms", Value::Float(c.period_ms)),
+            ("dm_sharpness", Value::Float(dm_sharpness(c))),
+            ("nh", Value::Int(c.nh as i64)),
+            ("nassoc", Value::Int(c.nassoc as i64)),
+            ("candidate_id", Value::Int(c.candidate_id as i64)),
+            ("label", Value::String(c.label.clone())),
+        ];
+        for (name, value) in vars {
+            ctx.set_value(name.into(), value)?;
+        }
+        self.node
+            .eval_boolean_with_context(&ctx)
+            .map_err(|e| anyhow!("--filter '{}' failed on {}: {}", self.expr, c.id_string(), e))
+    }
+}
+
+/// `--normalize-uuid`: a regex marking where a version suffix starts. Everything from the
+/// start of its last match to the end of the uuid is stripped, so a literal such as `:v`
+/// works as a plain prefix and `[:#](v|rev)[0-9]+$` as a full pattern.
+#[derive(Debug, Clone)]
+pub struct UuidNormalizer {
+    re: regex::Regex,
+}
+
+impl UuidNormalizer {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let re = regex::Regex::new(pattern).map_err(|e| anyhow!("invalid --normalize-uuid pattern '{pattern}': {e}"))?;
+        Ok(Self { re })
+    }
+
+    pub fn normalize<'a>(&self, uuid: &'a str) -> &'a str {
+        match self.re.find_iter(uuid).last() {
+            Some(m) => &uuid[..m.start()],
+            None => uuid,
+        }
+    }
+}
+
+// Replace each uuid by its normalized form, keeping the original in `uuid_raw`. Every
+// candidate of a normalizing run gets a `uuid_raw`, changed or not. Returns how many changed.
+fn normalize_uuids(cands: &mut [Candidate], n: &UuidNormalizer) -> usize {
+    let mut changed = 0;
+    for c in cands.iter_mut() {
+        if let Some(raw) = c.uuid.take() {
+            let norm = n.normalize(&raw).to_string();
+            changed += usize::from(norm != raw);
+            c.uuid = Some(norm);
+            c.uuid_raw = Some(raw);
+        }
+    }
+    changed
+}
+
+// --group-by-uuid-prefix: candidates only relate within the same first `len` uuid chars.
+// Candidates without a uuid share one "unknown" group, or are dropped (and so rejected)
+// with `exclude_missing`.
+fn group_by_uuid_prefix(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, len: usize, exclude_missing: bool) {
+    if exclude_missing {
+        let before = cands.len();
+        retain_or_remove(cands, removed, |c| c.uuid.is_some());
+        println!("[INFO] Excluded {} candidates without a uuid.", before - cands.len());
+    }
+    for c in cands.iter_mut() {
+        c.uuid_group = c.uuid.as_ref().map(|u| u.chars().take(len).collect());
+    }
+    let groups: std::collections::HashSet<&Option<String>> = cands.iter().map(|c| &c.uuid_group).collect();
+    println!("[INFO] Clustering within {} uuid prefix groups (prefix length {len}).", groups.len());
+}
+
+// DM peak sharpness in [0, 1]: the geometric mean of (1 - ddm_snr_ratio) and
+// (1 - ddm_count_ratio). Both ratios compare the off-peak DM response with the peak, so a
+// narrow, well-defined DM peak (small ratios) scores near 1 and a broadband, RFI-like
+// response (ratios near 1) near 0. Ratios are clamped to [0, 1] and non-finite ratios count
+// as 1, so the score is never NaN or infinite.
+pub fn dm_sharpness(c: &Candidate) -> f64 {
+    let spread = |r: f32| if r.is_finite() { (r as f64).clamp(0.0, 1.0) } else { 1.0 };
+    ((1.0 - spread(c.ddm_snr_ratio)) * (1.0 - spread(c.ddm_count_ratio))).sqrt()
+}
+
+fn remove_unsharp_candidates(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, min: f64) {
+    let before = cands.len();
+    retain_or_remove(cands, removed, |c| dm_sharpness(c) >= min);
+    println!("[INFO] DM sharpness >= {min} kept {} of {before} candidates.", cands.len());
+}
+
+// Drops candidates for which the filter is false; like RFI lines, they end up rejected.
+fn apply_candidate_filter(cands: &mut Vec<Candidate>, removed: &mut Vec<Candidate>, filter: &CandidateFilter) -> Result<()> {
+    let before = cands.len();
+    let mut error = None;
+    retain_or_remove(cands, removed, |c| {
+        filter.matches(c).unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            true
+        })
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    println!("[INFO] Filter '{}' kept {} of {before} candidates.", filter.expr, cands.len());
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct XmlSections {
+    pub misc_info: Option<String>,
+    pub header_parameters: Option<String>,
+    pub search_parameters: Option<String>,
+    pub segment_parameters: Option<String>,
+    pub dedispersion_trials: Option<String>,
+    pub acceleration_trials: Option<String>,
+    pub cuda_device_parameters: Option<String>,
+    pub execution_times: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct XmlFile {
+    pub filename: String,
+    pub sections: XmlSections,
+    pub fft_size: i64,
+    pub tsamp: f64,
+    pub segment_start_sample: Option<i64>,
+    pub segment_nsamples: Option<i64>,
+    // Median spacing of the <trial> DMs in dedispersion_trials, if there are at least two.
+    pub dm_step: Option<f64>,
+    // <misc_info><version>, if present.
+    pub version: Option<String>,
+    // (RA, Dec) in degrees, J2000, from header_parameters src_raj/src_dej when both parse.
+    pub ra_dec: Option<(f64, f64)>,
+    // As parsed. parse_inputs moves them out into ParsedInputs::candidates (leaving this
+    // empty) so each candidate is held once; candidate_ids keeps their document order.
+    pub candidates: Vec<Candidate>,
+    pub candidate_ids: Vec<i32>,
+}
+
+impl XmlFile {
+    // Segment length when segment_parameters provides one, else fft_size * tsamp.
+    pub fn effective_tobs(&self) -> f64 {
+        match self.segment_nsamples {
+            Some(n) if n > 0 => n as f64 * self.tsamp,
+            _ => self.fft_size as f64 * self.tsamp,
+        }
+    }
+}
+
+// A sigproc angle: either packed `[-]ddmmss.s` as written by peasoup (hhmmss.s for RA) or
+// `[-]dd:mm:ss.s`. Returns the value in units of the leading field (hours or degrees).
+fn parse_sigproc_angle(text: &str) -> Option<f64> {
+    let t = text.trim();
+    let (sign, t) = match t.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, t.strip_prefix('+').unwrap_or(t)),
+    };
+    let (d, m, s) = if t.contains(':') {
+        let mut parts = t.split(':').map(|p| p.trim().parse::<f64>());
+        let d = parts.next()?.ok()?;
+        let m = parts.next().transpose().ok()?.unwrap_or(0.0);
+        let s = parts.next().transpose().ok()?.unwrap_or(0.0);
+        (d, m, s)
+    } else {
+        let v: f64 = t.parse().ok()?;
+        let d = (v / 10000.0).floor();
+        let m = ((v - d * 10000.0) / 100.0).floor();
+        (d, m, v - d * 10000.0 - m * 100.0)
+    };
+    let minutes_or_seconds = |x: f64| (0.0..60.0).contains(&x);
+    (d.is_finite() && minutes_or_seconds(m) && minutes_or_seconds(s)).then_some(sign * (d + m / 60.0 + s / 3600.0))
+}
+
+// header_parameters src_raj/src_dej as (RA, Dec) in degrees. None when either is missing;
+// a warning when present but unparsable.
+fn parse_ra_dec(root: &Element, filename: &str) -> Option<(f64, f64)> {
+    let raj = get_text_path(root, &["header_parameters", "src_raj"])?;
+    let dej = get_text_path(root, &["header_parameters", "src_dej"])?;
+    match (parse_sigproc_angle(&raj), parse_sigproc_angle(&dej)) {
+        (Some(ra_h), Some(dec)) if (0.0..24.0).contains(&ra_h) && (-90.0..=90.0).contains(&dec) => Some((ra_h * 15.0, dec)),
+        _ => {
+            eprintln!("[WARN] {filename}: unparsable src_raj/src_dej '{}'/'{}'; no coordinates", raj.trim(), dej.trim());
+            None
+        }
+    }
+}
+
+// Rotation from J2000 equatorial (ICRS axes) to galactic, as defined for Hipparcos
+// (ESA 1997, vol. 1, sec. 1.5.3): north galactic pole at RA 192.85948, Dec 27.12825 and
+// l = 122.93192 for the north celestial pole.
+const EQ_TO_GAL: [[f64; 3]; 3] = [
+    [-0.054_875_560_416_215_4, -0.873_437_090_234_885, -0.483_835_015_548_713_2],
+    [0.494_109_427_875_583_7, -0.444_829_629_960_011_2, 0.746_982_244_497_219],
+    [-0.867_666_149_019_004_7, -0.198_076_373_431_201_5, 0.455_983_776_175_066_9],
+];
+
+/// J2000 (RA, Dec) in degrees to galactic (l, b) in degrees, l in [0, 360).
+pub fn equatorial_to_galactic(ra_deg: f64, dec_deg: f64) -> (f64, f64) {
+    let (ra, dec) = (ra_deg.to_radians(), dec_deg.to_radians());
+    let v = [dec.cos() * ra.cos(), dec.cos() * ra.sin(), dec.sin()];
+    let g: Vec<f64> = EQ_TO_GAL.iter().map(|row| row.iter().zip(&v).map(|(a, b)| a * b).sum()).collect();
+    let l = g[1].atan2(g[0]).to_degrees().rem_euclid(360.0);
+    let b = g[2].clamp(-1.0, 1.0).asin().to_degrees();
+    (l, b)
+}
+
+// Median gap between consecutive (sorted) DM trials; peasoup grids widen with DM, so the
+// median is more representative than the first or last gap.
+fn parse_dm_step(root: &Element) -> Option<f64> {
+    let trials = root.get_child("dedispersion_trials")?;
+    let mut dms: Vec<f64> = trials
+        .children
+        .iter()
+        .filter_map(|n| match n {
+            xmltree::XMLNode::Element(e) if e.name == "trial" => e.get_text()?.trim().parse().ok(),
+            _ => None,
+        })
+        .collect();
+    dms.sort_by(|a, b| a.total_cmp(b));
+    let mut gaps: Vec<f64> = dms.windows(2).map(|w| w[1] - w[0]).filter(|g| *g > 0.0).collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort_by(|a, b| a.total_cmp(b));
+    Some(gaps[gaps.len() / 2])
+}
+
+fn element_to_string(e: &Element) -> String {
+    let mut buf = Vec::new();
+    e.write_with_config(&mut buf, EmitterConfig::new().perform_indent(true))
+        .expect("serialize element");
+    String::from_utf8(buf).unwrap()
+}
+
+// Some forks nest <candidate> children inside a candidate, so the block ends at the
+// </candidate> that brings the depth back to zero. None if the tags never balance, in which
+// case the caller falls back to xmltree's serialization. Returns the block's byte range.
+fn slice_candidate_block(xml: &str, id: i32) -> Option<std::ops::Range<usize>> {
+    const CLOSE: &str = "</candidate>";
+    let pat = format!("<candidate id='{id}'>");
+    let start = xml.find(&pat)?;
+    let mut depth = 1;
+    let mut pos = start + pat.len();
+    while depth > 0 {
+        let next = pos + xml[pos..].find("<candidate").into_iter().chain(xml[pos..].find(CLOSE)).min()?;
+        if xml[next..].starts_with(CLOSE) {
+            depth -= 1;
+            pos = next + CLOSE.len();
+            continue;
+        }
+        let after = &xml[next + "<candidate".len()..];
+        pos = next + "<candidate".len();
+        if after.starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            let gt = after.find('>')?;
+            if !after[..gt].ends_with('/') {
+                depth += 1;
+            }
+            pos += gt + 1;
+        }
+    }
+    Some(start..pos)
+}
+
+// Original text of the first <tag>...</tag> (or <tag/>) element, so sections round-trip
+// with their comments and whitespace intact.
+fn slice_section(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{tag}");
+    let mut from = 0;
+    while let Some(pos) = xml[from..].find(&open) {
+        let start = from + pos;
+        let after = &xml[start + open.len()..];
+        if after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            let gt = start + open.len() + after.find('>')?;
+            if xml[..gt].ends_with('/') {
+                return Some(xml[start..=gt].to_string());
+            }
+            let close = format!("</{tag}>");
+            let end = gt + xml[gt..].find(&close)? + close.len();
+            return Some(xml[start..end].to_string());
+        }
+        from = start + open.len();
+    }
+    None
+}
+
+// A top-level section as written in the source, falling back to xmltree's serialization.
+fn section_text(root: &Element, xml: &str, tag: &str) -> Option<String> {
+    let el = root.get_child(tag)?;
+    Some(slice_section(xml, tag).unwrap_or_else(|| element_to_string(el)))
+}
+
+fn get_text_path(root: &Element, path: &[&str]) -> Option<String> {
+    let mut cur = root;
+    for &p in path {
+        cur = cur.get_child(p)?;
+    }
+    cur.get_text().map(|cow| cow.to_string())
+}
+
+fn get_text_child(el: &Element, tag: &str, filename: &str) -> Result<String> {
+    el.get_child(tag)
+        .and_then(|e| e.get_text().map(|cow| cow.to_string()))
+        .ok_or_else(|| anyhow!("Missing <{}> in {}", tag, filename))
+}
+
+// Float parsing for legacy writers: unless `strict`, a value Rust rejects is retried with
+// Fortran exponents (1.234D-03) turned into E and a trailing type suffix (f, d, l) dropped.
+fn parse_float<T: std::str::FromStr>(text: &str, strict: bool) -> Option<T> {
+    let t = text.trim();
+    if let Ok(v) = t.parse() {
+        return Some(v);
+    }
+    if strict {
+        return None;
+    }
+    let t = t.strip_suffix(['f', 'F', 'd', 'D', 'l', 'L']).filter(|s| s.ends_with(|c: char| c.is_ascii_digit() || c == '.')).unwrap_or(t);
+    t.replace(['D', 'd'], "E").parse().ok()
+}
+
+// Like get_float_child, but a missing tag is None; a present but unparsable value is still an error.
+fn get_optional_float_child<T: std::str::FromStr>(el: &Element, tag: &str, filename: &str, strict: bool) -> Result<Option<T>> {
+    if el.get_child(tag).is_none() {
+        return Ok(None);
+    }
+    get_float_child(el, tag, filename, strict).map(Some)
+}
+
+fn get_float_child<T: std::str::FromStr>(el: &Element, tag: &str, filename: &str, strict: bool) -> Result<T> {
+    let text = get_text_child(el, tag, filename)?;
+    parse_float(&text, strict).ok_or_else(|| anyhow!("Invalid number '{}' in <{}> of {}", text.trim(), tag, filename))
+}
+
+/// Cheap pre-flight check: the file must be well-formed XML with a tsamp, an fft size and a
+/// <candidates> block. Candidates themselves are not parsed. Reading (gzip, UTF-8 handling
+/// under `strict`, retries) is as in [`parse_xml_file`].
+pub fn validate_xml_file(filename: &str, strict: bool, read_retries: u32) -> Result<()> {
+    let content = read_xml_text(filename, strict, read_retries)?;
+    let root: Element = Element::parse(content.as_bytes())?;
+    for path in [&["header_parameters", "tsamp"][..], &["search_parameters", "size"][..]] {
+        if get_text_path(&root, path).is_none() {
+            return Err(anyhow!("Missing {}", path.join("/")));
+        }
+    }
+    if root.get_child("candidates").is_none() {
+        return Err(anyhow!("Missing candidates"));
+    }
+    Ok(())
+}
+
+// Invalid UTF-8 (e.g. a stray Latin-1 byte in a comment) is replaced with U+FFFD and
+// reported, unless `strict` is set.
+fn read_xml_text(filename: &str, strict: bool, read_retries: u32) -> Result<String> {
+    let bytes = read_xml_bytes(filename, read_retries)?;
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(e) if strict => Err(anyhow!("Invalid UTF-8 in {}: {}", filename, e.utf8_error())),
+        Err(e) => {
+            eprintln!(
+                "[WARN] Invalid UTF-8 in {} ({}); replacing bad bytes",
+                filename,
+                e.utf8_error()
+            );
+            Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        }
+    }
+}
+
+// Contents of `filename`, gunzipped when the name ends in `.gz` (e.g. `beam01.xml.gz`).
+// Only reading the compressed file is retried.
+fn read_xml_bytes(filename: &str, read_retries: u32) -> Result<Vec<u8>> {
+    let bytes = read_with_retries(filename, read_retries)?;
+    if !filename.ends_with(".gz") {
+        return Ok(bytes);
+    }
+    let mut xml = Vec::new();
+    std::io::Read::read_to_end(&mut flate2::read::MultiGzDecoder::new(bytes.as_slice()), &mut xml)
+        .map_err(|e| anyhow!("Cannot decompress {}: {}", filename, e))?;
+    Ok(xml)
+}
+
+/// Buffered reader over an input XML, gunzipping `.gz` files like `parse_xml_file` does.
+pub fn open_xml_reader(filename: &str) -> Result<Box<dyn std::io::BufRead>> {
+    let file = fs::File::open(filename)?;
+    Ok(if filename.ends_with(".gz") {
+        Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+    } else {
+        Box::new(std::io::BufReader::new(file))
+    })
+}
+
+// Read-only memory map of `filename` for --mmap. The map is only valid while nobody
+// modifies or truncates the file: a concurrent write changes the bytes under the parser,
+// and a truncation makes later accesses fault (SIGBUS).
+fn map_xml_file(filename: &str) -> Result<memmap2::Mmap> {
+    let file = fs::File::open(filename)?;
+    // SAFETY: see above; the inputs are treated as immutable for the duration of the run.
+    Ok(unsafe { memmap2::Mmap::map(&file)? })
+}
+
+/// Ids of all <candidate> elements in a file, in document order.
+pub fn read_candidate_ids(filename: &str, strict: bool, read_retries: u32) -> Result<Vec<i32>> {
+    let content = read_xml_text(filename, strict, read_retries)?;
+    let root: Element = Element::parse(content.as_bytes())?;
+    let mut ids = Vec::new();
+    if let Some(cands_el) = root.get_child("candidates") {
+        for node in &cands_el.children {
+            if let xmltree::XMLNode::Element(e) = node {
+                let id = e.attributes.get("id")
+                    .ok_or_else(|| anyhow!("Candidate missing id in {}", filename))?
+                    .parse::<i32>()?;
+                ids.push(id);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+pub fn parse_xml_file(filename: &str, strict: bool, read_retries: u32, mmap: bool) -> Result<XmlFile> {
+    println!("[INFO] Parsing {filename}");
+    let mapped: std::sync::Arc<memmap2::Mmap>;
+    let owned: String;
+    // Set when the candidate blocks can point into the map rather than be copied.
+    let mut shared_map: Option<&std::sync::Arc<memmap2::Mmap>> = None;
+    // A compressed file has to be inflated into memory anyway, so --mmap does not apply.
+    let content: &str = if mmap && !filename.ends_with(".gz") {
+        mapped = std::sync::Arc::new(map_xml_file(filename)?);
+        match std::str::from_utf8(&mapped) {
+            Ok(s) => {
+                shared_map = Some(&mapped);
+                s
+            }
+            Err(e) if strict => return Err(anyhow!("Invalid UTF-8 in {}: {}", filename, e)),
+            Err(e) => {
+                eprintln!("[WARN] Invalid UTF-8 in {} ({}); replacing bad bytes", filename, e);
+                owned = String::from_utf8_lossy(&mapped).into_owned();
+                &owned
+            }
+        }
+    } else {
+        owned = read_xml_text(filename, strict, read_retries)?;
+        &owned
+    };
+    let root: Element = Element::parse(content.as_bytes())?;
+
+    let tsamp_text = get_text_path(&root, &["header_parameters", "tsamp"])
+        .ok_or_else(|| anyhow!("Missing tsamp in {}", filename))?;
+    let tsamp: f64 = parse_float(&tsamp_text, strict)
+        .ok_or_else(|| anyhow!("Invalid tsamp '{}' in {}", tsamp_text.trim(), filename))?;
+
+    let fft_size: i64 = get_text_path(&root, &["search_parameters", "size"])
+        .ok_or_else(|| anyhow!("Missing fft size in {}", filename))?
+        .parse()?;
+
+    let segment_start_sample: Option<i64> = get_text_path(&root, &["segment_parameters", "segment_start_sample"])
+        .map(|s| s.trim().parse()).transpose()?;
+    let segment_nsamples: Option<i64> = get_text_path(&root, &["segment_parameters", "segment_nsamples"])
+        .map(|s| s.trim().parse()).transpose()?;
+
+    let dm_step = parse_dm_step(&root);
+    let ra_dec = parse_ra_dec(&root, filename);
+    let version = get_text_path(&root, &["misc_info", "version"]).map(|v| v.trim().to_string());
+
+    let mut candidates = Vec::new();
+    if let Some(cands_el) = root.get_child("candidates") {
+        for cand_el in &cands_el.children {
+            if let xmltree::XMLNode::Element(e) = cand_el {
+                let cid = e.attributes.get("id")
+                    .ok_or_else(|| anyhow!("Candidate missing id in {}", filename))?
+                    .parse::<i32>()?;
+                let period: f64 = get_float_child(e, "period", filename, strict)?;
+                let dm: f64 = get_float_child(e, "dm", filename, strict)?;
+                let acc: f64 = get_float_child(e, "acc", filename, strict)?;
+                let nh: i32 = get_text_child(e, "nh", filename)?.parse()?;
+                let snr: f64 = get_float_child(e, "snr", filename, strict)?;
+                // Older files lack these three; see the sentinels on Candidate.
+                let ddm_count_ratio: f32 = get_optional_float_child(e, "ddm_count_ratio", filename, strict)?.unwrap_or(f32::NAN);
+                let ddm_snr_ratio: f32 = get_optional_float_child(e, "ddm_snr_ratio", filename, strict)?.unwrap_or(f32::NAN);
+                let nassoc: i32 = get_text_path(e, &["nassoc"]).map(|t| t.trim().parse()).transpose()?.unwrap_or(MISSING_NASSOC);
+                let uuid = get_text_path(e, &["search_candidates_database_uuid"]);
+                let label = get_text_path(e, &["label"]).map(|l| l.trim().to_string()).unwrap_or_default();
+                let raw_xml = match (slice_candidate_block(content, cid), shared_map) {
+                    (Some(r), Some(map)) => RawXml::Mapped(map.clone(), r),
+                    (Some(r), None) => RawXml::Owned(content[r].to_string()),
+                    (None, _) => RawXml::Owned(element_to_string(e)),
+                };
+                candidates.push(Candidate {
+                    raw_xml,
+                    ..Candidate::new(
+                        snr, period, dm, acc, nh,
+                        ddm_count_ratio, ddm_snr_ratio, nassoc,
+                        uuid, label, filename.to_string(), cid, String::new(),
+                    )
+                });
+            }
+        }
+    }
+
+    let sections = XmlSections {
+        misc_info: section_text(&root, content, "misc_info"),
+        header_parameters: section_text(&root, content, "header_parameters"),
+        search_parameters: section_text(&root, content, "search_parameters"),
+        segment_parameters: section_text(&root, content, "segment_parameters"),
+        dedispersion_trials: section_text(&root, content, "dedispersion_trials"),
+        acceleration_trials: section_text(&root, content, "acceleration_trials"),
+        cuda_device_parameters: section_text(&root, content, "cuda_device_parameters"),
+        execution_times: section_text(&root, content, "execution_times"),
+    };
+
+    println!("[INFO] Parsed {filename}: {} candidates", candidates.len());
+    let candidate_ids = candidates.iter().map(|c| c.candidate_id).collect();
+    Ok(XmlFile {
+        filename: filename.to_string(),
+        sections,
+        fft_size,
+        tsamp,
+        segment_start_sample,
+        segment_nsamples,
+        dm_step,
+        version,
+        ra_dec,
+        candidates,
+        candidate_ids,
+    })
+}
+
+// One element of a --json-input array. snr, period, dm and nh are required, as in the XML;
+// missing ratios and nassoc get the XML sentinels (NaN, MISSING_NASSOC).
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonCandidate {
+    snr: f64,
+    period: f64,
+    dm: f64,
+    #[serde(default)]
+    acc: f64,
+    nh: i32,
+    ddm_count_ratio: Option<f32>,
+    ddm_snr_ratio: Option<f32>,
+    nassoc: Option<i32>,
+    uuid: Option<String>,
+    #[serde(default)]
+    label: String,
+    id: Option<i32>,
+}
+
+/// Candidates from a JSON array of objects (see the README for the schema). `xml_file`
+/// is the JSON path; a missing `id` defaults to the array index.
+pub fn read_json_candidates(path: &std::path::Path, read_retries: u32) -> Result<Vec<Candidate>> {
+    println!("[INFO] Parsing {}", path.display());
+    let bytes = read_with_retries(path, read_retries)?;
+    let items: Vec<JsonCandidate> =
+        serde_json::from_slice(&bytes).map_err(|e| anyhow!("invalid JSON candidates in {}: {e}", path.display()))?;
+    let file = path.to_string_lossy().into_owned();
+    let cands: Vec<Candidate> = items
+        .into_iter()
+        .enumerate()
+        .map(|(i, j)| {
+            Candidate::new(
+                j.snr, j.period, j.dm, j.acc, j.nh,
+                j.ddm_count_ratio.unwrap_or(f32::NAN), j.ddm_snr_ratio.unwrap_or(f32::NAN), j.nassoc.unwrap_or(MISSING_NASSOC),
+                j.uuid, j.label, file.clone(), j.id.unwrap_or(i as i32), String::new(),
+            )
+        })
+        .collect();
+    println!("[INFO] Parsed {}: {} candidates", path.display(), cands.len());
+    Ok(cands)
+}
+
+// Pivot priority: candidates carrying `prefer_label` first, then by SNR descending (NaN last);
+// equal SNRs (common after quantization) go to the higher nassoc, then the shorter period.
+pub fn compare_pivot_priority(a: &Candidate, b: &Candidate, prefer_label: Option<&str>) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    if let Some(label) = prefer_label {
+        let (pa, pb) = (a.label == label, b.label == label);
+        if pa != pb {
+            return if pa { Ordering::Less } else { Ordering::Greater };
+        }
+    }
+    let by_snr = match (a.snr.is_finite(), b.snr.is_finite()) {
+        (false, false) => Ordering::Equal,
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+        (true, true) => b.snr.partial_cmp(&a.snr).unwrap_or(Ordering::Equal),
+    };
+    by_snr
+        .then_with(|| b.nassoc.cmp(&a.nassoc))
+        .then_with(|| a.period.total_cmp(&b.period))
+}
+
+// Pivot priority for --pivot-policy longest-tobs: candidates carrying `prefer_label` first,
+// then the longest file tobs (unknown last), then compare_pivot_priority.
+pub fn compare_longest_tobs(a: &Candidate, b: &Candidate, prefer_label: Option<&str>) -> std::cmp::Ordering {
+    let unpreferred = |c: &Candidate| prefer_label.is_some_and(|l| c.label != l);
+    let tobs = |c: &Candidate| c.tobs.unwrap_or(f64::NEG_INFINITY);
+    unpreferred(a)
+        .cmp(&unpreferred(b))
+        .then_with(|| tobs(b).total_cmp(&tobs(a)))
+        .then_with(|| compare_pivot_priority(a, b, None))
+}
+
+/// The pair test used by [`cluster_candidates`]: `related(a, b)` decides whether `b` joins
+/// `a`'s related list. It is only asked for pairs where `a` comes before `b` in the input, and
+/// from several threads at once, hence `Sync`.
+///
+/// Implement it to cluster on a different notion of similarity; the CLI always uses
+/// [`AccelerationRelatedness`].
+pub trait Relatedness: Sync {
+    fn related(&self, a: &Candidate, b: &Candidate) -> bool;
+}
+
+/// The default [`Relatedness`]: [`Candidate::is_related`] with these settings.
+#[derive(Debug, Clone, Copy)]
+pub struct AccelerationRelatedness {
+    pub period_thresh: f64,
+    pub dm_thresh: Option<f64>,
+    pub tobs_over_c: f64,
+    pub acc_sign: f64,
+    pub acc_ref: AccReference,
+    pub max_modulo_harmonic: Option<u32>,
+    pub max_snr_ratio: Option<f64>,
+    pub domain: RelateDomain,
+    /// Also relate harmonics k = 2..=hmax (see `Candidate::is_related`).
+    pub harmonics: bool,
+    pub hmax: u32,
+    /// is_related corrects the second candidate into the first one's frame, so it need not
+    /// be symmetric; this also accepts pairs that only match the other way round.
+    pub symmetric: bool,
+}
+
+impl AccelerationRelatedness {
+    /// The settings `params` cluster with, given the period threshold and Tobs/c in use
+    /// (see [`PickResult::period_thresh`] and [`PickResult::tobs_over_c`]).
+    pub fn from_params(params: &PickParams, period_thresh: f64, tobs_over_c: f64) -> Self {
+        AccelerationRelatedness {
+            period_thresh,
+            dm_thresh: params.dm_thresh,
+            tobs_over_c,
+            acc_sign: params.acc_sign,
+            acc_ref: params.acc_reference,
+            max_modulo_harmonic: params.max_modulo_harmonic,
+            max_snr_ratio: params.max_snr_ratio,
+            domain: params.relate_on,
+            harmonics: params.harmonics,
+            hmax: params.hmax,
+            symmetric: params.symmetric_relate,
+        }
+    }
+}
+
+impl Relatedness for AccelerationRelatedness {
+    fn related(&self, a: &Candidate, b: &Candidate) -> bool {
+        let test = |a: &Candidate, b: &Candidate| {
+            a.is_related(b, self.period_thresh, self.dm_thresh, self.tobs_over_c, self.acc_sign, self.acc_ref,
+                self.max_modulo_harmonic, self.max_snr_ratio, self.domain, self.harmonics, self.hmax)
+        };
+        test(a, b) || (self.symmetric && test(b, a))
+    }
+}
+
+/// Fill each candidate's `related` with the later candidates `rel` relates to it. With
+/// `bin_dm`, only candidates in the same DM bin are compared; the bins are as wide as the
+/// loosest DM gate (`dm_tol`, else `dm_thresh`; see [`bin_dm_width`]). Returns the number of
+/// pairs passed to `rel`.
+pub fn cluster_candidates<R: Relatedness>(
+    cands: &mut [Candidate],
+    rel: &R,
+    dm_thresh: Option<f64>,
+    bin_dm: bool,
+    timeout: Option<std::time::Duration>,
+) -> Result<u64> {
+    println!("[INFO] Clustering (binning: {bin_dm})...");
+    let n = cands.len();
+    // Added to once per candidate, not per pair, so the count costs next to nothing.
+    let comparisons = std::sync::atomic::AtomicU64::new(0);
+    let count = |k: usize| comparisons.fetch_add(k as u64, std::sync::atomic::Ordering::Relaxed);
+    let related = |i: usize, j: usize| rel.related(&cands[i], &cands[j]);
+    // Checked once per candidate; the first expired check (timeout or Ctrl-C) makes the
+    // parallel collect stop.
+    let deadline = timeout.map(|t| std::time::Instant::now() + t);
+    let expired = || cancelled() || deadline.is_some_and(|d| std::time::Instant::now() >= d);
+    let results: Option<Vec<(usize, Vec<usize>)>> = if bin_dm {
+        let width = bin_dm_width(cands, dm_thresh);
+        match width {
+            Some(w) if dm_thresh.is_some_and(|d| w > d) => println!("[INFO] DM bins {w} wide, the largest per-file DM gate"),
+            None => eprintln!("[WARN] --bin-dm: some candidates have no DM gate; comparing all pairs"),
+            _ => {}
+        }
+        let mut bins: HashMap<i64, Vec<usize>> = HashMap::new();
+        for (i, c) in cands.iter().enumerate() {
+            let b = if let Some(w) = width {
+                (c.dm / w).floor() as i64
+            } else {
+                0
+            };
+            bins.entry(b).or_default().push(i);
+        }
+        bins.into_par_iter().map(|(_, idxs)| {
+            idxs.iter().map(|&i| {
+                if expired() {
+                    return None;
+                }
+                let mut rels = Vec::new();
+                let mut compared = 0;
+                for &j in &idxs {
+                    if j > i {
+                        compared += 1;
+                        if related(i, j) {
+                            rels.push(j);
+                        }
+                    }
+                }
+                count(compared);
+                Some((i, rels))
+            }).collect::<Option<Vec<_>>>()
+        }).collect::<Option<Vec<_>>>().map(|r| r.into_iter().flatten().collect())
+    } else {
+        (0..n).into_par_iter().map(|i| {
+            if expired() {
+                return None;
+            }
+            let mut rels = Vec::new();
+            for j in (i+1)..n {
+                if related(i, j) {
+                    rels.push(j);
+                }
+            }
+            count(n - i - 1);
+            Some((i, rels))
+        }).collect()
+    };
+    let Some(mut results) = results else {
+        if cancelled() {
+            return Err(anyhow!("Clustering cancelled"));
+        }
+        return Err(anyhow!(
+            "Clustering {n} candidates exceeded --timeout of {} s; try --bin-dm or tighter prefilters (--filter, --min-dm-sharpness, --id-range)",
+            timeout.unwrap_or_default().as_secs_f64()
+        ));
+    };
+    // HashMap bin order and rayon scheduling both vary between runs; sort so everything
+    // downstream sees the same related lists whatever -n is.
+    results.sort_unstable_by_key(|(i, _)| *i);
+    for (i, mut rels) in results {
+        rels.sort_unstable();
+        cands[i].related = rels;
+    }
+    println!("[INFO] Finished clustering.");
+    Ok(comparisons.into_inner())
+}
+
+/// Width of the `bin_dm` DM bins: the largest DM gate of any candidate (its `dm_tol`, else
+/// `dm_thresh`), so bins are never narrower than a gate. None, meaning a single bin, when
+/// any candidate has no gate at all.
+pub fn bin_dm_width(cands: &[Candidate], dm_thresh: Option<f64>) -> Option<f64> {
+    cands
+        .iter()
+        .map(|c| c.dm_tol.or(dm_thresh))
+        .try_fold(0.0f64, |w, g| g.map(|g| w.max(g)))
+        .filter(|&w| w > 0.0)
+}
+
+/// Pick pivots from clustered candidates: one per connected component of the `related`
+/// lists, its highest-SNR member by [`compare_pivot_priority`], dropping those near a birdie.
+/// Sets `is_pivot` and returns the pivot indices. For the same related pairs, the pivots do
+/// not depend on the input order.
+pub fn shortlist_candidates(
+    cands: &mut [Candidate],
+    birdies: Option<&[Birdie]>,
+    birdie_harmonics: u32,
+    scale_birdie_width: bool,
+    ) -> Vec<usize> {
+    shortlist_candidates_by(cands, |a, b| compare_pivot_priority(a, b, None), birdies, birdie_harmonics, scale_birdie_width)
+}
+
+/// [`shortlist_candidates`] with the pivot of each component chosen by `better` instead
+/// (`Less` = better pivot; ties go to the earlier candidate).
+pub fn shortlist_candidates_by<F>(
+    cands: &mut [Candidate],
+    better: F,
+    birdies: Option<&[Birdie]>,
+    birdie_harmonics: u32,
+    scale_birdie_width: bool,
+) -> Vec<usize>
+where
+    F: Fn(&Candidate, &Candidate) -> std::cmp::Ordering,
+{
+    // 1) one pivot per connected component; every other candidate is suppressed.
+    let best = merge_connected_components(cands, better);
+    println!("[INFO] Shortlisting pivots...");
+    let mut to_remove: std::collections::HashSet<usize> = (0..cands.len()).collect();
+    for i in best {
+        to_remove.remove(&i);
+    }
+
+    // 2) Birdie + harmonic pruning
+    if let Some(birds) = birdies {
+        let windows = build_birdie_windows(birds, birdie_harmonics, scale_birdie_width);
+        let mut rfi_hits = 0usize;
+
+        'cand: for (i, c) in cands.iter().enumerate() {
+            if to_remove.contains(&i) { continue; }
+            let f0 = c.f0; // Hz
+
+            // Simple scan is fine for modest window counts
+            for &(lo, hi) in &windows {
+                if f0 >= lo && f0 <= hi {
+                    to_remove.insert(i);
+                    rfi_hits += 1;
+                    continue 'cand;
+                }
+            }
+        }
+
+        println!(
+            "[INFO] Birdie pruning (with harmonics up to k={}) removed {} candidates.",
+            birdie_harmonics, rfi_hits
+        );
+    }
+
+    // Mark remaining as pivots
+    let mut pivots = Vec::new();
+    for (i, c) in cands.iter_mut().enumerate() {
+        if !to_remove.contains(&i) {
+            c.is_pivot = true;
+            pivots.push(i);
+        }
+    }
+    println!("[INFO] Found {} pivots.", pivots.len());
+    pivots
+}
+
+// Union-find root of `x`, halving paths on the way.
+fn find_root(parent: &mut [usize], mut x: usize) -> usize {
+    while parent[x] != x {
+        parent[x] = parent[parent[x]];
+        x = parent[x];
+    }
+    x
+}
+
+/// Join transitively related candidates (union-find over the `related` lists) into one
+/// cluster each and set every candidate's `cluster_id`. The best member of each cluster by
+/// `better` (a pivot-priority comparator, `Less` = better; ties go to the earlier candidate)
+/// gets all the others as its `related` list and every other list is cleared. Returns those
+/// best members in input order.
+pub fn merge_connected_components<F>(cands: &mut [Candidate], better: F) -> Vec<usize>
+where
+    F: Fn(&Candidate, &Candidate) -> std::cmp::Ordering,
+{
+    let n = cands.len();
+    let mut parent: Vec<usize> = (0..n).collect();
+    for (i, c) in cands.iter().enumerate() {
+        for &j in &c.related {
+            let (a, b) = (find_root(&mut parent, i), find_root(&mut parent, j));
+            if a != b {
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    // Members per root, in input order; roots numbered by first appearance.
+    let mut members: Vec<Vec<usize>> = Vec::new();
+    let mut cluster_of_root: HashMap<usize, usize> = HashMap::new();
+    for (i, c) in cands.iter_mut().enumerate() {
+        let root = find_root(&mut parent, i);
+        let id = *cluster_of_root.entry(root).or_insert_with(|| {
+            members.push(Vec::new());
+            members.len() - 1
+        });
+        members[id].push(i);
+        c.cluster_id = Some(id);
+    }
+    let mut bests = Vec::with_capacity(members.len());
+    for group in &members {
+        let best = *group.iter().min_by(|&&a, &&b| better(&cands[a], &cands[b])).unwrap();
+        for &m in group {
+            cands[m].related.clear();
+        }
+        cands[best].related = group.iter().copied().filter(|&m| m != best).collect();
+        bests.push(best);
+    }
+    println!("[INFO] Connected components: {} clusters from {n} candidates", members.len());
+    bests.sort_unstable();
+    bests
+}
+
+/// Number the final clusters (each pivot with its `related` list) 0, 1, ... in order of
+/// their earliest member and set `cluster_id` on every member; a candidate in several
+/// related lists keeps the first cluster's id. Candidates in no cluster get `None`.
+pub fn assign_cluster_ids(cands: &mut [Candidate], pivots: &[usize]) {
+    let mut order: Vec<(usize, usize)> = pivots
+        .iter()
+        .map(|&p| (cands[p].related.iter().copied().fold(p, usize::min), p))
+        .collect();
+    order.sort_unstable();
+    cands.iter_mut().for_each(|c| c.cluster_id = None);
+    for (id, &(_, p)) in order.iter().enumerate() {
+        cands[p].cluster_id = Some(id);
+        for k in 0..cands[p].related.len() {
+            let m = cands[p].related[k];
+            cands[m].cluster_id.get_or_insert(id);
+        }
+    }
+}
+
+/// Member indices of each cluster by `cluster_id` (see [`assign_cluster_ids`]), pivot
+/// included, in candidate order. This, not the `related` lists, is what the outputs count.
+pub fn cluster_members(cands: &[Candidate]) -> HashMap<usize, Vec<usize>> {
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, c) in cands.iter().enumerate() {
+        if let Some(id) = c.cluster_id {
+            members.entry(id).or_default().push(i);
+        }
+    }
+    members
+}
+
+// Output filter only: pivots with fewer than `min_related` related candidates are
+// demoted (is_pivot = false), so they go to the rejected XML instead of the picked one.
+pub fn filter_pivots_by_related(cands: &mut [Candidate], pivots: &mut Vec<usize>, min_related: usize) {
+    let before = pivots.len();
+    pivots.retain(|&i| {
+        let keep = cands[i].related.len() >= min_related;
+        if !keep {
+            cands[i].is_pivot = false;
+        }
+        keep
+    });
+    println!(
+        "[INFO] --min-related {min_related}: kept {} of {before} pivots.",
+        pivots.len()
+    );
+}
+
+// --protect: a cluster with a member related (either way round, under `rel`) to a protected
+// candidate is pinned to that member, whatever its SNR: it becomes the pivot and the old
+// pivot joins its related list. As an RFI pivot sharing the period may match too, the member
+// closest to a protected candidate (period, then DM) is the one pinned. Matching candidates
+// left outside every cluster (e.g. birdie-pruned) become pivots of their own. Returns the
+// number of clusters whose pivot changed.
+pub fn pin_protected_pivots<R: Relatedness>(cands: &mut [Candidate], pivots: &mut Vec<usize>, protected: &[Candidate], rel: &R) -> usize {
+    // Per candidate: the closest protected candidate it matches and the (period, DM) distance.
+    let matches: Vec<Option<(usize, (f64, f64))>> = cands
+        .par_iter()
+        .map(|c| {
+            protected
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| rel.related(p, c) || rel.related(c, p))
+                .map(|(k, p)| (k, ((p.period - c.period).abs(), (p.dm - c.dm).abs())))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        })
+        .collect();
+    let mut pinned = 0;
+    for slot in pivots.iter_mut() {
+        let i = *slot;
+        if !cands[i].is_pivot {
+            continue;
+        }
+        let Some(m) = std::iter::once(i)
+            .chain(cands[i].related.iter().copied())
+            .filter_map(|m| matches[m].map(|(_, d)| (m, d)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(m, _)| m)
+        else {
+            continue;
+        };
+        if m == i {
+            continue;
+        }
+        println!(
+            "[INFO] Pinned cluster of {} to {} (protected {})",
+            cands[i].id_string(),
+            cands[m].id_string(),
+            protected[matches[m].unwrap().0].id_string()
+        );
+        let mut members = std::mem::take(&mut cands[i].related);
+        members.retain(|&j| j != m);
+        members.push(i);
+        cands[i].is_pivot = false;
+        for j in members {
+            if !cands[m].related.contains(&j) {
+                cands[m].related.push(j);
+            }
+        }
+        cands[m].is_pivot = true;
+        *slot = m;
+        pinned += 1;
+    }
+    let covered: std::collections::HashSet<usize> =
+        pivots.iter().flat_map(|&i| std::iter::once(i).chain(cands[i].related.iter().copied())).collect();
+    let mut rescued = 0;
+    for (i, c) in cands.iter_mut().enumerate() {
+        if matches[i].is_some() && !covered.contains(&i) {
+            c.is_pivot = true;
+            pivots.push(i);
+            rescued += 1;
+        }
+    }
+    let kept = pivots.iter().filter(|&&i| matches[i].is_some()).count();
+    println!("[INFO] --protect: {kept} pivots match protected sources ({pinned} clusters re-pivoted, {rescued} candidates restored).");
+    pinned
+}
+
+// Output filter only, like filter_pivots_by_related: pivots whose cluster (the pivot and its
+// related candidates) spans less than `min_spread` m/s^2 in acc are demoted. Non-finite accs
+// are ignored; a cluster with no finite acc has spread 0.
+pub fn filter_pivots_by_acc_spread(cands: &mut [Candidate], pivots: &mut Vec<usize>, min_spread: f64) {
+    let before = pivots.len();
+    pivots.retain(|&i| {
+        let (lo, hi) = std::iter::once(i)
+            .chain(cands[i].related.iter().copied())
+            .map(|j| cands[j].acc)
+            .filter(|a| a.is_finite())
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), a| (lo.min(a), hi.max(a)));
+        let spread = if hi >= lo { hi - lo } else { 0.0 };
+        let keep = spread >= min_spread;
+        if !keep {
+            println!(
+                "[INFO] Dropped cluster of {} ({} members): acc spread {spread} m/s^2",
+                cands[i].id_string(),
+                cands[i].related.len() + 1
+            );
+            cands[i].is_pivot = false;
+        }
+        keep
+    });
+    println!(
+        "[INFO] --min-acc-spread {min_spread}: kept {} of {before} pivots.",
+        pivots.len()
+    );
+}
+
+// Post-pass over the shortlisted clusters: a pivot whose period is within period_thresh of
+// an integer multiple (k = 2..=hmax) of a shorter-period pivot is merged into
+// that cluster. The shorter-period pivot always stays the pivot, regardless of SNR.
+// The longer period is compared after correcting it into that pivot's acceleration frame.
+// Returns the number of merges.
+pub fn merge_harmonic_clusters(
+    cands: &mut [Candidate],
+    pivots: &mut Vec<usize>,
+    period_thresh: f64,
+    dm_thresh: Option<f64>,
+    tobs_over_c: f64,
+    acc_sign: f64,
+    hmax: u32,
+) -> usize {
+    let mut order = pivots.clone();
+    order.sort_by(|&a, &b| cands[a].period.partial_cmp(&cands[b].period).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut kept: Vec<usize> = Vec::with_capacity(order.len());
+    let mut merges = 0usize;
+    for &i in &order {
+        let shorter = kept.iter().copied().find(|&f| {
+            let (fc, c) = (&cands[f], &cands[i]);
+            if fc.uuid_group != c.uuid_group {
+                return false;
+            }
+            if let Some(dmth) = fc.dm_gate(c, dm_thresh) {
+                if (fc.dm - c.dm).abs() > dmth {
+                    return false;
+                }
+            }
+            let corrected = 1.0 / (c.f0 - acc_sign * (c.acc - fc.acc) * c.f0 * tobs_over_c);
+            let k = (corrected / fc.period).round();
+            k >= 2.0 && k <= hmax as f64 && (corrected - k * fc.period).abs() <= period_thresh
+        });
+        match shorter {
+            Some(f) => {
+                let mut absorbed = std::mem::take(&mut cands[i].related);
+                absorbed.push(i);
+                cands[i].is_pivot = false;
+                cands[f].related.extend(absorbed);
+                merges += 1;
+            }
+            None => kept.push(i),
+        }
+    }
+    pivots.retain(|&i| cands[i].is_pivot);
+    println!("[INFO] Harmonic cluster merging: {merges} clusters merged into shorter-period pivots.");
+    merges
+}
+
+// --canonicalize-periods pre-pass. A candidate summed over 2^nh harmonics may itself be
+// up to the 2^nh-th harmonic (at least 2, at most hmax) of a signal, so its
+// period is multiplied by the largest such k for which another candidate (within
+// dm_thresh) lies at k * period within period_thresh. Candidates with no such partner keep
+// their period. P and nh alone cannot fix k (nh only bounds it), so this is a neighbour
+// search rather than a per-candidate estimate; see "Canonical periods" in the README.
+// Returns the original periods so the caller can restore them.
+fn canonicalize_periods(cands: &mut [Candidate], period_thresh: f64, dm_thresh: Option<f64>, hmax: u32) -> Vec<f64> {
+    let original: Vec<f64> = cands.iter().map(|c| c.period).collect();
+    let mut by_period: Vec<usize> = (0..cands.len()).collect();
+    by_period.sort_by(|&a, &b| original[a].total_cmp(&original[b]));
+    let sorted: Vec<f64> = by_period.iter().map(|&i| original[i]).collect();
+
+    let canonical: Vec<f64> = cands
+        .par_iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let kmax = (1u32 << c.nh.clamp(1, 5)).min(hmax);
+            (2..=kmax)
+                .rev()
+                .map(|k| k as f64 * c.period)
+                .find(|&target| {
+                    let lo = sorted.partition_point(|&p| p < target - period_thresh);
+                    sorted[lo..]
+                        .iter()
+                        .take_while(|&&p| p <= target + period_thresh)
+                        .zip(&by_period[lo..])
+                        .any(|(_, &j)| j != i && dm_thresh.is_none_or(|d| (cands[j].dm - c.dm).abs() <= d))
+                })
+                .unwrap_or(c.period)
+        })
+        .collect();
+
+    let mut folded = 0usize;
+    for (c, p) in cands.iter_mut().zip(canonical) {
+        if p != c.period {
+            folded += 1;
+            c.set_period(p);
+        }
+    }
+    println!("[INFO] Canonicalized {folded} candidate periods to their fundamentals.");
+    original
+}
+
+// A single cluster swallowing most candidates almost always means period_thresh (or -d)
+// is too loose. Diagnostic only.
+fn warn_if_overmerged(cands: &[Candidate], pivots: &[usize], frac: f64) {
+    let Some(largest) = pivots.iter().map(|&i| 1 + cands[i].related.len()).max() else {
+        return;
+    };
+    let f = largest as f64 / cands.len() as f64;
+    if cands.len() > 1 && f > frac {
+        eprintln!(
+            "[WARN] Largest cluster holds {largest} of {} candidates ({:.1}%); the period threshold may be too loose",
+            cands.len(),
+            100.0 * f
+        );
+    }
+}
+
+// Output selection on the final pivots; dropped pivots are demoted (is_pivot = false) so
+// they go to the rejected XML. `top_k` keeps the highest-SNR pivots (ties by file, then
+// candidate id, so no RNG is involved). `limit` then draws a uniform random subset with a
+// StdRng seeded from `seed`: the same seed and inputs always give the same selection.
+pub fn select_pivots(cands: &mut [Candidate], pivots: &mut Vec<usize>, top_k: Option<usize>, limit: Option<usize>, seed: u64) {
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    let before = pivots.len();
+    let mut keep = pivots.clone();
+    if let Some(k) = top_k {
+        keep.sort_by(|&a, &b| {
+            compare_pivot_priority(&cands[a], &cands[b], None)
+                .then_with(|| cands[a].xml_file.cmp(&cands[b].xml_file))
+                .then_with(|| cands[a].candidate_id.cmp(&cands[b].candidate_id))
+        });
+        keep.truncate(k);
+    }
+    if let Some(n) = limit {
+        if keep.len() > n {
+            let mut rng = StdRng::seed_from_u64(seed);
+            keep.sort_unstable();
+            keep = keep.choose_multiple(&mut rng, n).copied().collect();
+        }
+    }
+    let keep: std::collections::HashSet<usize> = keep.into_iter().collect();
+    pivots.retain(|&i| {
+        let k = keep.contains(&i);
+        if !k {
+            cands[i].is_pivot = false;
+        }
+        k
+    });
+    println!("[INFO] Pivot selection (top_k={top_k:?}, limit={limit:?}, seed={seed}): kept {} of {before} pivots.", pivots.len());
+}
+
+// Stable, so equal keys keep their input order.
+pub fn sort_pivots(cands: &[Candidate], pivots: &mut [usize], order: PivotOrder) {
+    match order {
+        PivotOrder::KeepOrder => {}
+        PivotOrder::Snr => pivots.sort_by(|&a, &b| compare_pivot_priority(&cands[a], &cands[b], None)),
+        PivotOrder::Period => pivots.sort_by(|&a, &b| cands[a].period.total_cmp(&cands[b].period)),
+        PivotOrder::ClusterSize => pivots.sort_by(|&a, &b| {
+            cands[b].related.len().cmp(&cands[a].related.len())
+                .then_with(|| compare_pivot_priority(&cands[a], &cands[b], None))
+        }),
+    }
+}
+
+// Which member of a group becomes its pivot (--pivot-policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PivotPolicy {
+    // The earliest member in the input.
+    InputOrder,
+    // By compare_pivot_priority: highest SNR, then higher nassoc, then shorter period.
+    #[default]
+    Priority,
+    // By compare_longest_tobs: longest file tobs, then as Priority.
+    LongestTobs,
+}
+
+/// Settings for [`pick_candidates`]; mirrors the `candy_picker_rs` command line.
+#[derive(Debug, Clone)]
+pub struct PickParams {
+    pub period_thresh: f64,
+    /// When set, replaces `period_thresh` with `multiplier / tobs` of the first file.
+    pub ptol_auto: Option<f64>,
+    pub dm_thresh: Option<f64>,
+    /// When set, each file's DM gate is this many times its DM trial step (falling back
+    /// to `dm_thresh` for files whose step cannot be parsed).
+    pub dm_tol_steps: Option<f64>,
+    /// +1.0 for the peasoup acc convention, -1.0 to flip the acceleration correction.
+    pub acc_sign: f64,
+    pub acc_reference: AccReference,
+    pub bin_dm: bool,
+    /// Reject period-modulo matches whose implied harmonic ratio exceeds this.
+    pub max_modulo_harmonic: Option<u32>,
+    /// Never relate candidates whose SNRs differ by more than this factor.
+    pub max_snr_ratio: Option<f64>,
+    pub birdies: Option<Vec<Birdie>>,
+    pub birdie_harmonics: u32,
+    pub scale_birdie_width: bool,
+    pub rfi_lines: Option<Vec<RfiLine>>,
+    pub filter: Option<CandidateFilter>,
+    /// Reject candidates whose [`dm_sharpness`] is below this before clustering.
+    pub min_dm_sharpness: Option<f64>,
+    /// Inclusive `candidate_id` range; candidates outside it are dropped from their file
+    /// entirely (they appear in neither the picked nor the rejected output).
+    pub id_range: Option<(i32, i32)>,
+    /// `(xml_file, candidate_id)` pairs dropped right after parsing, like `id_range`.
+    pub veto: Option<Vec<(String, i32)>>,
+    /// Strip version suffixes from uuids right after parsing; see [`UuidNormalizer`].
+    pub normalize_uuid: Option<UuidNormalizer>,
+    /// Only relate candidates whose uuids share the first this-many characters.
+    pub uuid_prefix_len: Option<usize>,
+    /// With `uuid_prefix_len`, drop candidates without a uuid instead of grouping them.
+    pub exclude_missing_uuid: bool,
+    /// Drop candidates below the this-th SNR percentile (0-100) of their own file.
+    pub snr_percentile: Option<f64>,
+    /// Keep only the strongest candidate per file and period bucket of this many ms.
+    pub collapse_period_ms: Option<f64>,
+    pub prefer_label: Option<String>,
+    /// How each connected component's pivot is chosen: by [`compare_pivot_priority`] (highest
+    /// SNR; ties: higher nassoc, then shorter period), [`compare_longest_tobs`], or the
+    /// earliest member. `prefer_label` turns `InputOrder` into `Priority`.
+    pub pivot_policy: PivotPolicy,
+    pub merge_harmonic_clusters: bool,
+    /// Cluster on each candidate's estimated fundamental period (outputs keep the original).
+    pub canonicalize_periods: bool,
+    pub min_related: usize,
+    /// Confirmed sources (`--protect`); see [`pin_protected_pivots`].
+    pub protect: Option<Vec<Candidate>>,
+    /// Print how many pairs clustering compared against the brute-force n*(n-1)/2.
+    pub report_comparisons: bool,
+    /// Demote pivots whose cluster spans less than this acc range (m/s^2).
+    pub min_acc_spread: Option<f64>,
+    /// Warn when the largest cluster holds more than this fraction of all candidates.
+    pub overmerge_warn_frac: f64,
+    pub top_k: Option<usize>,
+    pub limit: Option<usize>,
+    pub seed: u64,
+    /// Row order of the pivots in the outputs.
+    pub sort_by: PivotOrder,
+    /// Turn recoverable input problems (e.g. invalid UTF-8) into errors.
+    pub strict: bool,
+    /// Fail unless every file's `<misc_info><version>` equals this.
+    pub require_version: Option<String>,
+    /// Memory-map the XML inputs instead of reading them into memory.
+    pub mmap: bool,
+    /// Extra attempts for reads failing with transient IO errors (EIO, ETIMEDOUT).
+    pub read_retries: u32,
+    /// Period or frequency form of the period tests in `is_related`.
+    pub relate_on: RelateDomain,
+    /// Also relate pairs whose periods are k = 2..=`hmax` multiples after the acceleration
+    /// correction.
+    pub harmonics: bool,
+    /// Highest k for `harmonics`, `merge_harmonic_clusters` and `canonicalize_periods`.
+    pub hmax: u32,
+    /// Relate a pair when either direction of `is_related` passes.
+    pub symmetric_relate: bool,
+    /// Fail if clustering takes longer than this.
+    pub timeout: Option<std::time::Duration>,
+}
+
+impl Default for PickParams {
+    fn default() -> Self {
+        Self {
+            period_thresh: 1e-6,
+            ptol_auto: None,
+            dm_thresh: None,
+            dm_tol_steps: None,
+            acc_sign: 1.0,
+            acc_reference: AccReference::SelfFrame,
+            bin_dm: false,
+            max_modulo_harmonic: None,
+            max_snr_ratio: None,
+            birdies: None,
+            birdie_harmonics: 16,
+            scale_birdie_width: false,
+            rfi_lines: None,
+            filter: None,
+            min_dm_sharpness: None,
+            id_range: None,
+            veto: None,
+            normalize_uuid: None,
+            uuid_prefix_len: None,
+            exclude_missing_uuid: false,
+            snr_percentile: None,
+            collapse_period_ms: None,
+            prefer_label: None,
+            pivot_policy: PivotPolicy::Priority,
+            merge_harmonic_clusters: false,
+            canonicalize_periods: false,
+            min_related: 0,
+            protect: None,
+            report_comparisons: false,
+            min_acc_spread: None,
+            overmerge_warn_frac: 0.5,
+            top_k: None,
+            limit: None,
+            seed: 0,
+            sort_by: PivotOrder::KeepOrder,
+            strict: false,
+            require_version: None,
+            mmap: false,
+            read_retries: 0,
+            relate_on: RelateDomain::Period,
+            harmonics: false,
+            hmax: 16,
+            symmetric_relate: false,
+            timeout: None,
+        }
+    }
+}
+
+/// Outcome of [`pick_candidates`]. `candidates` holds every candidate that took part in
+/// clustering; `pivots` and each candidate's `related` list index into it. Each candidate
+/// is owned exactly once, here or in `removed`; use [`PickResult::file_candidates`] for a
+/// file's candidates in document order.
+#[derive(Debug)]
+pub struct PickResult {
+    pub files: Vec<XmlFile>,
+    pub candidates: Vec<Candidate>,
+    /// Candidates dropped by the pre-filters (`snr_percentile`, `rfi_lines`, `filter`, ...)
+    /// before clustering; they are never pivots.
+    pub removed: Vec<Candidate>,
+    pub pivots: Vec<usize>,
+    pub tobs_over_c: f64,
+    /// Period threshold actually used (differs from the params under `ptol_auto`).
+    pub period_thresh: f64,
+    /// Every related pair `(i, j)`, `i < j`, found by clustering, before pivots were chosen.
+    pub edges: Vec<(usize, usize)>,
+}
+
+impl PickResult {
+    /// Every candidate, clustered or removed: file by file in document order, or for JSON
+    /// inputs (no `files`) by `xml_file` and then `candidate_id`.
+    pub fn parsed_candidates(&self) -> Vec<&Candidate> {
+        if !self.files.is_empty() {
+            return self.file_candidates().into_iter().flatten().collect();
+        }
+        let mut all: Vec<&Candidate> = self.candidates.iter().chain(&self.removed).collect();
+        all.sort_by(|a, b| a.xml_file.cmp(&b.xml_file).then(a.candidate_id.cmp(&b.candidate_id)));
+        all
+    }
+
+    /// For each of `files`, its candidates (clustered or removed) in document order.
+    pub fn file_candidates(&self) -> Vec<Vec<&Candidate>> {
+        let mut by_id: HashMap<(&str, i32), Vec<&Candidate>> = HashMap::new();
+        for c in self.candidates.iter().chain(&self.removed) {
+            by_id.entry((c.xml_file.as_str(), c.candidate_id)).or_default().push(c);
+        }
+        self.files
+            .iter()
+            .map(|xf| {
+                xf.candidate_ids
+                    .iter()
+                    .filter_map(|&id| {
+                        let same = by_id.get_mut(&(xf.filename.as_str(), id))?;
+                        (!same.is_empty()).then(|| same.remove(0))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Each pivot together with the other members of its cluster (same `cluster_id`).
+    pub fn clusters(&self) -> impl Iterator<Item = (&Candidate, Vec<&Candidate>)> + '_ {
+        let members = cluster_members(&self.candidates);
+        self.pivots.iter().map(move |&i| {
+            let pivot = &self.candidates[i];
+            let others = pivot.cluster_id.and_then(|id| members.get(&id)).into_iter().flatten();
+            (pivot, others.filter(|&&j| j != i).map(|&j| &self.candidates[j]).collect())
+        })
+    }
+
+    /// (xml_file, candidate_id) -> is pivot, for splitting files into picked/rejected.
+    pub fn pivot_map(&self) -> HashMap<(String, i32), bool> {
+        self.pivots
+            .iter()
+            .map(|&i| ((self.candidates[i].xml_file.clone(), self.candidates[i].candidate_id), true))
+            .collect()
+    }
+
+    /// `--baseline`: the status of each pivot (in `pivots` order) against the pivots of an
+    /// earlier run. A pivot matches a baseline pivot when the two would be related under this
+    /// run's clustering settings (the [`AccelerationRelatedness`] of `params`, with a baseline
+    /// pivot taking the `dm_tol` of its file in this run, if any); of several, the closest in
+    /// period is taken. A matched pivot
+    /// has `persisted` when its SNR and membership (itself plus `related_cands`, by uuid or
+    /// `xml_file_candidate_id`) are unchanged, else `changed`; an unmatched one is `new`.
+    /// Also returns how many baseline pivots matched no pivot.
+    pub fn baseline_statuses(&self, baseline: &[BaselinePivot], params: &PickParams) -> (Vec<PivotStatus>, usize) {
+        let rel = AccelerationRelatedness::from_params(params, self.period_thresh, self.tobs_over_c);
+        let file_dm_tol: HashMap<&str, f64> =
+            self.candidates.iter().filter_map(|c| Some((c.xml_file.as_str(), c.dm_tol?))).collect();
+        let baseline_cands: Vec<Candidate> = baseline
+            .iter()
+            .map(|b| {
+                let mut bc = b.cand.clone();
+                bc.uuid_group = params.uuid_prefix_len.and_then(|len| bc.uuid.as_ref().map(|u| u.chars().take(len).collect()));
+                bc.dm_tol = file_dm_tol.get(bc.xml_file.as_str()).copied();
+                bc
+            })
+            .collect();
+        let mut seen = vec![false; baseline.len()];
+        let statuses = self
+            .pivots
+            .iter()
+            .map(|&i| {
+                let c = &self.candidates[i];
+                let mut nearest: Option<usize> = None;
+                for (k, bc) in baseline_cands.iter().enumerate() {
+                    if !rel.related(c, bc) {
+                        continue;
+                    }
+                    seen[k] = true;
+                    let dist = |k: usize| (baseline[k].cand.period - c.period).abs();
+                    if nearest.is_none_or(|n| dist(k) < dist(n)) {
+                        nearest = Some(k);
+                    }
+                }
+                let Some(k) = nearest else {
+                    return PivotStatus::New;
+                };
+                let members: std::collections::BTreeSet<String> =
+                    std::iter::once(c.id_string()).chain(c.related.iter().map(|&j| self.candidates[j].id_string())).collect();
+                if baseline[k].cand.snr == c.snr && baseline[k].members == members {
+                    PivotStatus::Persisted
+                } else {
+                    PivotStatus::Changed
+                }
+            })
+            .collect();
+        (statuses, seen.iter().filter(|&&s| !s).count())
+    }
+}
+
+/// How a pivot compares with an earlier run; see [`PickResult::baseline_statuses`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PivotStatus {
+    New,
+    Persisted,
+    Changed,
+}
+
+impl PivotStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PivotStatus::New => "new",
+            PivotStatus::Persisted => "persisted",
+            PivotStatus::Changed => "changed",
+        }
+    }
+}
+
+/// One pivot of an earlier run's `pivots.csv`.
+pub struct BaselinePivot {
+    cand: Candidate,
+    /// The pivot's own id plus its `related_cands`.
+    members: std::collections::BTreeSet<String>,
+}
+
+/// Read a `pivots.csv` for `--baseline`. Needs the `snr`, `period`, `dm`, `uuid`, `xml_file`,
+/// `candidate_id` and `related_cands` columns; `acc` defaults to 0 when absent.
+pub fn read_baseline_pivots(path: &str) -> Result<Vec<BaselinePivot>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(true).trim(csv::Trim::All).from_reader(open_csv(path)?);
+    let hdr = rdr.headers()?.clone();
+    let opt_col = |name: &str| hdr.iter().position(|h| h == name);
+    let col = |name: &str| opt_col(name).ok_or_else(|| anyhow!("baseline {path}: missing '{name}' column"));
+    let (i_snr, i_period, i_dm, i_uuid) = (col("snr")?, col("period")?, col("dm")?, col("uuid")?);
+    let (i_file, i_id, i_related) = (col("xml_file")?, col("candidate_id")?, col("related_cands")?);
+    let i_acc = opt_col("acc");
+    let mut out = Vec::new();
+    for (lineno, rec) in rdr.records().enumerate() {
+        let rec = rec?;
+        let field = |i: usize| rec.get(i).unwrap_or("");
+        let num = |i: usize| {
+            field(i).parse::<f64>().map_err(|e| anyhow!("baseline {path} line {}: bad {}: {e}", lineno + 2, &hdr[i]))
+        };
+        let uuid = Some(field(i_uuid).to_string()).filter(|u| !u.is_empty());
+        let cand = Candidate::new(
+            num(i_snr)?, num(i_period)?, num(i_dm)?, i_acc.map(num).transpose()?.unwrap_or(0.0), 0,
+            f32::NAN, f32::NAN, MISSING_NASSOC,
+            uuid, String::new(), field(i_file).to_string(), num(i_id)? as i32, String::new(),
+        );
+        let members = std::iter::once(cand.id_string())
+            .chain(field(i_related).split(':').filter(|m| !m.is_empty()).map(str::to_string))
+            .collect();
+        out.push(BaselinePivot { cand, members });
+    }
+    Ok(out)
+}
+
+// --require-version: the file's version must equal `required` (a missing version never
+// matches). A missing version is otherwise only reported under --strict.
+fn check_version(xf: &XmlFile, required: Option<&str>, strict: bool) -> Result<()> {
+    match (&xf.version, required) {
+        (Some(v), Some(r)) if v != r => Err(anyhow!("{} has schema version {v}, but {r} is required", xf.filename)),
+        (None, Some(r)) => Err(anyhow!("{} has no <misc_info><version>, but {r} is required", xf.filename)),
+        (None, None) if strict => {
+            eprintln!("[WARN] {} has no <misc_info><version>; schema version unknown", xf.filename);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parse, cluster and shortlist the candidates of `files`. Nothing is written to disk.
+pub fn pick_candidates(files: &[PathBuf], params: &PickParams) -> Result<PickResult> {
+    pick_parsed(parse_inputs(files, params)?, params)
+}
+
+/// Candidates after parsing and the per-file steps (`id_range`, `veto`, `normalize_uuid`,
+/// `dm_tol_steps`), before any pre-filter or clustering; see [`pick_parsed`].
+pub struct ParsedInputs {
+    /// Empty for JSON inputs. Their candidates have been moved into `candidates`.
+    pub files: Vec<XmlFile>,
+    pub candidates: Vec<Candidate>,
+    pub effective_tobs: f64,
+}
+
+/// The parsing half of [`pick_candidates`].
+pub fn parse_inputs(files: &[PathBuf], params: &PickParams) -> Result<ParsedInputs> {
+    let mut xml_file_objects = Vec::new();
+    let mut all_candidates = Vec::new();
+    let mut veto_hits = vec![false; params.veto.as_ref().map_or(0, |v| v.len())];
+    let mut vetoed = 0;
+    // Parse on the worker pool; the per-file steps below stay sequential, in input order, and
+    // the first failing file (in input order) is the error reported.
+    let parsed: Vec<Result<XmlFile>> = files
+        .par_iter()
+        .map(|f| {
+            if cancelled() {
+                return Err(anyhow!("Parsing cancelled"));
+            }
+            parse_xml_file(&f.to_string_lossy(), params.strict, params.read_retries, params.mmap)
+        })
+        .collect();
+    for xf in parsed {
+        if cancelled() {
+            return Err(anyhow!("Parsing cancelled"));
+        }
+        let mut xf = xf?;
+        check_version(&xf, params.require_version.as_deref(), params.strict)?;
+        if let Some((lo, hi)) = params.id_range {
+            let before = xf.candidates.len();
+            xf.candidates.retain(|c| (lo..=hi).contains(&c.candidate_id));
+            println!("[INFO] {}: kept {} of {before} candidates with ids in [{lo}, {hi}]", xf.filename, xf.candidates.len());
+        }
+        if let Some(veto) = &params.veto {
+            vetoed += apply_veto(&mut xf.candidates, &xf.filename, veto, &mut veto_hits);
+        }
+        if let Some(n) = &params.normalize_uuid {
+            let changed = normalize_uuids(&mut xf.candidates, n);
+            println!("[INFO] {}: normalized {changed} uuids", xf.filename);
+        }
+        if let Some(steps) = params.dm_tol_steps {
+            match xf.dm_step {
+                Some(step) => {
+                    println!("[INFO] {}: DM step {step}, DM gate {}", xf.filename, steps * step);
+                    xf.candidates.iter_mut().for_each(|c| c.dm_tol = Some(steps * step));
+                }
+                None => eprintln!("[WARN] {}: no DM step in dedispersion_trials; using -d ({:?})", xf.filename, params.dm_thresh),
+            }
+        }
+        let tobs = xf.effective_tobs();
+        xf.candidates.iter_mut().for_each(|c| c.tobs = Some(tobs));
+        xf.candidate_ids = xf.candidates.iter().map(|c| c.candidate_id).collect();
+        all_candidates.extend(std::mem::take(&mut xf.candidates));
+        xml_file_objects.push(xf);
+    }
+    if let Some(veto) = &params.veto {
+        report_veto(veto, &veto_hits, vetoed);
+    }
+    let versions: std::collections::BTreeSet<&str> =
+        xml_file_objects.iter().map(|xf| xf.version.as_deref().unwrap_or("unknown")).collect();
+    if versions.len() > 1 {
+        eprintln!("[WARN] Input files have different schema versions: {:?}", versions);
+    }
+    if xml_file_objects.is_empty() {
+        return Err(anyhow!("No candidates found"));
+    }
+    // Mixed observation lengths are the point of longest-tobs; clustering then uses the longest.
+    let mixed_tobs = params.pivot_policy == PivotPolicy::LongestTobs;
+    if xml_file_objects.len() > 1 && !mixed_tobs {
+        for xf in &xml_file_objects[1..] {
+            if xf.fft_size != xml_file_objects[0].fft_size || xf.tsamp != xml_file_objects[0].tsamp {
+                return Err(anyhow!("fft size and tsamp differ across files"));
+            }
+        }
+    }
+    let mut effective_tobs = xml_file_objects[0].effective_tobs();
+    if xml_file_objects.iter().any(|xf| xf.effective_tobs() != effective_tobs) {
+        if !mixed_tobs {
+            return Err(anyhow!("segment lengths differ across files"));
+        }
+        effective_tobs = xml_file_objects.iter().map(XmlFile::effective_tobs).fold(effective_tobs, f64::max);
+        eprintln!("[WARN] Observation lengths differ across files; using the longest, {effective_tobs} s, for the acceleration correction");
+    }
+    if let Some(start) = xml_file_objects[0].segment_start_sample {
+        println!("[INFO] Using segment of {effective_tobs} s starting at sample {start}");
+    }
+    Ok(ParsedInputs { files: xml_file_objects, candidates: all_candidates, effective_tobs })
+}
+
+/// Like [`pick_candidates`] for JSON inputs (see [`read_json_candidates`]). JSON carries
+/// no observation parameters, so `tobs` (seconds) must be given. `PickResult::files` is
+/// empty: there is no XML to split.
+pub fn pick_json_candidates(files: &[PathBuf], tobs: f64, params: &PickParams) -> Result<PickResult> {
+    pick_parsed(parse_json_inputs(files, tobs, params)?, params)
+}
+
+/// The parsing half of [`pick_json_candidates`].
+pub fn parse_json_inputs(files: &[PathBuf], tobs: f64, params: &PickParams) -> Result<ParsedInputs> {
+    let mut all_candidates = Vec::new();
+    let mut veto_hits = vec![false; params.veto.as_ref().map_or(0, |v| v.len())];
+    let mut vetoed = 0;
+    for f in files {
+        let mut cands = read_json_candidates(f, params.read_retries)?;
+        if let Some((lo, hi)) = params.id_range {
+            cands.retain(|c| (lo..=hi).contains(&c.candidate_id));
+        }
+        if let Some(veto) = &params.veto {
+            vetoed += apply_veto(&mut cands, &f.to_string_lossy(), veto, &mut veto_hits);
+        }
+        if let Some(n) = &params.normalize_uuid {
+            let changed = normalize_uuids(&mut cands, n);
+            println!("[INFO] {}: normalized {changed} uuids", f.display());
+        }
+        if let Some(d) = params.dm_tol_steps {
+            eprintln!("[WARN] {}: --dm-tol-steps {d} needs dedispersion_trials; using -d", f.display());
+        }
+        cands.iter_mut().for_each(|c| c.tobs = Some(tobs));
+        all_candidates.extend(cands);
+    }
+    if let Some(veto) = &params.veto {
+        report_veto(veto, &veto_hits, vetoed);
+    }
+    Ok(ParsedInputs { files: Vec::new(), candidates: all_candidates, effective_tobs: tobs })
+}
+
+/// The input-independent part of picking: pre-filters, clustering, shortlisting, selection.
+pub fn pick_parsed(inputs: ParsedInputs, params: &PickParams) -> Result<PickResult> {
+    let ParsedInputs { files, candidates: mut all_candidates, effective_tobs } = inputs;
+    let mut removed = Vec::new();
+    if let Some(p) = params.snr_percentile {
+        apply_snr_percentile(&mut all_candidates, &mut removed, p);
+    }
+    if let Some(len) = params.uuid_prefix_len {
+        group_by_uuid_prefix(&mut all_candidates, &mut removed, len, params.exclude_missing_uuid);
+    }
+    if let Some(lines) = &params.rfi_lines {
+        remove_rfi_line_candidates(&mut all_candidates, &mut removed, lines);
+    }
+    if let Some(filter) = &params.filter {
+        apply_candidate_filter(&mut all_candidates, &mut removed, filter)?;
+    }
+    if let Some(min) = params.min_dm_sharpness {
+        remove_unsharp_candidates(&mut all_candidates, &mut removed, min);
+    }
+    if let Some(res) = params.collapse_period_ms {
+        collapse_by_period_ms(&mut all_candidates, &mut removed, res);
+    }
+    if all_candidates.is_empty() {
+        return Err(anyhow!("No candidates found"));
+    }
+    let tobs_over_c = effective_tobs / SPEED_OF_LIGHT;
+    println!("[INFO] Effective TOBS: {effective_tobs} s");
+    let period_thresh = match params.ptol_auto {
+        Some(m) => {
+            let p = m / effective_tobs;
+            println!("[INFO] Auto period threshold: {m} / {effective_tobs} s = {p}");
+            p
+        }
+        None => params.period_thresh,
+    };
+
+    let prefer_label = params.prefer_label.as_deref();
+
+    // Cluster on canonical (fundamental) periods, then restore the originals for output.
+    let original_periods: Option<Vec<f64>> = params
+        .canonicalize_periods
+        .then(|| canonicalize_periods(&mut all_candidates, period_thresh, params.dm_thresh, params.hmax));
+    let rel = AccelerationRelatedness::from_params(params, period_thresh, tobs_over_c);
+    if rel.symmetric {
+        println!("[INFO] Relating pairs in both frames (--symmetric-relate)");
+    }
+    let comparisons = cluster_candidates(&mut all_candidates, &rel, params.dm_thresh, params.bin_dm, params.timeout)?;
+    if params.report_comparisons {
+        let n = all_candidates.len() as u64;
+        let brute = n * n.saturating_sub(1) / 2;
+        let pruned = if brute > 0 { 100.0 * (brute - comparisons) as f64 / brute as f64 } else { 0.0 };
+        println!("[INFO] Comparisons: {comparisons} of {brute} brute-force pairs ({pruned:.1}% pruned)");
+    }
+    if let Some(periods) = original_periods {
+        for (c, p) in all_candidates.iter_mut().zip(periods) {
+            c.set_period(p);
+        }
+    }
+    let edges: Vec<(usize, usize)> = all_candidates
+        .iter()
+        .enumerate()
+        .flat_map(|(i, c)| c.related.iter().map(move |&j| (i, j)))
+        .collect();
+    let better = |a: &Candidate, b: &Candidate| match params.pivot_policy {
+        PivotPolicy::LongestTobs => compare_longest_tobs(a, b, prefer_label),
+        PivotPolicy::InputOrder if prefer_label.is_none() => std::cmp::Ordering::Equal,
+        _ => compare_pivot_priority(a, b, prefer_label),
+    };
+    let mut pivots = shortlist_candidates_by(
+        &mut all_candidates,
+        better,
+        params.birdies.as_deref(),
+        params.birdie_harmonics,
+        params.scale_birdie_width,
+    );
+    if params.merge_harmonic_clusters {
+        merge_harmonic_clusters(&mut all_candidates, &mut pivots, period_thresh, params.dm_thresh, tobs_over_c, params.acc_sign, params.hmax);
+    }
+    if let Some(protected) = &params.protect {
+        let mut protected = protected.clone();
+        if let Some(len) = params.uuid_prefix_len {
+            // Match within the same uuid group, as clustering does.
+            for p in protected.iter_mut() {
+                p.uuid_group = p.uuid.as_ref().map(|u| u.chars().take(len).collect());
+            }
+        }
+        pin_protected_pivots(&mut all_candidates, &mut pivots, &protected, &rel);
+    }
+    warn_if_overmerged(&all_candidates, &pivots, params.overmerge_warn_frac);
+    if params.min_related > 0 {
+        filter_pivots_by_related(&mut all_candidates, &mut pivots, params.min_related);
+    }
+    if let Some(spread) = params.min_acc_spread {
+        filter_pivots_by_acc_spread(&mut all_candidates, &mut pivots, spread);
+    }
+    if params.top_k.is_some() || params.limit.is_some() {
+        select_pivots(&mut all_candidates, &mut pivots, params.top_k, params.limit, params.seed);
+    }
+    assign_cluster_ids(&mut all_candidates, &pivots);
+    sort_pivots(&all_candidates, &mut pivots, params.sort_by);
+
+    Ok(PickResult { files, candidates: all_candidates, removed, pivots, tobs_over_c, period_thresh, edges })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cand(id: i32, snr: f64, period: f64) -> Candidate {
+        Candidate::new(snr, period, 10.0, 0.0, 2, f32::NAN, f32::NAN, MISSING_NASSOC,
+            None, String::new(), "t.xml".into(), id, String::new())
+    }
+
+    // One peasoup <candidate> with the fields parse_xml_file needs; `period` is written as given.
+    fn candidate_xml(id: i32, period: &str, dm: f64, snr: f64) -> String {
+        format!(
+            "<candidate id='{id}'>\n<period>{period}</period>\n<dm>{dm}</dm>\n<acc>0.0</acc>\n<nh>2</nh>\n<snr>{snr}</snr>\n\
+             <ddm_count_ratio>0.5</ddm_count_ratio>\n<ddm_snr_ratio>0.8</ddm_snr_ratio>\n<nassoc>0</nassoc>\n</candidate>\n"
+        )
+    }
+
+    // A peasoup XML (tsamp 64 us, fft size 2^23) with `header` inside header_parameters,
+    // `extra` sections before <candidates>, and the given candidate blocks.
+    fn search_xml(header: &str, extra: &str, candidates: &str) -> String {
+        format!(
+            "<?xml version='1.0' encoding='ISO-8859-1'?>\n<peasoup_search>\n<header_parameters>\n<tsamp>6.4e-05</tsamp>\n\
+             {header}</header_parameters>\n<search_parameters>\n<size>8388608</size>\n</search_parameters>\n\
+             {extra}<candidates>\n{candidates}</candidates>\n</peasoup_search>\n"
+        )
+    }
+
+    // Write a fixture into a temp directory of this test process and return its path.
+    fn write_fixture(name: &str, contents: impl AsRef<[u8]>) -> String {
+        let dir = std::env::temp_dir().join(format!("candy_picker_rs_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn rel(period_thresh: f64) -> AccelerationRelatedness {
+        AccelerationRelatedness::from_params(&PickParams::default(), period_thresh, 0.0)
+    }
+
+    // Ids of the pivots of `cands` clustered and shortlisted in the given order.
+    fn pivot_ids(mut cands: Vec<Candidate>, period_thresh: f64) -> Vec<i32> {
+        cluster_candidates(&mut cands, &rel(period_thresh), None, false, None).unwrap();
+        let mut ids: Vec<i32> = shortlist_candidates(&mut cands, None, 0, false).iter().map(|&i| cands[i].candidate_id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn acc_sign_flip_recovers_match() {
+        // Detected at 0.5 s in the acc = 0 frame; 0.5001 s is that period seen at acc = 100
+        // m/s^2 over 600 s under the opposite sign convention.
+        let a = cand(0, 10.0, 0.5);
+        let mut b = cand(1, 10.0, 0.5001);
+        b.acc = 100.0;
+        let pos = AccelerationRelatedness { tobs_over_c: 600.0 / SPEED_OF_LIGHT, ..rel(1e-5) };
+        let neg = AccelerationRelatedness { acc_sign: -1.0, ..pos };
+        assert!(!pos.related(&a, &b));
+        assert!(neg.related(&a, &b));
+    }
+
+    #[test]
+    fn segment_length_sets_effective_tobs() {
+        let cands = candidate_xml(0, "0.5", 10.0, 10.0);
+        let segment = "<segment_parameters>\n<segment_start_sample>4194304</segment_start_sample>\n\
+                       <segment_nsamples>1048576</segment_nsamples>\n</segment_parameters>\n";
+        let xf = parse_xml_file(&write_fixture("segment.xml", search_xml("", segment, &cands)), false, 0, false).unwrap();
+        assert_eq!(xf.segment_start_sample, Some(4194304));
+        assert_eq!(xf.effective_tobs(), 1048576.0 * 6.4e-5);
+        let whole = parse_xml_file(&write_fixture("whole.xml", search_xml("", "", &cands)), false, 0, false).unwrap();
+        assert_eq!(whole.effective_tobs(), 8388608.0 * 6.4e-5);
+    }
+
+    #[test]
+    fn invalid_byte_outside_candidates_is_replaced_unless_strict() {
+        let cands = candidate_xml(0, "0.5", 10.0, 10.0) + &candidate_xml(1, "0.25", 10.0, 9.0);
+        // A Latin-1 e-acute (0xE9) in a header comment is not valid UTF-8.
+        let mut bytes = search_xml("<!-- observer: Ren\u{e9} -->\n", "", &cands).into_bytes();
+        let at = bytes.iter().position(|&b| b == 0xC3).unwrap();
+        bytes.splice(at..at + 2, [0xE9]);
+        let path = write_fixture("latin1.xml", &bytes);
+        assert!(std::str::from_utf8(&fs::read(&path).unwrap()).is_err());
+        let xf = parse_xml_file(&path, false, 0, false).unwrap();
+        assert_eq!(xf.candidate_ids, [0, 1]);
+        assert!(parse_xml_file(&path, true, 0, false).is_err());
+        assert!(parse_xml_file(&path, true, 0, true).is_err());
+        assert_eq!(parse_xml_file(&path, false, 0, true).unwrap().candidate_ids, [0, 1]);
+    }
+
+    #[test]
+    fn max_modulo_harmonic_caps_the_implied_ratio() {
+        let base = cand(0, 10.0, 0.125);
+        let (ninth, third) = (cand(1, 10.0, 1.125), cand(2, 10.0, 0.375));
+        let capped = AccelerationRelatedness { max_modulo_harmonic: Some(4), ..rel(1e-4) };
+        assert!(rel(1e-4).related(&base, &ninth));
+        assert!(!capped.related(&base, &ninth));
+        assert!(capped.related(&base, &third));
+        for domain in [RelateDomain::Period, RelateDomain::Frequency] {
+            assert!(!AccelerationRelatedness { domain, ..capped }.related(&ninth, &base));
+        }
+    }
+
+    #[test]
+    fn zero_acc_reference_is_symmetric() {
+        let self_frame = AccelerationRelatedness { tobs_over_c: 600.0 / SPEED_OF_LIGHT, ..rel(1e-6) };
+        let zero = AccelerationRelatedness { acc_ref: AccReference::Zero, ..self_frame };
+        let a = cand(0, 10.0, 0.5);
+        let mut asymmetric = 0;
+        for acc in [-150.0, 40.0, 100.0] {
+            // Periods around the 0.5 s signal seen at `acc`, spanning the 1 us threshold.
+            let x = acc * self_frame.tobs_over_c;
+            for k in -30..=30 {
+                let mut b = cand(1, 10.0, 0.5 * (1.0 - x) + k as f64 * 1e-7);
+                b.acc = acc;
+                assert_eq!(zero.related(&a, &b), zero.related(&b, &a), "acc {acc}, k {k}");
+                asymmetric += (self_frame.related(&a, &b) != self_frame.related(&b, &a)) as usize;
+            }
+        }
+        assert!(asymmetric > 0, "the self frame should be asymmetric somewhere on this grid");
+    }
+
+    #[test]
+    fn uuid_prefixes_isolate_pointings() {
+        // The same 0.5 s signal in two pointings (uuid prefixes PA and PB), plus one without a uuid.
+        let with_uuid = |id, uuid: Option<&str>| Candidate { uuid: uuid.map(String::from), ..cand(id, 10.0 - f64::from(id), 0.5) };
+        let pointings = || {
+            vec![
+                with_uuid(0, Some("PA-0001")),
+                with_uuid(1, Some("PB-0001")),
+                with_uuid(2, Some("PA-0002")),
+                with_uuid(3, None),
+                with_uuid(4, Some("PA-0003")),
+            ]
+        };
+        assert_eq!(pivot_ids(pointings(), 1e-3), [0]);
+
+        let mut cands = pointings();
+        let mut removed = Vec::new();
+        group_by_uuid_prefix(&mut cands, &mut removed, 2, false);
+        assert_eq!(pivot_ids(cands, 1e-3), [0, 1, 3]);
+
+        let mut cands = pointings();
+        group_by_uuid_prefix(&mut cands, &mut removed, 2, true);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(pivot_ids(cands, 1e-3), [0, 1]);
+    }
+
+    #[test]
+    fn fortran_exponents_parse_unless_strict() {
+        assert_eq!(parse_float::<f64>("1.234D-03", false), Some(1.234e-3));
+        assert_eq!(parse_float::<f64>(" 2.5d+01 ", false), Some(25.0));
+        assert_eq!(parse_float::<f64>("1.5f", false), Some(1.5));
+        assert_eq!(parse_float::<f64>("1.234D-03", true), None);
+
+        let path = write_fixture("fortran.xml", search_xml("", "", &candidate_xml(0, "1.234D-03", 10.0, 9.0)));
+        let xf = parse_xml_file(&path, false, 0, false).unwrap();
+        assert_eq!(xf.candidates[0].period, 1.234e-3);
+        assert!(parse_xml_file(&path, true, 0, false).is_err());
+    }
+
+    #[test]
+    fn header_comment_survives_in_section_text() {
+        let header = "<!-- rerun with new mask -->\n<source_name>J0000</source_name>\n";
+        let path = write_fixture("comment.xml", search_xml(header, "", &candidate_xml(0, "0.01", 10.0, 9.0)));
+        let xf = parse_xml_file(&path, false, 0, false).unwrap();
+        let section = xf.sections.header_parameters.unwrap();
+        assert!(section.starts_with("<header_parameters>"));
+        assert!(section.contains(header), "{}", section);
+    }
+
+    #[test]
+    fn max_snr_ratio_splits_bright_and_faint_pairs() {
+        let gated = AccelerationRelatedness { max_snr_ratio: Some(100.0), ..rel(1e-3) };
+        let bright = cand(0, 500.0, 0.5);
+        let (inside, outside) = (cand(1, 6.0, 0.5001), cand(2, 4.0, 0.5001));
+        assert!(rel(1e-3).related(&bright, &outside));
+        assert!(gated.related(&bright, &inside));
+        assert!(!gated.related(&bright, &outside) && !gated.related(&outside, &bright));
+    }
+
+    #[test]
+    fn cluster_size_order_puts_bigger_cluster_first() {
+        // Pivot 0 (SNR 20) has 2 related members, pivot 3 (SNR 10) has 10.
+        let mut cands: Vec<Candidate> = (0..3).map(|i| cand(i, 20.0 - f64::from(i), 0.31 + 1e-5 * f64::from(i))).collect();
+        cands.extend((3..14).map(|i| cand(i, 13.0 - f64::from(i) * 0.1, 0.47 + 1e-5 * f64::from(i))));
+        cluster_candidates(&mut cands, &rel(1e-3), None, false, None).unwrap();
+        let mut pivots = shortlist_candidates(&mut cands, None, 0, false);
+        sort_pivots(&cands, &mut pivots, PivotOrder::Snr);
+        assert_eq!(pivots, [0, 3]);
+        sort_pivots(&cands, &mut pivots, PivotOrder::ClusterSize);
+        assert_eq!(pivots, [3, 0]);
+        assert_eq!((cands[3].related.len(), cands[0].related.len()), (10, 2));
+    }
+
+    #[test]
+    fn nested_candidate_block_is_sliced_whole() {
+        let outer = "<candidate id='0'>\n<period>0.01</period>\n<candidate id='sub'>\n<period>0.02</period>\n</candidate>\n\
+                     <candidate/>\n<snr>9</snr>\n</candidate>";
+        let xml = format!("<candidates>\n{outer}\n<candidate id='1'>\n<period>0.03</period>\n</candidate>\n</candidates>");
+        assert_eq!(&xml[slice_candidate_block(&xml, 0).unwrap()], outer);
+        assert_eq!(&xml[slice_candidate_block(&xml, 1).unwrap()], "<candidate id='1'>\n<period>0.03</period>\n</candidate>");
+        // Unbalanced tags give None, so the caller falls back to xmltree.
+        assert_eq!(slice_candidate_block("<candidate id='2'>\n<candidate>\n</candidate>", 2), None);
+    }
+
+    #[test]
+    fn symmetric_relate_clusters_a_one_way_pair() {
+        let one_way = AccelerationRelatedness { tobs_over_c: 600.0 / SPEED_OF_LIGHT, ..rel(1e-6) };
+        let both_ways = AccelerationRelatedness { symmetric: true, ..one_way };
+        // A 0.5 s signal seen at 0 and at 100 m/s^2 that matches in one of the two frames only.
+        let x = 100.0 * one_way.tobs_over_c;
+        let (first, second) = (-30..=30)
+            .find_map(|k| {
+                let a = cand(0, 10.0, 0.5);
+                let mut b = cand(1, 9.0, 0.5 * (1.0 - x) + f64::from(k) * 1e-7);
+                b.acc = 100.0;
+                match (one_way.related(&a, &b), one_way.related(&b, &a)) {
+                    (false, true) => Some((a, b)),
+                    (true, false) => Some((b, a)),
+                    _ => None,
+                }
+            })
+            .expect("a pair related in one direction only");
+        assert!(both_ways.related(&first, &second));
+
+        // cluster_candidates only asks related(earlier, later), so order decides without it.
+        for (rel, clusters) in [(one_way, 2), (both_ways, 1)] {
+            let mut cands = vec![first.clone(), second.clone()];
+            cluster_candidates(&mut cands, &rel, None, false, None).unwrap();
+            assert_eq!(shortlist_candidates(&mut cands, None, 0, false).len(), clusters);
+        }
+    }
+
+    #[test]
+    fn snr_tie_goes_to_higher_nassoc() {
+        let mut few = cand(0, 10.0, 0.5);
+        few.nassoc = 1;
+        let mut many = cand(1, 10.0, 0.5001);
+        many.nassoc = 3;
+        assert_eq!(compare_pivot_priority(&many, &few, None), std::cmp::Ordering::Less);
+        assert_eq!(pivot_ids(vec![few.clone(), many.clone()], 1e-3), [1]);
+        assert_eq!(pivot_ids(vec![many, few], 1e-3), [1]);
+    }
+
+    #[test]
+    fn period_and_frequency_domains_on_the_same_pairs() {
+        let period = rel(1e-4);
+        let frequency = AccelerationRelatedness { domain: RelateDomain::Frequency, ..period };
+        let short = cand(0, 10.0, 0.1);
+        // (longer period, related by period, related by frequency)
+        let pairs = [
+            (0.10005, true, true),   // within p of each other
+            (0.20005, true, true),   // just above 2 P_short: the modulo is 5e-5
+            (0.19995, false, true),  // just below 2 P_short: only the nearest-k test sees it
+            (0.1503, false, false),  // halfway between harmonics
+        ];
+        for (p_long, by_period, by_frequency) in pairs {
+            let long = cand(1, 10.0, p_long);
+            assert_eq!(period.related(&short, &long), by_period, "period domain, {p_long}");
+            assert_eq!(frequency.related(&short, &long), by_frequency, "frequency domain, {p_long}");
+        }
+    }
+
+    #[test]
+    fn galactic_coordinates_of_reference_positions() {
+        // Angular offsets in l wrap at 360 deg.
+        let dl = |l: f64, want: f64| ((l - want + 180.0).rem_euclid(360.0) - 180.0).abs();
+        // (RA, Dec, l, b) in degrees of the Galactic centre and the Crab.
+        for (ra, dec, want_l, want_b) in [(266.405, -28.936, 0.0, 0.0), (83.633, 22.0145, 184.557, -5.784)] {
+            let (l, b) = equatorial_to_galactic(ra, dec);
+            assert!(dl(l, want_l) < 2e-3 && (b - want_b).abs() < 2e-3, "({ra}, {dec}) -> ({l}, {b})");
+            assert!((0.0..360.0).contains(&l));
+        }
+        // At the north Galactic pole only b is defined.
+        let (_, b) = equatorial_to_galactic(192.8595, 27.1283);
+        assert!((b - 90.0).abs() < 1e-3, "NGP b = {b}");
+    }
+
+    #[test]
+    fn custom_relatedness_drives_clustering() {
+        // Relate any two candidates at the same DM, whatever their periods.
+        struct SameDm;
+        impl Relatedness for SameDm {
+            fn related(&self, a: &Candidate, b: &Candidate) -> bool {
+                a.dm == b.dm
+            }
+        }
+        let mut cands = vec![cand(0, 12.0, 0.5), cand(1, 10.0, 0.3), cand(2, 9.0, 0.7)];
+        cands[2].dm = 20.0;
+        let pairs = cluster_candidates(&mut cands, &SameDm, None, false, None).unwrap();
+        assert_eq!(pairs, 3);
+        let mut pivots: Vec<i32> = shortlist_candidates(&mut cands, None, 0, false).iter().map(|&i| cands[i].candidate_id).collect();
+        pivots.sort_unstable();
+        assert_eq!(pivots, [0, 2]);
+    }
+
+    #[test]
+    fn protected_weak_candidate_beats_stronger_rfi() {
+        // Strong RFI and a weak real signal share the period to within p = 1 ms.
+        let mut cands = vec![cand(0, 50.0, 0.5), cand(1, 8.0, 0.5004)];
+        let protected = [cand(7, 6.0, 0.50041)];
+        cluster_candidates(&mut cands, &rel(1e-3), None, false, None).unwrap();
+        let mut pivots = shortlist_candidates(&mut cands, None, 0, false);
+        assert_eq!(pivots, [0]);
+        assert_eq!(pin_protected_pivots(&mut cands, &mut pivots, &protected, &rel(1e-3)), 1);
+        assert_eq!(pivots, [1]);
+        assert!(cands[1].is_pivot && !cands[0].is_pivot);
+        assert_eq!(cands[1].related, [0]);
+    }
+
+    #[test]
+    fn snr_percentile_is_per_file() {
+        let mut values: Vec<f64> = (1..=11).rev().map(f64::from).collect();
+        // Rank 7.5 of 1..=11 lies halfway between 8 and 9.
+        assert_eq!(percentile(&mut values, 75.0), 8.5);
+
+        let mut cands: Vec<Candidate> = (1..=11).map(|i| cand(i, f64::from(i), 0.1 * f64::from(i))).collect();
+        // Too few for a percentile: all five survive.
+        cands.extend((1..=5).map(|i| Candidate { xml_file: "few.xml".into(), ..cand(i, f64::from(i), 0.1) }));
+        let mut removed = Vec::new();
+        apply_snr_percentile(&mut cands, &mut removed, 75.0);
+        let kept = |file: &str| cands.iter().filter(|c| c.xml_file == file).map(|c| c.snr).collect::<Vec<_>>();
+        assert_eq!(kept("t.xml"), [9.0, 10.0, 11.0]);
+        assert_eq!(kept("few.xml").len(), 5);
+        assert_eq!(removed.len(), 8);
+    }
+
+    #[test]
+    fn longest_tobs_policy_picks_the_longer_observation() {
+        let detection = |id, file: &str, tobs| Candidate { xml_file: file.into(), tobs: Some(tobs), ..cand(id, 10.0, 0.5) };
+        let longest = |cands: &mut Vec<Candidate>| {
+            cluster_candidates(cands, &rel(1e-3), None, false, None).unwrap();
+            let pivots = shortlist_candidates_by(cands, |a, b| compare_longest_tobs(a, b, None), None, 0, false);
+            pivots.iter().map(|&i| cands[i].xml_file.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(longest(&mut vec![detection(0, "short.xml", 600.0), detection(0, "long.xml", 1200.0)]), ["long.xml"]);
+        // Equal tobs falls back to SNR.
+        let mut tied = vec![detection(0, "a.xml", 600.0), Candidate { snr: 12.0, ..detection(0, "b.xml", 600.0) }];
+        assert_eq!(longest(&mut tied), ["b.xml"]);
+    }
+
+    #[test]
+    fn chain_is_one_cluster_pivoted_on_highest_snr_in_any_order() {
+        // 0 relates to 1 and 1 to 2, but 0 not to 2; 3 is on its own.
+        let all = [cand(0, 8.0, 0.125), cand(1, 9.0, 0.12575), cand(2, 12.0, 0.1265), cand(3, 7.0, 0.3)];
+        for order in [[0, 1, 2, 3], [2, 0, 3, 1], [3, 1, 2, 0], [1, 3, 0, 2]] {
+            let cands = order.iter().map(|&k| all[k].clone()).collect();
+            assert_eq!(pivot_ids(cands, 1e-3), vec![2, 3], "order {order:?}");
+        }
+    }
+
+    #[test]
+    fn cluster_members_follow_cluster_ids() {
+        let mut cands = vec![cand(0, 8.0, 0.125), cand(1, 9.0, 0.12575), cand(2, 12.0, 0.1265), cand(3, 7.0, 0.3)];
+        cluster_candidates(&mut cands, &rel(1e-3), None, false, None).unwrap();
+        let pivots = shortlist_candidates(&mut cands, None, 0, false);
+        assign_cluster_ids(&mut cands, &pivots);
+        let members = cluster_members(&cands);
+        assert_eq!(members[&cands[2].cluster_id.unwrap()], vec![0, 1, 2]);
+        assert_eq!(members[&cands[3].cluster_id.unwrap()], vec![3]);
+    }
+}
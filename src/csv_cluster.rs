@@ -1,10 +1,13 @@
 // src/csv_cluster.rs
 use anyhow::{anyhow, Context, Result};
-use csv::{ReaderBuilder, StringRecord, Writer};
+use csv::{ReaderBuilder, StringRecord};
 use std::cmp::Ordering;
-use std::fs::File;
+use std::collections::HashMap;
 use std::path::Path;
 
+use crate::provenance::{create_csv_writer, skip_provenance};
+use crate::retry::read_with_retries;
+
 const SPEED_OF_LIGHT: f64 = 299_792_458.0;
 
 #[derive(Clone, Debug)]
@@ -17,7 +20,8 @@ struct RowView {
     period_s: f64,
     dm: f64,
     acc: f64,
-    snr: f64,
+    /// Pivot-ranking key (the --rank-col value, or SNR).
+    rank: f64,
 }
 
 /// Which column set we’re using.
@@ -25,6 +29,7 @@ struct RowView {
 enum Schema {
     FoldSearch, // (#id, dm_new, p0_new, acc_new, S/N_new, ...)
     Pics,       // (dm_opt, f0_opt, acc_opt, sn_fold, ...)
+    Pivots,     // candy_picker_rs pivots.csv (snr, period, dm, acc, ...)
 }
 
 #[derive(Clone, Debug)]
@@ -33,74 +38,175 @@ struct ColMap {
     schema: Schema,
     idx_period_like: usize, // p0_new or f0_opt
     idx_dm: usize,          // dm_new or dm_opt
-    idx_acc: usize,         // acc_new or acc_opt
+    idx_acc: Option<usize>, // acc_new or acc_opt; None when the CSV has no acc column
     idx_snr: usize,         // S/N_new or sn_fold
+    idx_rank: usize,        // --rank-col if present, else idx_snr
     // Whether idx_period_like is already a period (true) or a frequency f0 (false).
     is_period: bool,
+    // Multipliers from the header's unit tags to s (or Hz), pc/cm^3 and m/s^2.
+    period_like_scale: f64,
+    dm_scale: f64,
+    acc_scale: f64,
+}
+
+/// A header name without surrounding whitespace and its bracketed unit, if any:
+/// ` p0_new [ms] ` -> (`p0_new`, Some(`ms`)).
+fn split_unit(h: &str) -> (&str, Option<&str>) {
+    let h = h.trim();
+    match h.strip_suffix(']').and_then(|r| r.rsplit_once('[')) {
+        Some((name, unit)) => (name.trim(), Some(unit.trim())),
+        None => (h, None),
+    }
 }
 
 fn find_col(header: &StringRecord, name: &str) -> Option<usize> {
-    header.iter().position(|h| h.trim() == name)
+    header.iter().position(|h| split_unit(h).0 == name)
+}
+
+/// Multiplier taking values in the unit tag of column `idx` to the base unit of `kind`
+/// (1 when untagged). Unknown tags are an error rather than a silent mis-scaling.
+fn unit_scale(header: &StringRecord, idx: usize, kind: &str) -> Result<f64> {
+    let (name, unit) = split_unit(header.get(idx).unwrap_or(""));
+    let Some(unit) = unit else { return Ok(1.0) };
+    let scale = match (kind, unit) {
+        ("period", "s") => 1.0,
+        ("period", "ms") => 1e-3,
+        ("period", "us" | "µs") => 1e-6,
+        ("f0", "Hz") => 1.0,
+        ("f0", "mHz") => 1e-3,
+        ("f0", "kHz") => 1e3,
+        ("f0", "MHz") => 1e6,
+        ("dm", "pc/cc" | "pc/cm3" | "pc/cm^3" | "pc cm^-3") => 1.0,
+        ("acc", "m/s^2" | "m/s/s" | "m/s2") => 1.0,
+        _ => return Err(anyhow!("Unsupported unit [{}] on column {} (see the README for supported units)", unit, name)),
+    };
+    Ok(scale)
 }
 
 fn detect_schema(header: &StringRecord) -> Result<ColMap> {
+    let mut cols = detect_columns(header)?;
+    cols.period_like_scale = unit_scale(header, cols.idx_period_like, if cols.is_period { "period" } else { "f0" })?;
+    cols.dm_scale = unit_scale(header, cols.idx_dm, "dm")?;
+    if let Some(i) = cols.idx_acc {
+        cols.acc_scale = unit_scale(header, i, "acc")?;
+    }
+    Ok(cols)
+}
+
+fn detect_columns(header: &StringRecord) -> Result<ColMap> {
     // Try FoldSearch first
-    if let (Some(i_p0), Some(i_dm), Some(i_acc), Some(i_snr)) = (
+    if let (Some(i_p0), Some(i_dm), Some(i_snr)) = (
         find_col(header, "p0_new"),
         find_col(header, "dm_new"),
-        find_col(header, "acc_new"),
         find_col(header, "S/N_new"),
     ) {
         return Ok(ColMap {
             schema: Schema::FoldSearch,
             idx_period_like: i_p0,
             idx_dm: i_dm,
-            idx_acc: i_acc,
+            idx_acc: find_col(header, "acc_new"),
             idx_snr: i_snr,
+            idx_rank: i_snr,
             is_period: true,
+            period_like_scale: 1.0,
+            dm_scale: 1.0,
+            acc_scale: 1.0,
         });
     }
 
     // Then PICS / TRAPUM style
-    if let (Some(i_f0), Some(i_dm), Some(i_acc), Some(i_snr)) = (
+    if let (Some(i_f0), Some(i_dm), Some(i_snr)) = (
         find_col(header, "f0_opt"),
         find_col(header, "dm_opt"),
-        find_col(header, "acc_opt"),
         find_col(header, "sn_fold"),
     ) {
         return Ok(ColMap {
             schema: Schema::Pics,
             idx_period_like: i_f0,
             idx_dm: i_dm,
-            idx_acc: i_acc,
+            idx_acc: find_col(header, "acc_opt"),
             idx_snr: i_snr,
+            idx_rank: i_snr,
             is_period: false, // it's f0; convert to period = 1/f0
+            period_like_scale: 1.0,
+            dm_scale: 1.0,
+            acc_scale: 1.0,
+        });
+    }
+
+    // Finally candy_picker_rs output
+    if let (Some(i_p), Some(i_dm), Some(i_snr)) = (
+        find_col(header, "period"),
+        find_col(header, "dm"),
+        find_col(header, "snr"),
+    ) {
+        return Ok(ColMap {
+            schema: Schema::Pivots,
+            idx_period_like: i_p,
+            idx_dm: i_dm,
+            idx_acc: find_col(header, "acc"),
+            idx_snr: i_snr,
+            idx_rank: i_snr,
+            is_period: true,
+            period_like_scale: 1.0,
+            dm_scale: 1.0,
+            acc_scale: 1.0,
         });
     }
 
     Err(anyhow!(
-        "Unsupported CSV header: could not find either \
-         (p0_new, dm_new, acc_new, S/N_new) or (f0_opt, dm_opt, acc_opt, sn_fold)."
+        "Unsupported CSV header: could not find \
+         (p0_new, dm_new, S/N_new), (f0_opt, dm_opt, sn_fold) or (period, dm, snr) \
+         (acc_new/acc_opt/acc optional)."
     ))
 }
 
+/// Index of the SNR column of a header in one of the supported schemas (S/N_new, sn_fold
+/// or snr), or None when the schema is not recognised. Used by csv_matcher for ranking.
+pub fn snr_column(header: &StringRecord) -> Option<usize> {
+    detect_schema(header).ok().map(|c| c.idx_snr)
+}
+
+/// Point the ranking at `rank_col` when the header has it; otherwise keep SNR and warn.
+fn apply_rank_col(cols: &mut ColMap, header: &StringRecord, rank_col: Option<&str>, path: &str) {
+    if let Some(name) = rank_col {
+        match find_col(header, name) {
+            Some(i) => cols.idx_rank = i,
+            None => eprintln!(
+                "[WARN] Rank column '{}' not found in {}; ranking by SNR instead.",
+                name, path
+            ),
+        }
+    }
+}
+
 fn parse_row(cols: &ColMap, rec: &StringRecord, src: &str) -> Option<RowView> {
     // Defensive: ensure row has enough columns
     let get = |i: usize| rec.get(i).unwrap_or("").trim();
 
-    let dm = get(cols.idx_dm).parse::<f64>().ok()?;
-    let acc = get(cols.idx_acc).parse::<f64>().ok()?;
+    let dm = get(cols.idx_dm).parse::<f64>().ok()? * cols.dm_scale;
+    // Without an acc column the acceleration correction is a no-op.
+    let acc = match cols.idx_acc {
+        Some(i) => get(i).parse::<f64>().ok()? * cols.acc_scale,
+        None => 0.0,
+    };
     let snr = get(cols.idx_snr).parse::<f64>().ok()?;
+    // Unparseable rank values become NaN and sort last, like non-finite SNRs.
+    let rank = if cols.idx_rank == cols.idx_snr {
+        snr
+    } else {
+        get(cols.idx_rank).parse::<f64>().unwrap_or(f64::NAN)
+    };
 
     let period_s = if cols.is_period {
-        let p = get(cols.idx_period_like).parse::<f64>().ok()?;
+        let p = get(cols.idx_period_like).parse::<f64>().ok()? * cols.period_like_scale;
         if p <= 0.0 || !p.is_finite() {
             return None;
         }
         p
     } else {
         // f0 → period
-        let f0 = get(cols.idx_period_like).parse::<f64>().ok()?;
+        let f0 = get(cols.idx_period_like).parse::<f64>().ok()? * cols.period_like_scale;
         if f0 <= 0.0 || !f0.is_finite() {
             return None;
         }
@@ -116,7 +222,7 @@ fn parse_row(cols: &ColMap, rec: &StringRecord, src: &str) -> Option<RowView> {
         period_s,
         dm,
         acc,
-        snr,
+        rank,
     })
 }
 
@@ -143,6 +249,8 @@ fn periods_match(
     }
 
     // Acceleration correction (match b to a's frame)
+    // f' = f * (1 - (acc_b - acc_a) * tobs / c) is linear in f, so k * p_b_corr below is
+    // exactly the corrected period of b's k-th subharmonic f_b / k.
     let tobs_over_c = tobs_opt.unwrap_or(600.0) / SPEED_OF_LIGHT;
     let f0_b = 1.0 / b.period_s;
     let p_b_corr = 1.0 / (f0_b - (b.acc - a.acc) * f0_b * tobs_over_c);
@@ -166,7 +274,7 @@ fn periods_match(
     false
 }
 
-/// Greedy SNR-first clustering. Higher SNR rows win; all related rows are suppressed.
+/// Greedy rank-first clustering. Higher rank (SNR by default) rows win; all related rows are suppressed.
 fn cluster_rows(
     mut rows: Vec<RowView>,
     ptol_abs: f64,
@@ -175,18 +283,18 @@ fn cluster_rows(
     allow_harmonics: bool,
     tobs_opt: Option<f64>,
 ) -> Vec<RowView> {
-    // Sort by SNR descending so the first time we see a cluster we keep the strongest.
+    // Sort by rank descending so the first time we see a cluster we keep the strongest.
     rows.sort_by(|a, b| {
-        // NaNs sorted to end, otherwise descending snr
-        if !a.snr.is_finite() && !b.snr.is_finite() {
+        // NaNs sorted to end, otherwise descending rank
+        if !a.rank.is_finite() && !b.rank.is_finite() {
             Ordering::Equal
-        } else if !a.snr.is_finite() {
+        } else if !a.rank.is_finite() {
             Ordering::Greater
-        } else if !b.snr.is_finite() {
+        } else if !b.rank.is_finite() {
             Ordering::Less
         } else {
-            b.snr
-                .partial_cmp(&a.snr)
+            b.rank
+                .partial_cmp(&a.rank)
                 .unwrap_or(Ordering::Equal)
         }
     });
@@ -225,17 +333,26 @@ fn cluster_rows(
 }
 
 /// Read a CSV, detect schema, return (header, rows)
-fn read_one_csv(path: &str) -> Result<(Vec<String>, Vec<RowView>)> {
-    let file = File::open(path).with_context(|| format!("open {}", path))?;
+fn read_one_csv(path: &str, rank_col: Option<&str>, read_retries: u32) -> Result<(Vec<String>, Vec<RowView>)> {
+    let bytes = read_with_retries(path, read_retries).with_context(|| format!("open {}", path))?;
+    let mut data = bytes.as_slice();
+    skip_provenance(&mut data)?;
     let mut rdr = ReaderBuilder::new()
         .has_headers(true)
-        .from_reader(file);
+        .from_reader(data);
 
     let hdr = rdr
         .headers()
         .with_context(|| format!("read header of {}", path))?
         .clone();
-    let colmap = detect_schema(&hdr).with_context(|| format!("detect schema in {}", path))?;
+    let mut colmap = detect_schema(&hdr).with_context(|| format!("detect schema in {}", path))?;
+    apply_rank_col(&mut colmap, &hdr, rank_col, path);
+    if colmap.idx_acc.is_none() {
+        eprintln!(
+            "[WARN] No acc column in {}; assuming acc = 0 (no acceleration correction).",
+            path
+        );
+    }
 
     let header_vec: Vec<String> = hdr.iter().map(|s| s.to_string()).collect();
 
@@ -250,9 +367,10 @@ fn read_one_csv(path: &str) -> Result<(Vec<String>, Vec<RowView>)> {
     Ok((header_vec, out_rows))
 }
 
-/// Write rows with the header (plus optional source_col appended).
-fn write_csv(output: &str, header: &[String], rows: &[RowView], source_col: Option<&str>) -> Result<()> {
-    let mut wtr = Writer::from_path(output)
+/// Write rows with the header (plus optional source_col appended), after the optional
+/// provenance comment line.
+fn write_csv(output: &str, header: &[String], rows: &[RowView], source_col: Option<&str>, provenance: Option<&str>) -> Result<()> {
+    let mut wtr = create_csv_writer(output, provenance)
         .with_context(|| format!("create output {}", output))?;
 
     if let Some(sc) = source_col {
@@ -278,6 +396,46 @@ fn write_csv(output: &str, header: &[String], rows: &[RowView], source_col: Opti
     Ok(())
 }
 
+/// Human-readable label for the period decade `[10^e, 10^(e+1))` seconds, e.g. `1ms-10ms`.
+fn decade_label(e: i32) -> String {
+    fn unit(e: i32) -> String {
+        match e {
+            0.. => format!("{}s", 10f64.powi(e)),
+            -3..=-1 => format!("{}ms", 10f64.powi(e + 3)),
+            -6..=-4 => format!("{}us", 10f64.powi(e + 6)),
+            _ => format!("1e{}s", e),
+        }
+    }
+    format!("{}-{}", unit(e), unit(e + 1))
+}
+
+/// `out.csv` + `1ms-10ms` -> `out_1ms-10ms.csv`.
+fn decade_path(output: &str, label: &str) -> String {
+    let p = Path::new(output);
+    let stem = p.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match p.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, label, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, label),
+    };
+    p.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Route picked rows into one file per period decade (lower bound inclusive, upper
+/// exclusive). Decades without rows produce no file.
+fn write_period_split(output: &str, header: &[String], rows: &[RowView], source_col: Option<&str>, provenance: Option<&str>) -> Result<()> {
+    let mut decades: std::collections::BTreeMap<i32, Vec<RowView>> = std::collections::BTreeMap::new();
+    for r in rows {
+        let e = r.period_s.log10().floor() as i32;
+        decades.entry(e).or_default().push(r.clone());
+    }
+    for (e, rs) in &decades {
+        let path = decade_path(output, &decade_label(*e));
+        write_csv(&path, header, rs, source_col, provenance)?;
+        println!("[INFO]  {} rows → {}", rs.len(), path);
+    }
+    Ok(())
+}
+
 /// Public entry called from the bin.
 ///
 /// - `inputs`: one or more CSV paths
@@ -288,6 +446,14 @@ fn write_csv(output: &str, header: &[String], rows: &[RowView], source_col: Opti
 /// - `allow_harmonics`: enable/disable harmonic matching
 /// - `tobs_opt`: optional TOBS seconds for acceleration correction (default 600s if None)
 /// - `source_col`: optional new column name to append with the source filename
+/// - `group_col`: optional column whose value partitions the rows; each group is clustered
+///   on its own. A name equal to `source_col` that no input header has groups by source file
+/// - `rank_col`: optional column to rank pivots by instead of the detected SNR column
+/// - `period_split`: write one file per period decade instead of a single `output`
+/// - `read_retries`: extra attempts for input reads failing with transient IO errors
+/// - `strict_csv`: fail instead of warning when an input header differs from the first
+/// - `provenance`: comment line (see `provenance::provenance_line`) written above each output header
+#[allow(clippy::too_many_arguments)]
 pub fn cluster_csv_multi(
     inputs: &[String],
     output: &str,
@@ -297,6 +463,12 @@ pub fn cluster_csv_multi(
     allow_harmonics: bool,
     tobs_opt: Option<f64>,
     source_col: Option<&str>,
+    group_col: Option<&str>,
+    rank_col: Option<&str>,
+    period_split: bool,
+    read_retries: u32,
+    strict_csv: bool,
+    provenance: Option<&str>,
 ) -> Result<()> {
     if inputs.is_empty() {
         return Err(anyhow!("No input CSVs provided"));
@@ -314,9 +486,11 @@ pub fn cluster_csv_multi(
 
     let mut all_rows: Vec<RowView> = Vec::new();
     let mut first_header: Option<Vec<String>> = None;
+    // Group key per row, parallel to all_rows; dropped if any input lacks the group column.
+    let mut group_keys: Option<Vec<String>> = group_col.map(|_| Vec::new());
 
     for (k, p) in inputs.iter().enumerate() {
-        let (hdr, mut rows) = read_one_csv(p)?;
+        let (hdr, mut rows) = read_one_csv(p, rank_col, read_retries)?;
         println!(
             "[INFO]  {}. {} → {} rows",
             k + 1,
@@ -329,6 +503,13 @@ pub fn cluster_csv_multi(
         if let Some(prev) = first_header.as_ref() {
             let same_len = prev.len() == hdr.len();
             let same_elems = same_len && prev.iter().zip(&hdr).all(|(a, b)| a == b);
+            if !same_elems && strict_csv {
+                return Err(anyhow!(
+                    "Header of {} differs from the first file ({}) in names or order",
+                    p,
+                    inputs[0]
+                ));
+            }
             if !same_elems {
                 eprintln!(
                     "[WARN] Header of {} differs from the first file; \
@@ -336,7 +517,23 @@ pub fn cluster_csv_multi(
                     p
                 );
             }
-        } else {
+        }
+
+        if let (Some(g), Some(keys)) = (group_col, group_keys.as_mut()) {
+            match find_col(&StringRecord::from(hdr.clone()), g) {
+                Some(gi) => keys.extend(rows.iter().map(|r| r.row.get(gi).cloned().unwrap_or_default())),
+                None if source_col == Some(g) => keys.extend(rows.iter().map(|r| r.source.clone())),
+                None => {
+                    eprintln!(
+                        "[WARN] Group column '{}' not in header of {}; clustering all rows together.",
+                        g, p
+                    );
+                    group_keys = None;
+                }
+            }
+        }
+
+        if first_header.is_none() {
             first_header = Some(hdr);
         }
 
@@ -352,22 +549,186 @@ pub fn cluster_csv_multi(
         all_rows.len()
     );
 
-    let picked = cluster_rows(
-        all_rows,
+    let picked = match group_keys {
+        Some(keys) => {
+            // Partition in order of first appearance so output follows the inputs.
+            let mut order: Vec<String> = Vec::new();
+            let mut groups: HashMap<String, Vec<RowView>> = HashMap::new();
+            for (key, row) in keys.into_iter().zip(all_rows) {
+                if !groups.contains_key(&key) {
+                    order.push(key.clone());
+                }
+                groups.entry(key).or_default().push(row);
+            }
+            println!(
+                "[INFO] Clustering {} group(s) by '{}' separately",
+                order.len(),
+                group_col.unwrap_or_default()
+            );
+            let mut picked = Vec::new();
+            for key in order {
+                let rows = groups.remove(&key).unwrap_or_default();
+                picked.extend(cluster_rows(rows, ptol_abs, dmtol, acctol, allow_harmonics, tobs_opt));
+            }
+            picked
+        }
+        None => cluster_rows(
+            all_rows,
+            ptol_abs,
+            dmtol,
+            acctol,
+            allow_harmonics,
+            tobs_opt,
+        ),
+    };
+
+    write_picked(output, &first_header.unwrap(), &picked, source_col, period_split, provenance)
+}
+
+/// Write the picked rows to `output`, or split by period decade.
+fn write_picked(
+    output: &str,
+    header: &[String],
+    picked: &[RowView],
+    source_col: Option<&str>,
+    period_split: bool,
+    provenance: Option<&str>,
+) -> Result<()> {
+    if period_split {
+        write_period_split(output, header, picked, source_col, provenance)?;
+        println!(
+            "[INFO] Clustering complete. Wrote {} picked rows split by period decade",
+            picked.len()
+        );
+    } else {
+        write_csv(output, header, picked, source_col, provenance)?;
+        println!(
+            "[INFO] Clustering complete. Wrote {} picked rows to {}",
+            picked.len(),
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Second-stage entry for rows already in memory (`candy_picker_rs --second-stage-ptol`):
+/// `header` and `rows` must follow one of the supported schemas, e.g. the pivots.csv columns.
+/// The remaining arguments are as for [`cluster_csv_multi`].
+#[allow(clippy::too_many_arguments)]
+pub fn cluster_csv_rows(
+    header: &[String],
+    rows: &[Vec<String>],
+    output: &str,
+    ptol_abs: f64,
+    dmtol: Option<f64>,
+    acctol: Option<f64>,
+    allow_harmonics: bool,
+    tobs_opt: Option<f64>,
+    period_split: bool,
+    provenance: Option<&str>,
+) -> Result<()> {
+    let hdr = StringRecord::from(header.to_vec());
+    let colmap = detect_schema(&hdr)?;
+    let all_rows: Vec<RowView> = rows
+        .iter()
+        .filter_map(|r| parse_row(&colmap, &StringRecord::from(r.clone()), ""))
+        .collect();
+    if all_rows.is_empty() {
+        return Err(anyhow!("No valid rows for the second stage"));
+    }
+
+    println!(
+        "[INFO] Second stage: clustering {} rows… (ptol={}, dmtol={:?}, acctol={:?}, harmonics={}, tobs={:?})",
+        all_rows.len(),
         ptol_abs,
         dmtol,
         acctol,
         allow_harmonics,
-        tobs_opt,
+        tobs_opt
     );
+    let picked = cluster_rows(all_rows, ptol_abs, dmtol, acctol, allow_harmonics, tobs_opt);
+    write_picked(output, header, &picked, None, period_split, provenance)
+}
 
-    let header = first_header.unwrap();
-    write_csv(output, &header, &picked, source_col)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    println!(
-        "[INFO] Clustering complete. Wrote {} picked rows to {}",
-        picked.len(),
-        output
-    );
-    Ok(())
+    fn write_fixture(name: &str, contents: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("candy_picker_rs_csv_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn csv_without_acc_column_clusters_on_period() {
+        let path = write_fixture(
+            "no_acc.csv",
+            "#id,dm_new,p0_new,S/N_new\n\
+             1,10.0,0.0100000,20.0\n\
+             2,10.0,0.0100001,12.0\n\
+             3,10.0,0.0370000,9.0\n",
+        );
+        let (_, rows) = read_one_csv(&path, None, 0).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|r| r.acc == 0.0));
+
+        let picked = cluster_rows(rows, 1e-6, None, None, false, None);
+        let ids: Vec<&str> = picked.iter().map(|r| r.row[0].as_str()).collect();
+        assert_eq!(ids, ["1", "3"]);
+    }
+
+    #[test]
+    fn strict_csv_rejects_reordered_header() {
+        let a = write_fixture("order_a.csv", "#id,dm_new,p0_new,S/N_new\n1,10.0,0.01,20.0\n");
+        let b = write_fixture("order_b.csv", "#id,p0_new,dm_new,S/N_new\n2,0.02,10.0,15.0\n");
+        let inputs = [a, b];
+        let out = write_fixture("order_out.csv", "");
+        let run = |strict| {
+            cluster_csv_multi(&inputs, &out, 1e-6, None, None, false, None, None, None, None, false, 0, strict, None)
+        };
+        let err = run(true).unwrap_err().to_string();
+        assert!(err.contains("differs from the first file"), "{}", err);
+        assert!(run(false).is_ok());
+    }
+
+    #[test]
+    fn unit_tagged_headers_are_detected_and_scaled() {
+        let path = write_fixture(
+            "units.csv",
+            "#id, dm_new [pc/cc] ,p0_new[ms],acc_new[m/s^2],S/N_new\n1,10.0,12.5,3.0,20.0\n",
+        );
+        let (header, rows) = read_one_csv(&path, None, 0).unwrap();
+        // Output keeps the header as written.
+        assert_eq!(header[2], "p0_new[ms]");
+        assert_eq!((rows[0].period_s, rows[0].dm, rows[0].acc), (0.0125, 10.0, 3.0));
+
+        let bad = write_fixture("bad_unit.csv", "#id,dm_new,p0_new[min],S/N_new\n1,10.0,1.0,20.0\n");
+        let err = format!("{:#}", read_one_csv(&bad, None, 0).unwrap_err());
+        assert!(err.contains("Unsupported unit [min] on column p0_new"), "{}", err);
+    }
+
+    #[test]
+    fn group_col_keeps_groups_apart() {
+        let rows = "1,A,10.0,0.0100000,20.0\n\
+                    2,B,10.0,0.0100001,12.0\n\
+                    3,A,10.0,0.0100002,9.0\n";
+        let out = write_fixture("beams_out.csv", "");
+        let picked_ids = |header: &str, group_col| {
+            let inputs = [write_fixture("beams.csv", &format!("{header}\n{rows}"))];
+            cluster_csv_multi(&inputs, &out, 1e-6, None, None, false, None, None, group_col, None, false, 0, false, None)
+                .unwrap();
+            let mut rdr = ReaderBuilder::new().from_path(&out).unwrap();
+            rdr.records().map(|r| r.unwrap()[0].to_string()).collect::<Vec<_>>()
+        };
+        let plain = "#id,beam,dm_new,p0_new,S/N_new";
+        assert_eq!(picked_ids(plain, None), ["1"]);
+        assert_eq!(picked_ids(plain, Some("beam")), ["1", "2"]);
+        // Looked up like the other columns: surrounding whitespace and a [unit] tag are ignored.
+        for header in ["#id, beam ,dm_new,p0_new,S/N_new", "#id,beam[id],dm_new,p0_new,S/N_new"] {
+            assert_eq!(picked_ids(header, Some("beam")), ["1", "2"], "{}", header);
+        }
+    }
 }
@@ -1 +1,7 @@
+pub mod cancel;
+pub mod candidates;
 pub mod csv_cluster;
+pub mod provenance;
+pub mod retry;
+
+pub use candidates::{cluster_candidates, parse_xml_file, shortlist_candidates, Candidate};
@@ -1,376 +1,566 @@
 use anyhow::{anyhow, Result};
+use candy_picker_rs::candidates::{cluster_members, AccelerationRelatedness, Relatedness, compare_pivot_priority, dm_sharpness, parse_birdies, AccReference, PivotOrder, PivotPolicy, RelateDomain, CandidateFilter, parse_id_range, parse_rfi_lines, parse_veto, UuidNormalizer, read_baseline_pivots, PivotStatus, equatorial_to_galactic, parse_xml_file, open_xml_reader, pick_candidates, parse_inputs, parse_json_inputs, pick_parsed, read_candidate_ids, PickResult, validate_xml_file, Candidate, SPEED_OF_LIGHT, MISSING_NASSOC, PickParams, XmlFile};
+use candy_picker_rs::cancel::{self, cancelled, CANCELLED_EXIT_CODE};
+use candy_picker_rs::csv_cluster::cluster_csv_rows;
+use candy_picker_rs::provenance::{create_csv_writer, provenance_line};
 use clap::{Arg, Command};
 use rayon::prelude::*;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
-use xmltree::{Element, EmitterConfig};
-
-const SPEED_OF_LIGHT: f64 = 299_792_458.0;
-
-#[derive(Debug, Clone)]
-struct Candidate {
-    snr: f64,
-    period: f64,
-    f0: f64,
-    dm: f64,
-    acc: f64,
-    nh: i32,
-    ddm_count_ratio: f32,
-    ddm_snr_ratio: f32,
-    nassoc: i32,
-    period_ms: i32,
-    #[allow(dead_code)]
-    pulse_width: f64,
-    uuid: Option<String>,
-    xml_file: String,
-    candidate_id: i32,
-    raw_xml: String,
-    related: Vec<usize>,
-    is_pivot: bool,
-}
-
-impl Candidate {
-    fn new(
-        snr: f64,
-        period: f64,
-        dm: f64,
-        acc: f64,
-        nh: i32,
-        ddm_count_ratio: f32,
-        ddm_snr_ratio: f32,
-        nassoc: i32,
-        uuid: Option<String>,
-        xml_file: String,
-        candidate_id: i32,
-        raw_xml: String,
-    ) -> Self {
-        let f0 = 1.0 / period;
-        let period_ms = (period * 1000.0).round() as i32;
-        let pulse_width = period / 2.0f64.powi(nh);
-        Self {
-            snr,
-            period,
-            f0,
-            dm,
-            acc,
-            nh,
-            ddm_count_ratio,
-            ddm_snr_ratio,
-            nassoc,
-            period_ms,
-            pulse_width,
-            uuid,
-            xml_file,
-            candidate_id,
-            raw_xml,
-            related: Vec::new(),
-            is_pivot: false,
-        }
-    }
-
-    fn is_related(&self, other: &Candidate, period_thresh: f64, dm_thresh: Option<f64>, tobs_over_c: f64) -> bool {
-        if let Some(dmth) = dm_thresh {
-            if (self.dm - other.dm).abs() > dmth {
-                return false;
-            }
-        }
-        let corrected_other_period =
-            1.0 / (other.f0 - (other.acc - self.acc) * other.f0 * tobs_over_c);
-        let true_period_difference = if (self.period / corrected_other_period) > 1.0 {
-            self.period % corrected_other_period
-        } else {
-            corrected_other_period % self.period
-        };
-        true_period_difference <= period_thresh
-            || (self.period - corrected_other_period).abs() <= period_thresh
-    }
+use std::path::PathBuf;
+
+const DISTANCE_MATRIX_MAX_CANDS: usize = 2000;
+const GRAPH_MAX_NODES: usize = 5000;
+
+// Missing ddm ratios (NaN) and nassoc (MISSING_NASSOC) are written as empty cells.
+fn ratio_cell(r: f32) -> String {
+    if r.is_nan() { String::new() } else { r.to_string() }
 }
 
-#[derive(Debug, Clone)]
-struct Birdie {
-    freq: f64, // Hz
-    width: f64, // Hz (half width)
+fn nassoc_cell(n: i32) -> String {
+    if n == MISSING_NASSOC { String::new() } else { n.to_string() }
 }
 
-fn parse_birdies<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Vec<Birdie>> {
-    let txt = std::fs::read_to_string(&path)?;
-    let mut out = Vec::new();
-    for (lineno, line) in txt.lines().enumerate() {
-        let t = line.trim();
-        if t.is_empty() || t.starts_with('#') { continue; }
-        let cols: Vec<&str> = t.split_whitespace().collect();
-        if cols.len() < 2 {
-            return Err(anyhow::anyhow!(
-                "bidies file {} line {}: expected 'freq width'",
-                path.as_ref().display(),
-                lineno + 1
-                ));
-        }
-        let freq: f64 = cols[0].parse()?;
-        let width: f64 = cols[1].parse()?;
-        if freq > 0.0 && width > 0.0 {
-            out.push(Birdie { freq, width });
-        }
-    }
-    Ok(out)
+// Each pivot's cluster by cluster_id: the pivot, then its other members in candidate order.
+fn pivot_clusters<'a>(cands: &'a [Candidate], pivots: &[usize]) -> Vec<Vec<&'a Candidate>> {
+    let members = cluster_members(cands);
+    pivots
+        .iter()
+        .map(|&i| {
+            let others = cands[i].cluster_id.and_then(|id| members.get(&id)).into_iter().flatten().filter(|&&j| j != i);
+            std::iter::once(&cands[i]).chain(others.map(|&j| &cands[j])).collect()
+        })
+        .collect()
 }
 
-// Build [f_lo and f_hi] windows for each birdie and its harmonics.
-// if scale_width is true, the k-th harmonic gets widht * k ; else width is constant. 
-fn build_birdie_windows(birds: &[Birdie], hmax: u32, scale_width: bool) -> Vec<(f64, f64)> {
-    let mut wins = Vec::with_capacity(birds.len() * (hmax as usize));
-    for b in birds {
-        for k in 1..=hmax {
-            let kf = (k as f64) * b.freq;
-            if kf <= 0.0 { continue; }
-            let full_w = if scale_width { (k as f64) * b.width } else { b.width };
-            let half = 0.5 * full_w;
-            wins.push((kf - half, kf + half));
-        }
-    }
-    // Optional: sort for cache-friendliness (not strictly needed) // suggested by chatgpt 
-    wins.sort_by(|a,b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
-    wins
-} 
-
-
-#[derive(Debug)]
-struct XmlSections {
-    misc_info: Option<String>,
-    header_parameters: Option<String>,
-    search_parameters: Option<String>,
-    segment_parameters: Option<String>,
-    dedispersion_trials: Option<String>,
-    acceleration_trials: Option<String>,
-    cuda_device_parameters: Option<String>,
-    execution_times: Option<String>,
-}
-
-#[derive(Debug)]
-struct XmlFile {
-    filename: String,
-    sections: XmlSections,
-    fft_size: i64,
-    tsamp: f64,
-    candidates: Vec<Candidate>,
-}
-
-fn element_to_string(e: &Element) -> String {
-    let mut buf = Vec::new();
-    e.write_with_config(&mut buf, EmitterConfig::new().perform_indent(true))
-        .expect("serialize element");
-    String::from_utf8(buf).unwrap()
-}
-
-fn slice_candidate_block(xml: &str, id: i32) -> Option<String> {
-    let pat = format!("<candidate id='{id}'>");
-    if let Some(start) = xml.find(&pat) {
-        if let Some(end) = xml[start..].find("</candidate>") {
-            let block = &xml[start..start + end + "</candidate>".len()];
-            return Some(block.to_string());
-        }
-    }
-    None
-}
-
-fn get_text_path(root: &Element, path: &[&str]) -> Option<String> {
-    let mut cur = root;
-    for &p in path {
-        cur = cur.get_child(p)?;
-    }
-    cur.get_text().map(|cow| cow.to_string())
-}
-
-fn get_text_child(el: &Element, tag: &str, filename: &str) -> Result<String> {
-    el.get_child(tag)
-        .and_then(|e| e.get_text().map(|cow| cow.to_string()))
-        .ok_or_else(|| anyhow!("Missing <{}> in {}", tag, filename))
-}
-
-fn parse_xml_file(filename: &str) -> Result<XmlFile> {
-    println!("[INFO] Parsing {filename}");
-    let content = fs::read_to_string(filename)?;
-    let root: Element = Element::parse(content.as_bytes())?;
-
-    let tsamp: f64 = get_text_path(&root, &["header_parameters", "tsamp"])
-        .ok_or_else(|| anyhow!("Missing tsamp in {}", filename))?
-        .parse()?;
-
-    let fft_size: i64 = get_text_path(&root, &["search_parameters", "size"])
-        .ok_or_else(|| anyhow!("Missing fft size in {}", filename))?
-        .parse()?;
-
-    let mut candidates = Vec::new();
-    if let Some(cands_el) = root.get_child("candidates") {
-        for cand_el in &cands_el.children {
-            if let xmltree::XMLNode::Element(e) = cand_el {
-                let cid = e.attributes.get("id")
-                    .ok_or_else(|| anyhow!("Candidate missing id in {}", filename))?
-                    .parse::<i32>()?;
-                let period: f64 = get_text_child(e, "period", filename)?.parse()?;
-                let dm: f64 = get_text_child(e, "dm", filename)?.parse()?;
-                let acc: f64 = get_text_child(e, "acc", filename)?.parse()?;
-                let nh: i32 = get_text_child(e, "nh", filename)?.parse()?;
-                let snr: f64 = get_text_child(e, "snr", filename)?.parse()?;
-                let ddm_count_ratio: f32 = get_text_child(e, "ddm_count_ratio", filename)?.parse()?;
-                let ddm_snr_ratio: f32 = get_text_child(e, "ddm_snr_ratio", filename)?.parse()?;
-                let nassoc: i32 = get_text_child(e, "nassoc", filename)?.parse()?;
-                let uuid = get_text_path(e, &["search_candidates_database_uuid"]);
-                let raw_xml = slice_candidate_block(&content, cid).unwrap_or_else(|| element_to_string(e));
-                candidates.push(Candidate::new(
-                    snr, period, dm, acc, nh,
-                    ddm_count_ratio, ddm_snr_ratio, nassoc,
-                    uuid, filename.to_string(), cid, raw_xml,
-                ));
-            }
-        }
-    }
+// Number of input files contributing to a cluster.
+fn num_files(cluster: &[&Candidate]) -> usize {
+    cluster.iter().map(|m| m.xml_file.as_str()).collect::<std::collections::HashSet<_>>().len()
+}
+
+const PIVOTS_HEADER: [&str; 19] = ["snr","period","dm","acc","nh","ddm_count_ratio","ddm_snr_ratio","nassoc",
+    "period_ms","uuid","xml_file","candidate_id","num_related","related_cands","label","num_files","dm_sharpness",
+    "cluster_id","cluster_size"];
+
+// One pivots.csv row per pivot, in PIVOTS_HEADER order.
+fn pivot_rows(cands: &[Candidate], pivots: &[usize]) -> Vec<Vec<String>> {
+    pivot_clusters(cands, pivots)
+        .iter()
+        .map(|cluster| {
+            let c = cluster[0];
+            let related_ids: Vec<String> = cluster[1..].iter().map(|m| m.id_string()).collect();
+            vec![
+                c.snr.to_string(),
+                format!("{:.17}", c.period),
+                format!("{:.8}", c.dm),
+                c.acc.to_string(),
+                c.nh.to_string(),
+                ratio_cell(c.ddm_count_ratio),
+                ratio_cell(c.ddm_snr_ratio),
+                nassoc_cell(c.nassoc),
+                format!("{:.6}", c.period_ms),
+                c.id_string(),
+                c.xml_file.clone(),
+                c.candidate_id.to_string(),
+                related_ids.len().to_string(),
+                related_ids.join(":"),
+                c.label.clone(),
+                num_files(cluster).to_string(),
+                if c.ddm_snr_ratio.is_nan() || c.ddm_count_ratio.is_nan() { String::new() } else { format!("{:.4}", dm_sharpness(c)) },
+                c.cluster_id.map(|id| id.to_string()).unwrap_or_default(),
+                cluster.len().to_string(),
+            ]
+        })
+        .collect()
+}
 
-    let sections = XmlSections {
-        misc_info: root.get_child("misc_info").map(element_to_string),
-        header_parameters: root.get_child("header_parameters").map(element_to_string),
-        search_parameters: root.get_child("search_parameters").map(element_to_string),
-        segment_parameters: root.get_child("segment_parameters").map(element_to_string),
-        dedispersion_trials: root.get_child("dedispersion_trials").map(element_to_string),
-        acceleration_trials: root.get_child("acceleration_trials").map(element_to_string),
-        cuda_device_parameters: root.get_child("cuda_device_parameters").map(element_to_string),
-        execution_times: root.get_child("execution_times").map(element_to_string),
+// sqrt of the summed squared SNRs of a cluster's members, skipping non-finite ones: for
+// independent detections of one source this is the combined significance.
+fn snr_quadrature_sum(cluster: &[&Candidate]) -> f64 {
+    cluster
+        .iter()
+        .map(|m| m.snr)
+        .filter(|s| s.is_finite())
+        .map(|s| s * s)
+        .sum::<f64>()
+        .sqrt()
+}
+
+// Likely fundamental period of a pivot that may be its k-th harmonic: k * P for the k in
+// 2..=2^nh (at most hmax, as in canonicalize_periods) under which the most cluster members
+// sit at a longer period k * P / j (j < k) within period_thresh scaled by k / j; ties go to
+// the smaller k. Without any such member the pivot's own period. No acceleration correction
+// is applied.
+fn fundamental_period(cands: &[Candidate], c: &Candidate, period_thresh: f64, hmax: u32) -> f64 {
+    let kmax = (1u32 << c.nh.clamp(1, 5)).min(hmax);
+    let explained = |k: u32| {
+        c.related
+            .iter()
+            .filter(|&&m| {
+                (1..k).any(|j| {
+                    let ratio = k as f64 / j as f64;
+                    (cands[m].period - ratio * c.period).abs() <= period_thresh * ratio
+                })
+            })
+            .count()
     };
+    let (best_k, hits) = (2..=kmax).map(|k| (k, explained(k))).fold((1, 0), |best, kh| if kh.1 > best.1 { kh } else { best });
+    if hits == 0 { c.period } else { best_k as f64 * c.period }
+}
 
-    println!("[INFO] Parsed {filename}: {} candidates", candidates.len());
-    Ok(XmlFile { filename: filename.to_string(), sections, fft_size, tsamp, candidates })
+// Optional pivots.csv columns, appended in this order after PIVOTS_HEADER.
+#[derive(Default)]
+struct PivotExtras<'a> {
+    snr_sum: bool,                              // --emit-snr-sum
+    uuid_raw: bool,                             // --normalize-uuid
+    galactic: Option<&'a [XmlFile]>,            // --emit-galactic: l, b of each pivot's file
+    status: Option<&'a [PivotStatus]>,          // --baseline, in pivots order
+    fundamental: Option<(f64, u32)>,            // --flatten-harmonics: the run's period threshold and --hmax
+    provenance: Option<&'a str>,                // --embed-provenance: comment line above the header
 }
 
-fn cluster_candidates(cands: &mut [Candidate], period_thresh: f64, dm_thresh: Option<f64>, tobs_over_c: f64, bin_dm: bool) {
-    println!("[INFO] Clustering (binning: {bin_dm})...");
-    let n = cands.len();
-    if bin_dm {
-        let mut bins: HashMap<i64, Vec<usize>> = HashMap::new();
-        for (i, c) in cands.iter().enumerate() {
-            let b = if let Some(dmth) = dm_thresh {
-                (c.dm / dmth).floor() as i64
-            } else {
-                0
-            };
-            bins.entry(b).or_default().push(i);
-        }
-        let results: Vec<(usize, Vec<usize>)> = bins.into_par_iter().flat_map(|(_, idxs)| {
-            idxs.iter().map(|&i| {
-                let mut rels = Vec::new();
-                for &j in &idxs {
-                    if j > i && cands[i].is_related(&cands[j], period_thresh, dm_thresh, tobs_over_c) {
-                        rels.push(j);
-                    }
-                }
-                (i, rels)
-            }).collect::<Vec<_>>()
-        }).collect();
-        for (i, rels) in results {
-            cands[i].related = rels;
+fn save_candidates_csv(cands: &[Candidate], pivots: &[usize], filename: &str, extras: &PivotExtras) -> Result<()> {
+    println!("[INFO] Writing {filename}");
+    if let Some(parent) = std::path::Path::new(filename).parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut wtr = create_csv_writer(filename, extras.provenance)?;
+    let mut header = PIVOTS_HEADER.to_vec();
+    if extras.snr_sum {
+        header.push("snr_quadrature_sum");
+    }
+    if extras.uuid_raw {
+        header.push("uuid_raw");
+    }
+    if extras.galactic.is_some() {
+        header.extend(["l", "b"]);
+    }
+    if extras.status.is_some() {
+        header.push("status");
+    }
+    if extras.fundamental.is_some() {
+        header.push("fundamental_period");
+    }
+    wtr.write_record(header)?;
+    let coords: HashMap<&str, (f64, f64)> = extras
+        .galactic
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|xf| xf.ra_dec.map(|(ra, dec)| (xf.filename.as_str(), equatorial_to_galactic(ra, dec))))
+        .collect();
+    let clusters = if extras.snr_sum { pivot_clusters(cands, pivots) } else { Vec::new() };
+    for (k, (mut row, &i)) in pivot_rows(cands, pivots).into_iter().zip(pivots).enumerate() {
+        if extras.snr_sum {
+            row.push(format!("{:.4}", snr_quadrature_sum(&clusters[k])));
         }
-    } else {
-        let results: Vec<(usize, Vec<usize>)> = (0..n).into_par_iter().map(|i| {
-            let mut rels = Vec::new();
-            for j in (i+1)..n {
-                if cands[i].is_related(&cands[j], period_thresh, dm_thresh, tobs_over_c) {
-                    rels.push(j);
-                }
+        if extras.uuid_raw {
+            row.push(cands[i].uuid_raw.clone().unwrap_or_default());
+        }
+        if extras.galactic.is_some() {
+            match coords.get(cands[i].xml_file.as_str()) {
+                Some((l, b)) => row.extend([format!("{l:.6}"), format!("{b:.6}")]),
+                None => row.extend([String::new(), String::new()]),
             }
-            (i, rels)
-        }).collect();
-        for (i, rels) in results {
-            cands[i].related = rels;
         }
+        if let Some(status) = extras.status {
+            row.push(status[k].as_str().to_string());
+        }
+        if let Some((thresh, hmax)) = extras.fundamental {
+            row.push(format!("{:.17}", fundamental_period(cands, &cands[i], thresh, hmax)));
+        }
+        wtr.write_record(&row)?;
     }
-    println!("[INFO] Finished clustering.");
+    wtr.flush()?;
+    Ok(())
 }
 
-fn shortlist_candidates(
-    cands: &mut [Candidate],
-    birdies: Option<&[Birdie]>,
-    birdie_harmonics: u32,
-    scale_birdie_width: bool,
-    ) -> Vec<usize> {
-    println!("[INFO] Shortlisting pivots...");
-    let mut to_remove: std::collections::HashSet<usize> = std::collections::HashSet::new();
+const PARSED_HEADER: [&str; 14] = ["snr","period","f0","dm","acc","nh","ddm_count_ratio","ddm_snr_ratio","nassoc",
+    "period_ms","uuid","label","xml_file","candidate_id"];
 
-    // 1) clustering-based pruning: keep a single pivot per related group
-    for i in 0..cands.len() {
-        if cands[i].related.len() > 1 {
-            for &r in &cands[i].related {
-                to_remove.insert(r);
-            }
+// The fields extracted from the XML for one candidate, in PARSED_HEADER order.
+fn parsed_fields(c: &Candidate) -> Vec<String> {
+    vec![
+        c.snr.to_string(),
+        format!("{:.17}", c.period),
+        format!("{:.15}", c.f0),
+        format!("{:.8}", c.dm),
+        c.acc.to_string(),
+        c.nh.to_string(),
+        ratio_cell(c.ddm_count_ratio),
+        ratio_cell(c.ddm_snr_ratio),
+        nassoc_cell(c.nassoc),
+        format!("{:.6}", c.period_ms),
+        c.uuid.clone().unwrap_or_default(),
+        c.label.clone(),
+        c.xml_file.clone(),
+        c.candidate_id.to_string(),
+    ]
+}
+
+// Every parsed candidate with the fields extracted from the XML, before any filtering or
+// clustering.
+fn save_parsed_csv(files: &[XmlFile], filename: &str) -> Result<()> {
+    println!("[INFO] Writing {filename}");
+    let mut wtr = csv::Writer::from_path(filename)?;
+    wtr.write_record(PARSED_HEADER)?;
+    for c in files.iter().flat_map(|xf| &xf.candidates) {
+        wtr.write_record(parsed_fields(c))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Every parsed candidate plus its decision: is_pivot, and for absorbed candidates the
+// pivot_uuid of the cluster they joined, and the cluster_id of that cluster. Candidates
+// removed before clustering (RFI lines, --filter) have is_pivot = false and empty
+// pivot_uuid and cluster_id.
+fn save_all_candidates_csv(result: &PickResult, filename: &str, uuid_raw: bool) -> Result<()> {
+    println!("[INFO] Writing {filename}");
+    let mut owner: HashMap<(&str, i32), String> = HashMap::new();
+    for (pivot, members) in result.clusters() {
+        for m in members {
+            owner.entry((m.xml_file.as_str(), m.candidate_id)).or_insert_with(|| pivot.id_string());
+        }
+    }
+    let pivot_map = result.pivot_map();
+    let mut wtr = csv::Writer::from_path(filename)?;
+    let mut header: Vec<&str> = PARSED_HEADER.to_vec();
+    header.extend(["is_pivot", "pivot_uuid", "cluster_id"]);
+    if uuid_raw {
+        header.push("uuid_raw");
+    }
+    wtr.write_record(header)?;
+    for c in result.parsed_candidates() {
+        let is_pivot = pivot_map.contains_key(&(c.xml_file.clone(), c.candidate_id));
+        let mut row = parsed_fields(c);
+        // The id pivot_uuid refers to, also for candidates without a uuid.
+        row[10] = c.id_string();
+        row.push(is_pivot.to_string());
+        row.push(if is_pivot { String::new() } else { owner.get(&(c.xml_file.as_str(), c.candidate_id)).cloned().unwrap_or_default() });
+        row.push(c.cluster_id.map(|id| id.to_string()).unwrap_or_default());
+        if uuid_raw {
+            row.push(c.uuid_raw.clone().unwrap_or_default());
         }
+        wtr.write_record(row)?;
     }
+    wtr.flush()?;
+    Ok(())
+}
 
-    // 2) Birdie + harmonic pruning
-    if let Some(birds) = birdies {
-        let windows = build_birdie_windows(birds, birdie_harmonics, scale_birdie_width);
-        let mut rfi_hits = 0usize;
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    inputs TEXT NOT NULL,
+    period_thresh REAL NOT NULL,
+    dm_thresh REAL
+);
+CREATE TABLE IF NOT EXISTS pivots (
+    run_id INTEGER NOT NULL REFERENCES runs(run_id),
+    xml_file TEXT NOT NULL,
+    candidate_id INTEGER NOT NULL,
+    uuid TEXT,
+    snr REAL NOT NULL,
+    period REAL NOT NULL,
+    dm REAL NOT NULL,
+    acc REAL NOT NULL,
+    nh INTEGER NOT NULL,
+    ddm_count_ratio REAL,
+    ddm_snr_ratio REAL,
+    nassoc INTEGER,
+    label TEXT NOT NULL,
+    num_related INTEGER NOT NULL,
+    related_cands TEXT NOT NULL,
+    num_files INTEGER NOT NULL,
+    dm_sharpness REAL
+);
+CREATE TABLE IF NOT EXISTS candidates (
+    run_id INTEGER NOT NULL REFERENCES runs(run_id),
+    xml_file TEXT NOT NULL,
+    candidate_id INTEGER NOT NULL,
+    uuid TEXT,
+    snr REAL NOT NULL,
+    period REAL NOT NULL,
+    dm REAL NOT NULL,
+    acc REAL NOT NULL,
+    nh INTEGER NOT NULL,
+    ddm_count_ratio REAL,
+    ddm_snr_ratio REAL,
+    nassoc INTEGER,
+    label TEXT NOT NULL,
+    is_pivot INTEGER NOT NULL,
+    pivot_uuid TEXT
+);
+CREATE INDEX IF NOT EXISTS pivots_run ON pivots(run_id);
+CREATE INDEX IF NOT EXISTS pivots_period ON pivots(period);
+CREATE INDEX IF NOT EXISTS pivots_dm ON pivots(dm);
+CREATE INDEX IF NOT EXISTS candidates_run ON candidates(run_id);
+CREATE INDEX IF NOT EXISTS candidates_period ON candidates(period);
+CREATE INDEX IF NOT EXISTS candidates_dm ON candidates(dm);
+";
 
-        'cand: for i in 0..cands.len() {
-            if to_remove.contains(&i) { continue; }
-            let f0 = cands[i].f0; // Hz
+// --sqlite: append this run (a new run_id) with its pivots and, with `all`, every parsed
+// candidate with its cluster, in one transaction. Missing ratios and nassoc are NULL.
+fn save_sqlite(result: &PickResult, path: &str, inputs: &[PathBuf], dm_thresh: Option<f64>, all: bool) -> Result<()> {
+    use rusqlite::params;
 
-            // Simple scan is fine for modest window counts
-            for &(lo, hi) in &windows {
-                if f0 >= lo && f0 <= hi {
-                    to_remove.insert(i);
-                    rfi_hits += 1;
-                    continue 'cand;
-                }
+    println!("[INFO] Writing {path}");
+    // Through the shortest f32 text, so 0.8 is stored as 0.8 rather than 0.800000011920929.
+    let ratio = |r: f32| if r.is_nan() { None } else { r.to_string().parse::<f64>().ok() };
+    let nassoc = |n: i32| (n != MISSING_NASSOC).then_some(n);
+    let mut conn = rusqlite::Connection::open(path)?;
+    conn.execute_batch(SQLITE_SCHEMA)?;
+    let tx = conn.transaction()?;
+    let created_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64;
+    let input_list: Vec<String> = inputs.iter().map(|p| p.to_string_lossy().into_owned()).collect();
+    tx.execute(
+        "INSERT INTO runs (created_at, inputs, period_thresh, dm_thresh) VALUES (?1, ?2, ?3, ?4)",
+        params![created_at, input_list.join("\n"), result.period_thresh, dm_thresh],
+    )?;
+    let run_id = tx.last_insert_rowid();
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO pivots VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+        )?;
+        for cluster in pivot_clusters(&result.candidates, &result.pivots) {
+            let c = cluster[0];
+            let related_ids: Vec<String> = cluster[1..].iter().map(|m| m.id_string()).collect();
+            let sharpness = (ratio(c.ddm_snr_ratio).is_some() && ratio(c.ddm_count_ratio).is_some()).then(|| dm_sharpness(c));
+            stmt.execute(params![
+                run_id, c.xml_file, c.candidate_id, c.id_string(), c.snr, c.period, c.dm, c.acc, c.nh,
+                ratio(c.ddm_count_ratio), ratio(c.ddm_snr_ratio), nassoc(c.nassoc), c.label,
+                related_ids.len() as i64, related_ids.join(":"), num_files(&cluster) as i64, sharpness,
+            ])?;
+        }
+    }
+    if all {
+        let mut owner: HashMap<(&str, i32), String> = HashMap::new();
+        for (pivot, members) in result.clusters() {
+            for m in members {
+                owner.entry((m.xml_file.as_str(), m.candidate_id)).or_insert_with(|| pivot.id_string());
             }
         }
+        let pivot_map = result.pivot_map();
+        let mut stmt = tx.prepare(
+            "INSERT INTO candidates VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        )?;
+        for c in result.parsed_candidates() {
+            let is_pivot = pivot_map.contains_key(&(c.xml_file.clone(), c.candidate_id));
+            let pivot_uuid = if is_pivot { None } else { owner.get(&(c.xml_file.as_str(), c.candidate_id)) };
+            stmt.execute(params![
+                run_id, c.xml_file, c.candidate_id, c.id_string(), c.snr, c.period, c.dm, c.acc, c.nh,
+                ratio(c.ddm_count_ratio), ratio(c.ddm_snr_ratio), nassoc(c.nassoc), c.label,
+                is_pivot, pivot_uuid,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    println!("[INFO] Stored run {run_id} in {path}");
+    Ok(())
+}
 
-        println!(
-            "[INFO] Birdie pruning (with harmonics up to k={}) removed {} candidates.",
-            birdie_harmonics, rfi_hits
-        );
+// The top `n` pivots of the whole run, best first, with a 1-based rank column ahead of the
+// pivots.csv columns and snr_quadrature_sum. Ranked by SNR or, with `by_snr_sum`, by the
+// quadrature SNR sum; ties fall back to compare_pivot_priority, then to pivots order.
+fn save_global_shortlist(cands: &[Candidate], pivots: &[usize], n: usize, by_snr_sum: bool, filename: &str, provenance: Option<&str>) -> Result<()> {
+    println!("[INFO] Writing {filename}");
+    let sums: Vec<f64> = pivot_clusters(cands, pivots).iter().map(|cluster| snr_quadrature_sum(cluster)).collect();
+    let mut order: Vec<usize> = (0..pivots.len()).collect();
+    order.sort_by(|&a, &b| {
+        let by_sum = if by_snr_sum { sums[b].total_cmp(&sums[a]) } else { std::cmp::Ordering::Equal };
+        by_sum.then_with(|| compare_pivot_priority(&cands[pivots[a]], &cands[pivots[b]], None))
+    });
+    order.truncate(n);
+    let ranked: Vec<usize> = order.iter().map(|&k| pivots[k]).collect();
+    let mut wtr = create_csv_writer(filename, provenance)?;
+    let mut header = vec!["rank"];
+    header.extend(PIVOTS_HEADER);
+    header.push("snr_quadrature_sum");
+    wtr.write_record(header)?;
+    for (rank, (row, &k)) in pivot_rows(cands, &ranked).into_iter().zip(&order).enumerate() {
+        let mut out = vec![(rank + 1).to_string()];
+        out.extend(row);
+        out.push(format!("{:.4}", sums[k]));
+        wtr.write_record(&out)?;
     }
+    wtr.flush()?;
+    println!("[INFO] Shortlisted {} of {} pivots", ranked.len(), pivots.len());
+    Ok(())
+}
 
-    // Mark remaining as pivots
-    let mut pivots = Vec::new();
-    for (i, c) in cands.iter_mut().enumerate() {
-        if !to_remove.contains(&i) {
-            c.is_pivot = true;
-            pivots.push(i);
-        }
+// One row per cluster: pivot identity plus member count and SNR/period/DM ranges over the
+// pivot and everything it absorbed.
+fn save_cluster_stats(result: &PickResult, filename: &str) -> Result<()> {
+    println!("[INFO] Writing {filename}");
+    let mut wtr = csv::Writer::from_path(filename)?;
+    wtr.write_record(["uuid","xml_file","candidate_id","snr","period","dm","num_members",
+        "snr_min","snr_max","period_min","period_max","period_spread","dm_min","dm_max","dm_spread","num_files"])?;
+    for (pivot, members) in result.clusters() {
+        let all: Vec<&Candidate> = std::iter::once(pivot).chain(members).collect();
+        let range = |f: fn(&Candidate) -> f64| {
+            all.iter().map(|c| f(c)).fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+        };
+        let (snr_min, snr_max) = range(|c| c.snr);
+        let (p_min, p_max) = range(|c| c.period);
+        let (dm_min, dm_max) = range(|c| c.dm);
+        let num_files = all.iter().map(|c| c.xml_file.as_str()).collect::<std::collections::HashSet<_>>().len();
+        wtr.write_record(&[
+            pivot.uuid.clone().unwrap_or_default(),
+            pivot.xml_file.clone(),
+            pivot.candidate_id.to_string(),
+            pivot.snr.to_string(),
+            format!("{:.17}", pivot.period),
+            format!("{:.8}", pivot.dm),
+            all.len().to_string(),
+            snr_min.to_string(),
+            snr_max.to_string(),
+            format!("{:.17}", p_min),
+            format!("{:.17}", p_max),
+            format!("{:.6e}", p_max - p_min),
+            format!("{:.8}", dm_min),
+            format!("{:.8}", dm_max),
+            format!("{:.8}", dm_max - dm_min),
+            num_files.to_string(),
+        ])?;
     }
-    println!("[INFO] Found {} pivots.", pivots.len());
-    pivots
+    wtr.flush()?;
+    Ok(())
 }
 
-fn save_candidates_csv(cands: &[Candidate], pivots: &[usize], filename: &str) -> Result<()> {
+// One row per input XML: candidate statistics as parsed (before any filtering), the
+// observation parameters and how many of its candidates ended up as pivots.
+fn save_file_stats(result: &PickResult, filename: &str) -> Result<()> {
     println!("[INFO] Writing {filename}");
+    let mut pivots_per_file: HashMap<&str, usize> = HashMap::new();
+    for &p in &result.pivots {
+        *pivots_per_file.entry(result.candidates[p].xml_file.as_str()).or_default() += 1;
+    }
     let mut wtr = csv::Writer::from_path(filename)?;
-    wtr.write_record(&["snr","period","dm","acc","nh","ddm_count_ratio","ddm_snr_ratio","nassoc",
-        "period_ms","uuid","xml_file","candidate_id","num_related","related_cands"])?;
-    for &i in pivots {
-        let c = &cands[i];
-        let related_ids: Vec<String> = c.related.iter().map(|&j| {
-            cands[j].uuid.clone().unwrap_or_else(|| format!("{}_{}", cands[j].xml_file, cands[j].candidate_id))
-        }).collect();
+    wtr.write_record(["xml_file","num_candidates","snr_median","snr_max","period_min","period_max",
+        "dm_min","dm_max","fft_size","tsamp","tobs","num_pivots"])?;
+    for (xf, cands) in result.files.iter().zip(result.file_candidates()) {
+        // Empty cells for files without candidates.
+        let stat = |v: Option<String>| v.unwrap_or_default();
+        let mut snrs: Vec<f64> = cands.iter().map(|c| c.snr).collect();
+        snrs.sort_by(|a, b| a.total_cmp(b));
+        let median = (!snrs.is_empty()).then(|| {
+            let m = snrs.len() / 2;
+            if snrs.len().is_multiple_of(2) { (snrs[m - 1] + snrs[m]) / 2.0 } else { snrs[m] }
+        });
+        let range = |f: fn(&Candidate) -> f64| {
+            (!cands.is_empty()).then(|| {
+                cands.iter().map(|&c| f(c)).fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+            })
+        };
+        let (p_range, dm_range) = (range(|c| c.period), range(|c| c.dm));
         wtr.write_record(&[
-            c.snr.to_string(),
-            format!("{:.17}", c.period),
-            format!("{:.8}", c.dm),
-            c.acc.to_string(),
-            c.nh.to_string(),
-            c.ddm_count_ratio.to_string(),
-            c.ddm_snr_ratio.to_string(),
-            c.nassoc.to_string(),
-            c.period_ms.to_string(),
-            c.uuid.clone().unwrap_or_default(),
-            c.xml_file.clone(),
-            c.candidate_id.to_string(),
-            c.related.len().to_string(),
-            related_ids.join(":"),
+            xf.filename.clone(),
+            cands.len().to_string(),
+            stat(median.map(|m| m.to_string())),
+            stat(snrs.last().map(|m| m.to_string())),
+            stat(p_range.map(|r| format!("{:.17}", r.0))),
+            stat(p_range.map(|r| format!("{:.17}", r.1))),
+            stat(dm_range.map(|r| format!("{:.8}", r.0))),
+            stat(dm_range.map(|r| format!("{:.8}", r.1))),
+            xf.fft_size.to_string(),
+            xf.tsamp.to_string(),
+            xf.effective_tobs().to_string(),
+            pivots_per_file.get(xf.filename.as_str()).copied().unwrap_or(0).to_string(),
         ])?;
     }
     wtr.flush()?;
     Ok(())
 }
 
+// One minimal tempo2-style .par file per pivot, named after its uuid (or file_id). F1 is
+// the spin-down implied by the acceleration, -acc * F0 / c.
+fn save_par_files(cands: &[Candidate], pivots: &[usize], dir: &str) -> Result<()> {
+    println!("[INFO] Writing .par files to {dir}");
+    fs::create_dir_all(dir)?;
+    for &i in pivots {
+        let c = &cands[i];
+        let name = c.id_string().replace(['/', '\\'], "_");
+        let f1 = -c.acc * c.f0 / SPEED_OF_LIGHT;
+        let par = format!(
+            "PSRJ {name}\nF0 {:.15}\nF1 {:.6e}\nP0 {:.17}\nDM {:.8}\nACC {}\n",
+            c.f0, f1, c.period, c.dm, c.acc
+        );
+        fs::write(PathBuf::from(dir).join(format!("{name}.par")), par)?;
+    }
+    Ok(())
+}
+
+// The relatedness graph in Graphviz DOT: one node per clustered candidate, one edge per
+// related pair. Pivots are red, candidates absorbed into a pivot's cluster light blue and
+// the rest (e.g. pruned by birdies or --min-related) grey.
+fn save_graph(result: &PickResult, filename: &str) -> Result<()> {
+    use std::io::Write;
+
+    println!("[INFO] Writing {filename}");
+    let mut status = vec!["other"; result.candidates.len()];
+    for &p in &result.pivots {
+        for &j in &result.candidates[p].related {
+            status[j] = "absorbed";
+        }
+    }
+    for &p in &result.pivots {
+        status[p] = "pivot";
+    }
+    let mut out = std::io::BufWriter::new(fs::File::create(filename)?);
+    writeln!(out, "graph candidates {{")?;
+    writeln!(out, "  node [style=filled];")?;
+    for (i, c) in result.candidates.iter().enumerate() {
+        let color = match status[i] {
+            "pivot" => "red",
+            "absorbed" => "lightblue",
+            _ => "grey",
+        };
+        writeln!(
+            out,
+            "  n{i} [label=\"{}\\nP={:.6} ms DM={:.2}\", status={}, snr={}, period={:.17}, dm={:.8}, fillcolor={color}];",
+            c.id_string().replace('"', "'"),
+            c.period_ms,
+            c.dm,
+            status[i],
+            c.snr,
+            c.period,
+            c.dm
+        )?;
+    }
+    for &(i, j) in &result.edges {
+        writeln!(out, "  n{i} -- n{j};")?;
+    }
+    writeln!(out, "}}")?;
+    out.flush()?;
+    Ok(())
+}
+
+// Pairwise distances behind the is_related decision, one row per pair (i < j).
+#[allow(clippy::too_many_arguments)]
+fn save_distance_matrix(cands: &[Candidate], rel: &AccelerationRelatedness, filename: &str) -> Result<()> {
+    println!("[INFO] Writing {filename}");
+    let mut wtr = csv::Writer::from_path(filename)?;
+    wtr.write_record(["id_a","id_b","period_a","period_b","period_mod_diff","period_abs_diff","dm_diff","related"])?;
+    for (i, a) in cands.iter().enumerate() {
+        for b in &cands[(i + 1)..] {
+            let (mod_diff, abs_diff) = a.period_distance(b, rel.tobs_over_c, rel.acc_sign, rel.acc_ref);
+            wtr.write_record(&[
+                a.id_string(),
+                b.id_string(),
+                format!("{:.17}", a.period),
+                format!("{:.17}", b.period),
+                format!("{:.6e}", mod_diff),
+                format!("{:.6e}", abs_diff),
+                format!("{:.8}", (a.dm - b.dm).abs()),
+                rel.related(a, b).to_string(),
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
 fn strip_xml_decl(s: &str) -> &str {
     // Remove any UTF-8 BOM and leading whitespace
     let trimmed = s.trim_start_matches(|c: char| c == '\u{feff}' || c.is_whitespace());    // If it starts with an XML declaration, skip it
@@ -383,28 +573,180 @@ fn strip_xml_decl(s: &str) -> &str {
     trimmed
 }
 
+// Rewrites every attribute (and XML declaration pseudo-attribute) to be delimited by `quote`
+// for --attr-quote. Only the delimiters change: a value containing `quote` gets it escaped as
+// &quot; or &apos;, and text, comments and CDATA are copied as they are.
+fn normalize_attr_quotes(xml: &str, quote: char) -> String {
+    let escaped = if quote == '"' { "&quot;" } else { "&apos;" };
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(lt) = rest.find('<') {
+        out.push_str(&rest[..lt]);
+        rest = &rest[lt..];
+        // Comments and CDATA may contain anything; copy them whole.
+        if let Some((open, close)) = [("<!--", "-->"), ("<![CDATA[", "]]>")].into_iter().find(|(o, _)| rest.starts_with(o)) {
+            let end = rest[open.len()..].find(close).map_or(rest.len(), |e| open.len() + e + close.len());
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        // A tag: copy up to its '>', rewriting the delimiters of each quoted value.
+        let mut i = 0;
+        let mut closed = false;
+        while let Some(j) = rest[i..].find(['"', '\'', '>']).map(|j| i + j) {
+            out.push_str(&rest[i..j]);
+            let delim = rest[j..].chars().next().unwrap();
+            if delim == '>' {
+                out.push('>');
+                i = j + 1;
+                closed = true;
+                break;
+            }
+            let value_end = rest[j + 1..].find(delim).map_or(rest.len(), |e| j + 1 + e);
+            out.push(quote);
+            out.push_str(&rest[j + 1..value_end].replace(quote, escaped));
+            out.push(quote);
+            i = (value_end + 1).min(rest.len());
+        }
+        if !closed {
+            // Unterminated tag: copy whatever is left.
+            out.push_str(&rest[i..]);
+            i = rest.len();
+        }
+        rest = &rest[i..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Expands {dir} (parent directory, "." if none; `out_dir` instead when given), {stem} (file
+// name without extension) and {ext} (extension without the dot, "xml" if none) for an input file.
+fn output_path(template: &str, input: &str, out_dir: Option<&std::path::Path>) -> PathBuf {
+    let path = std::path::Path::new(input);
+    let dir = match out_dir {
+        Some(d) => Some(d.to_string_lossy().into_owned()),
+        None => path.parent().map(|p| p.to_string_lossy().into_owned()),
+    }
+    .filter(|d| !d.is_empty());
+    // `beam01.xml.gz` names its outputs like `beam01.xml`; they are written uncompressed.
+    let path = input.strip_suffix(".gz").map_or(path, std::path::Path::new);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let ext = path.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or_else(|| "xml".to_string());
+    let out = template
+        .replace("{dir}", dir.as_deref().unwrap_or("."))
+        .replace("{stem}", &stem)
+        .replace("{ext}", &ext);
+    match (dir, out.strip_prefix("./")) {
+        (None, Some(rest)) if template.starts_with("{dir}/") => PathBuf::from(rest),
+        _ => PathBuf::from(out),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RejectedSample {
+    TopN,
+    Stratified,
+}
+
+// The pivots CSV path: a relative `csv_out` goes below --outdir when one is given.
+fn pivots_csv_path(csv_out: &str, out_dir: Option<&std::path::Path>) -> String {
+    match out_dir {
+        Some(dir) if std::path::Path::new(csv_out).is_relative() => dir.join(csv_out).to_string_lossy().into_owned(),
+        _ => csv_out.to_string(),
+    }
+}
+
+// {dir} for an input under --outdir: the outdir itself, or with --preserve-tree the outdir
+// plus the input's directory relative to `input_root`. Inputs outside the root are an error.
+fn mirrored_dir(input: &str, out_dir: &std::path::Path, input_root: Option<&std::path::Path>) -> Result<PathBuf> {
+    let Some(root) = input_root else {
+        return Ok(out_dir.to_path_buf());
+    };
+    let parent = std::path::Path::new(input).parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+    let parent = fs::canonicalize(parent)?;
+    let root = fs::canonicalize(root)?;
+    let rel = parent
+        .strip_prefix(&root)
+        .map_err(|_| anyhow!("{input} is outside --input-root {}", root.display()))?;
+    Ok(out_dir.join(rel))
+}
+
+// How write_updated_xmls names and fills its outputs.
+struct XmlOutputOptions<'a> {
+    picked_template: &'a str,
+    rejected_template: &'a str,
+    // --outdir, and the --input-root to mirror below it under --preserve-tree.
+    out_dir: Option<&'a std::path::Path>,
+    input_root: Option<&'a std::path::Path>,
+    max_rejected: Option<usize>,
+    rejected_sample: RejectedSample,
+    seed: u64,
+    // --attr-quote single/double; None (preserve) keeps each region's own style.
+    attr_quote: Option<char>,
+}
+
+// Caps the rejected candidates of one file at `max`, returned in document order.
+// TopN keeps the highest-SNR ones. Stratified sorts by SNR, splits the ranks into 10 equal
+// bins (deciles; bin b holds ranks [b*n/10, (b+1)*n/10)) and draws from the bins in turn,
+// brightest first, picking at random within a bin (StdRng seeded from `seed`).
+fn cap_rejected(mut rejected: Vec<&Candidate>, max: usize, mode: RejectedSample, seed: u64) -> Vec<&Candidate> {
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    if rejected.len() <= max {
+        return rejected;
+    }
+    let order: HashMap<i32, usize> = rejected.iter().enumerate().map(|(i, c)| (c.candidate_id, i)).collect();
+    rejected.sort_by(|a, b| b.snr.partial_cmp(&a.snr).unwrap_or(std::cmp::Ordering::Equal));
+    let mut keep: Vec<&Candidate> = match mode {
+        RejectedSample::TopN => rejected.into_iter().take(max).collect(),
+        RejectedSample::Stratified => {
+            const BINS: usize = 10;
+            let n = rejected.len();
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut pools: Vec<Vec<&Candidate>> = (0..BINS)
+                .map(|b| {
+                    let mut bin = rejected[b * n / BINS..(b + 1) * n / BINS].to_vec();
+                    bin.shuffle(&mut rng);
+                    bin
+                })
+                .collect();
+            // Round-robin over the bins, so sparse bins hand their share to the others.
+            let mut out = Vec::with_capacity(max);
+            while out.len() < max {
+                for pool in pools.iter_mut() {
+                    if out.len() == max {
+                        break;
+                    }
+                    if let Some(c) = pool.pop() {
+                        out.push(c);
+                    }
+                }
+            }
+            out
+        }
+    };
+    keep.sort_by_key(|c| order[&c.candidate_id]);
+    keep
+}
+
 fn write_updated_xmls(
     xf: &XmlFile,
-    _cands: &[Candidate],
+    cands: &[&Candidate],
     pivot_map: &HashMap<(String, i32), bool>,
-) -> Result<()> {
-    use std::io::BufRead;
-
+    opts: &XmlOutputOptions,
+) -> Result<(PathBuf, PathBuf)> {
     // --- Preserve the original XML declaration from the first line ---
-    let file = fs::File::open(&xf.filename)?;
     let mut first_line = String::new();
-    {
-        let mut reader = std::io::BufReader::new(&file);
-        reader.read_line(&mut first_line)?;
-    }
+    open_xml_reader(&xf.filename)?.read_line(&mut first_line)?;
     let xml_decl = if first_line.trim_start().starts_with("<?xml") {
         first_line.trim().to_string()
     } else {
         "<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_string()
     };
 
-    let picked_name = xf.filename.replace(".xml", "_picked.xml");
-    let rejected_name = xf.filename.replace(".xml", "_rejected.xml");
+    let out_dir = opts.out_dir.map(|d| mirrored_dir(&xf.filename, d, opts.input_root)).transpose()?;
+    let picked_name = output_path(opts.picked_template, &xf.filename, out_dir.as_deref());
+    let rejected_name = output_path(opts.rejected_template, &xf.filename, out_dir.as_deref());
 
     let mut base = String::new();
     base.push_str(&xml_decl);
@@ -424,16 +766,27 @@ fn write_updated_xmls(
     picked.push_str("<candidates>\n");
     rejected.push_str("<candidates>\n");
 
-    for c in &xf.candidates {
+    let mut rejected_cands = Vec::new();
+    for &c in cands {
         let is_pivot = *pivot_map.get(&(c.xml_file.clone(), c.candidate_id)).unwrap_or(&false);
         if is_pivot {
-            picked.push_str(strip_xml_decl(&c.raw_xml));
+            picked.push_str(strip_xml_decl(c.raw_xml.as_str()));
             picked.push('\n');
         } else {
-            rejected.push_str(strip_xml_decl(&c.raw_xml));
-            rejected.push('\n');
+            rejected_cands.push(c);
         }
     }
+    if let Some(max) = opts.max_rejected {
+        let total = rejected_cands.len();
+        rejected_cands = cap_rejected(rejected_cands, max, opts.rejected_sample, opts.seed);
+        if rejected_cands.len() < total {
+            println!("[INFO] Capped rejected candidates of {} at {} of {}", xf.filename, rejected_cands.len(), total);
+        }
+    }
+    for c in rejected_cands {
+        rejected.push_str(strip_xml_decl(c.raw_xml.as_str()));
+        rejected.push('\n');
+    }
 
     picked.push_str("</candidates>\n");
     rejected.push_str("</candidates>\n");
@@ -447,71 +800,1008 @@ fn write_updated_xmls(
 
     picked.push_str("</peasoup_search>\n");
     rejected.push_str("</peasoup_search>\n");
+    if let Some(q) = opts.attr_quote {
+        picked = normalize_attr_quotes(&picked, q);
+        rejected = normalize_attr_quotes(&rejected, q);
+    }
+
+    for name in [&picked_name, &rejected_name] {
+        if let Some(parent) = name.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    write_atomically(&picked_name, &picked)?;
+    write_atomically(&rejected_name, &rejected)?;
+    println!("[INFO] Wrote {} and {}", picked_name.display(), rejected_name.display());
+
+    Ok((picked_name, rejected_name))
+}
+
+// Writes `<path>.tmp` and renames it over `path`, so an interrupted run never leaves a
+// truncated output behind.
+fn write_atomically(path: &std::path::Path, contents: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Ends a run stopped by SIGINT/SIGTERM (see cancel.rs) at a safe point.
+fn exit_cancelled(what: &str) -> ! {
+    println!("[INFO] Cancelled, {what}");
+    std::process::exit(CANCELLED_EXIT_CODE)
+}
+
+// Re-reads the written outputs and checks that every input candidate landed in exactly one
+// of them. Duplicate ids in the input are an error too, since the picked/rejected decision
+// is keyed by (file, id) and cannot tell such candidates apart.
+fn verify_split(xf: &XmlFile, picked: &std::path::Path, rejected: &std::path::Path, params: &PickParams) -> Result<()> {
+    use std::collections::HashSet;
+
+    let mut input_ids = HashSet::new();
+    for &id in &xf.candidate_ids {
+        if !input_ids.insert(id) {
+            return Err(anyhow!("verify: duplicate candidate id {} in {}", id, xf.filename));
+        }
+    }
+    let picked_ids = read_candidate_ids(&picked.to_string_lossy(), params.strict, params.read_retries)?;
+    let rejected_ids = read_candidate_ids(&rejected.to_string_lossy(), params.strict, params.read_retries)?;
+    let total = picked_ids.len() + rejected_ids.len();
+    if total != xf.candidate_ids.len() {
+        return Err(anyhow!(
+            "verify: {} has {} candidates but picked + rejected hold {}",
+            xf.filename,
+            xf.candidate_ids.len(),
+            total
+        ));
+    }
+    let mut seen = HashSet::new();
+    for id in picked_ids.iter().chain(&rejected_ids) {
+        if !seen.insert(*id) {
+            return Err(anyhow!("verify: candidate {} of {} was written twice", id, xf.filename));
+        }
+    }
+    if seen != input_ids {
+        return Err(anyhow!("verify: candidate ids of {} differ from its outputs", xf.filename));
+    }
+    println!("[INFO] Verified {}: {} picked + {} rejected", xf.filename, picked_ids.len(), rejected_ids.len());
+    Ok(())
+}
+
+// --jobs file: {"jobs": [ {...}, ... ]}. Unset job fields fall back to the command line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    jobs: Vec<Job>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    name: Option<String>,
+    inputs: Vec<PathBuf>,
+    period_thresh: Option<f64>,
+    ptol_auto: Option<f64>,
+    dm_thresh: Option<f64>,
+    bin_dm: Option<bool>,
+    min_related: Option<usize>,
+    csv_out: Option<String>,
+    picked_template: Option<String>,
+    rejected_template: Option<String>,
+    no_xml: Option<bool>,
+}
+
+impl Job {
+    fn csv_out(&self, name: &str, out_dir: Option<&std::path::Path>) -> String {
+        pivots_csv_path(self.csv_out.as_deref().unwrap_or(&format!("{name}_pivots.csv")), out_dir)
+    }
+
+    // Every file the job writes: its pivots CSV, then each input's picked and rejected XML.
+    fn outputs(&self, name: &str, base_xml: &XmlOutputOptions) -> Vec<PathBuf> {
+        let mut out = vec![PathBuf::from(self.csv_out(name, base_xml.out_dir))];
+        if !self.no_xml.unwrap_or(false) {
+            let picked = self.picked_template.as_deref().unwrap_or(base_xml.picked_template);
+            let rejected = self.rejected_template.as_deref().unwrap_or(base_xml.rejected_template);
+            for input in &self.inputs {
+                let input = input.to_string_lossy();
+                out.push(output_path(picked, &input, base_xml.out_dir));
+                out.push(output_path(rejected, &input, base_xml.out_dir));
+            }
+        }
+        out
+    }
+}
+
+// Jobs writing the same file would overwrite each other (or race under --parallel-jobs),
+// so any shared output path fails the whole --jobs run before a job starts.
+fn check_job_outputs(jobs: &[Job], names: &[String], base_xml: &XmlOutputOptions) -> Result<()> {
+    let mut owner: HashMap<PathBuf, &str> = HashMap::new();
+    for (job, name) in jobs.iter().zip(names) {
+        for path in job.outputs(name, base_xml) {
+            if let Some(other) = owner.insert(path.clone(), name) {
+                return Err(anyhow!(
+                    "jobs {other} and {name} both write {}; give one its own csv_out, picked_template/rejected_template or no_xml",
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Pick one job and write its outputs; returns the number of pivots. `period_thresh` is the
+// command-line -p, if given.
+fn run_job(
+    job: &Job,
+    name: &str,
+    base: &PickParams,
+    period_thresh: Option<f64>,
+    base_xml: &XmlOutputOptions,
+    provenance: Option<&str>,
+) -> Result<usize> {
+    let mut params = base.clone();
+    // A job's period_thresh overrides a command-line --ptol-auto; its ptol_auto overrides both.
+    params.ptol_auto = job.ptol_auto.or(if job.period_thresh.is_some() { None } else { base.ptol_auto });
+    if params.ptol_auto.is_none() {
+        params.period_thresh = job
+            .period_thresh
+            .or(period_thresh)
+            .ok_or_else(|| anyhow!("no period_thresh or ptol_auto (in the job or on the command line)"))?;
+    }
+    if job.dm_thresh.is_some() {
+        params.dm_thresh = job.dm_thresh;
+    }
+    if let Some(b) = job.bin_dm {
+        params.bin_dm = b;
+    }
+    if let Some(k) = job.min_related {
+        params.min_related = k;
+    }
+
+    let result = pick_candidates(&job.inputs, &params)?;
+    let csv_out = job.csv_out(name, base_xml.out_dir);
+    save_candidates_csv(
+        &result.candidates,
+        &result.pivots,
+        &csv_out,
+        &PivotExtras { uuid_raw: params.normalize_uuid.is_some(), provenance, ..Default::default() },
+    )?;
+    if !job.no_xml.unwrap_or(false) {
+        let xml_opts = XmlOutputOptions {
+            picked_template: job.picked_template.as_deref().unwrap_or(base_xml.picked_template),
+            rejected_template: job.rejected_template.as_deref().unwrap_or(base_xml.rejected_template),
+            ..*base_xml
+        };
+        let pivot_map = result.pivot_map();
+        for (xf, cands) in result.files.iter().zip(result.file_candidates()) {
+            write_updated_xmls(xf, &cands, &pivot_map, &xml_opts)?;
+        }
+    }
+    Ok(result.pivots.len())
+}
+
+// Run every job of a --jobs file, sequentially or (parallel) across the rayon pool, and
+// report a per-job summary. Fails if any job failed.
+fn run_jobs(
+    path: &str,
+    base: &PickParams,
+    period_thresh: Option<f64>,
+    base_xml: &XmlOutputOptions,
+    parallel: bool,
+    provenance: Option<&str>,
+) -> Result<()> {
+    let text = fs::read_to_string(path)?;
+    let file: JobFile = serde_json::from_str(&text).map_err(|e| anyhow!("invalid jobs file {path}: {e}"))?;
+    let names: Vec<String> = file
+        .jobs
+        .iter()
+        .enumerate()
+        .map(|(i, j)| j.name.clone().unwrap_or_else(|| format!("job{i}")))
+        .collect();
+    check_job_outputs(&file.jobs, &names, base_xml)?;
+    println!("[INFO] Running {} jobs from {path}", file.jobs.len());
+    let run = |(job, name): (&Job, &String)| run_job(job, name, base, period_thresh, base_xml, provenance);
+    let outcomes: Vec<Result<usize>> = if parallel {
+        file.jobs.par_iter().zip(names.par_iter()).map(run).collect()
+    } else {
+        file.jobs.iter().zip(names.iter()).map(run).collect()
+    };
+
+    let mut failed = 0;
+    println!("[INFO] Job summary:");
+    for (name, outcome) in names.iter().zip(&outcomes) {
+        match outcome {
+            Ok(n) => println!("[INFO]   {name}: OK ({n} pivots)"),
+            Err(e) => {
+                failed += 1;
+                eprintln!("[WARN]   {name}: FAILED: {e}");
+            }
+        }
+    }
+    println!("[INFO] {} of {} jobs succeeded.", outcomes.len() - failed, outcomes.len());
+    if failed > 0 {
+        return Err(anyhow!("{failed} jobs failed"));
+    }
+    Ok(())
+}
+
+// Calls timed by the --estimate micro-benchmark; a few milliseconds in practice.
+const ESTIMATE_BENCH_CALLS: usize = 100_000;
+
+// Whether a candidate carries an optional field.
+type FieldPresent = fn(&Candidate) -> bool;
+
+const COVERAGE_FIELDS: [(&str, FieldPresent); 5] = [
+    ("uuid", |c| c.uuid.as_deref().is_some_and(|u| !u.is_empty())),
+    ("label", |c| !c.label.is_empty()),
+    ("ddm_count_ratio", |c| !c.ddm_count_ratio.is_nan()),
+    ("ddm_snr_ratio", |c| !c.ddm_snr_ratio.is_nan()),
+    ("nassoc", |c| c.nassoc != MISSING_NASSOC),
+];
+
+fn print_coverage_table(title: &str, cands: &[&Candidate]) {
+    println!("[INFO] Field coverage, {title}: {} candidates", cands.len());
+    println!("  {:<16} {:>10} {:>8}", "field", "present", "percent");
+    for (name, present) in COVERAGE_FIELDS {
+        let count = cands.iter().filter(|c| present(c)).count();
+        let pct = if cands.is_empty() { 0.0 } else { 100.0 * count as f64 / cands.len() as f64 };
+        println!("  {name:<16} {count:>10} {pct:>7.1}%");
+    }
+}
+
+// --report-field-coverage: how many parsed candidates carry each optional field, over all
+// inputs and, with `per_file`, for each input in turn.
+fn print_field_coverage(cands: &[Candidate], per_file: bool) {
+    print_coverage_table("all inputs", &cands.iter().collect::<Vec<_>>());
+    if per_file {
+        let mut files: Vec<&str> = Vec::new();
+        for c in cands {
+            if !files.contains(&c.xml_file.as_str()) {
+                files.push(&c.xml_file);
+            }
+        }
+        for f in files {
+            print_coverage_table(f, &cands.iter().filter(|c| c.xml_file == f).collect::<Vec<_>>());
+        }
+    }
+}
+
+// --estimate: pairwise comparisons for the plain and --bin-dm clustering, and a rough wall
+// time from timing is_related on pseudo-random pairs of the parsed candidates. Filters and
+// prefilters are not applied, so this is an upper bound.
+fn print_estimate(files: &[XmlFile], params: &PickParams, ncpus: usize) {
+    let cands: Vec<&Candidate> = files.iter().flat_map(|xf| &xf.candidates).collect();
+    let n = cands.len();
+    let pairs = |k: usize| (k as u128) * (k.saturating_sub(1) as u128) / 2;
+    let flat = pairs(n);
+    let binned = match params.dm_thresh {
+        Some(d) => {
+            let mut bins: HashMap<i64, usize> = HashMap::new();
+            for c in &cands {
+                *bins.entry((c.dm / d).floor() as i64).or_default() += 1;
+            }
+            println!("[INFO] Estimate: {} DM bins of width {d}, largest holds {} candidates", bins.len(), bins.values().max().unwrap_or(&0));
+            bins.values().map(|&k| pairs(k)).sum()
+        }
+        None => flat,
+    };
+    println!("[INFO] Estimate: {n} candidates from {} files", files.len());
+    println!("[INFO] Estimate: {flat} comparisons without --bin-dm, {binned} with --bin-dm{}", if params.dm_thresh.is_none() { " (no -d, so one bin)" } else { "" });
+    if n < 2 {
+        return;
+    }
 
-    fs::write(&picked_name, picked)?;
-    fs::write(&rejected_name, rejected)?;
-    println!("[INFO] Wrote {picked_name} and {rejected_name}");
+    let tobs = files[0].effective_tobs();
+    let period_thresh = params.ptol_auto.map_or(params.period_thresh, |m| m / tobs);
+    let start = std::time::Instant::now();
+    let mut related = 0usize;
+    for k in 0..ESTIMATE_BENCH_CALLS {
+        let (i, j) = (k % n, (k.wrapping_mul(7919) + 1) % n);
+        related += std::hint::black_box(cands[i].is_related(
+            cands[j], period_thresh, params.dm_thresh, tobs / SPEED_OF_LIGHT, params.acc_sign,
+            params.acc_reference, params.max_modulo_harmonic, params.max_snr_ratio, params.relate_on,
+            params.harmonics, params.hmax,
+        )) as usize;
+    }
+    let per_call = start.elapsed().as_secs_f64() / ESTIMATE_BENCH_CALLS as f64;
+    std::hint::black_box(related);
+    // More workers than cores do not help.
+    let workers = std::thread::available_parallelism().map_or(ncpus, |c| ncpus.min(c.get())).max(1);
+    let wall = |pairs: u128| pairs as f64 * per_call / workers as f64;
+    println!(
+        "[INFO] Estimate: {:.0} ns per comparison; ~{:.1} s without --bin-dm, ~{:.1} s with --bin-dm on {workers} workers",
+        per_call * 1e9,
+        wall(flat),
+        wall(binned)
+    );
+}
 
+fn validate_xml_files(files: &[PathBuf], strict: bool, read_retries: u32) -> Result<()> {
+    let mut failed = 0usize;
+    for f in files {
+        match validate_xml_file(&f.to_string_lossy(), strict, read_retries) {
+            Ok(()) => println!("PASS {}", f.display()),
+            Err(e) => {
+                println!("FAIL {}: {e}", f.display());
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} files failed validation", files.len()));
+    }
+    println!("[INFO] All {} files passed validation.", files.len());
     Ok(())
 }
 
 fn main() -> Result<()> {
     let matches = Command::new("candy_picker_rs")
         .version("0.3.0")
-        .arg(Arg::new("period_thresh").short('p').num_args(1).required(true))
+        .arg(Arg::new("period_thresh").short('p').num_args(1).required_unless_present_any(["validate_xml", "dump_parsed", "ptol_auto", "jobs"]).conflicts_with("ptol_auto"))
+        .arg(Arg::new("ptol_auto").long("ptol-auto").num_args(1).value_name("MULT").help("Derive the period threshold as MULT / tobs of the first file instead of -p"))
         .arg(Arg::new("dm_thresh").short('d').num_args(1))
         .arg(Arg::new("ncpus").short('n').num_args(1).default_value("8"))
         .arg(Arg::new("bin_dm").long("bin-dm").action(clap::ArgAction::SetTrue))
-        .arg(Arg::new("xml_files").num_args(1..).required(true))
+        .arg(Arg::new("xml_files").num_args(1..).required_unless_present_any(["jobs", "json_input"]))
+        .arg(Arg::new("json_input").long("json-input").num_args(1..).value_name("JSON").conflicts_with_all(["xml_files", "jobs"]).requires("json_tobs").help("Read candidates from JSON arrays instead of XML; only CSV outputs are written"))
+        .arg(Arg::new("json_tobs").long("json-tobs").num_args(1).value_name("S").help("Observation length in seconds for --json-input (used for the acceleration correction)"))
+        .arg(Arg::new("jobs").long("jobs").num_args(1).value_name("JSON").help("Run the picking jobs described in a JSON file instead of the inputs on the command line"))
+        .arg(Arg::new("parallel_jobs").long("parallel-jobs").action(clap::ArgAction::SetTrue).requires("jobs").help("Run --jobs in parallel"))
         .arg(Arg::new("birdies").short('B').long("birdies").num_args(1).help("Optional file with 'freq width' pairs (Hz) to prune as RFI"))
-        .arg(Arg::new("birdies_harmonics").long("birdie-harmonics").short('H').num_args(1).value_name("N").default_value("16").help("Max harmonic multiple to reject for each birdie (k=1..N)"))
+        .arg(Arg::new("birdie_harmonics").long("birdie-harmonics").short('H').num_args(1).value_name("N").default_value("16").help("Max harmonic multiple to reject for each birdie (k=1..N)"))
         .arg(Arg::new("scale_birdie_width").short('W').long("scale-birdie-width").action(clap::ArgAction::SetTrue).help("Scale the birdie width by k for the k-th harmonic"))
+        .arg(Arg::new("rfi_lines").long("rfi-lines").num_args(1).value_name("CSV").help("CSV of known RFI lines (period or freq, tol); matching candidates are rejected before clustering"))
+        .arg(Arg::new("filter").long("filter").num_args(1).value_name("EXPR").help("Keep only candidates for which EXPR is true, e.g. 'snr > 9 && nh < 4'; the rest are rejected before clustering"))
+        .arg(Arg::new("min_dm_sharpness").long("min-dm-sharpness").num_args(1).value_name("S").help("Reject candidates with DM peak sharpness below S (0..1) before clustering"))
+        .arg(Arg::new("veto").long("veto").num_args(1).value_name("CSV").help("CSV of (xml_file, candidate_id) pairs to drop after parsing; vetoed candidates are left out of all outputs"))
+        .arg(Arg::new("id_range").long("id-range").num_args(1).value_name("LO-HI").help("Only process candidates with ids in [LO, HI]; the others are left out of all outputs"))
+        .arg(Arg::new("max_modulo_harmonic").long("max-modulo-harmonic").num_args(1).value_name("K").help("Reject period-modulo matches whose implied ratio round(p_a/p_b) exceeds K"))
+        .arg(Arg::new("collapse_by_period_ms").long("collapse-by-period-ms").num_args(0..=1).require_equals(true).value_name("RES_MS").default_missing_value("1").help("Before clustering keep only the highest-SNR candidate per file and period bucket of RES_MS ms (default 1)"))
+        .arg(Arg::new("group_by_uuid_prefix").long("group-by-uuid-prefix").num_args(1).value_name("LEN").help("Only cluster candidates whose uuids share the first LEN characters"))
+        .arg(Arg::new("normalize_uuid").long("normalize-uuid").num_args(1).value_name("REGEX").help("Strip uuid version suffixes from the last match of REGEX on (e.g. ':v' or '[:#](v|rev)[0-9]+$'); the original goes to a uuid_raw column"))
+        .arg(Arg::new("missing_uuid").long("missing-uuid").num_args(1).value_parser(["group", "exclude"]).default_value("group").requires("group_by_uuid_prefix").help("With --group-by-uuid-prefix, put candidates without a uuid in one group, or exclude them"))
+        .arg(Arg::new("relate_on").long("relate-on").num_args(1).value_parser(["period", "frequency"]).default_value("period").help("Compare periods directly (period modulo for harmonics) or as frequencies with integer frequency ratios"))
+        .arg(Arg::new("harmonics").long("harmonics").action(clap::ArgAction::SetTrue).help("Also relate candidates whose acceleration-corrected periods are k = 2..HMAX multiples of each other, as csv_candypicker does"))
+        .arg(Arg::new("hmax").long("hmax").num_args(1).value_name("HMAX").default_value("16").help("Highest harmonic number tried by --harmonics, --merge-harmonic-clusters, --canonicalize-periods and --flatten-harmonics"))
+        .arg(Arg::new("symmetric_relate").long("symmetric-relate").action(clap::ArgAction::SetTrue).help("Relate two candidates when either one matches in the other's acceleration frame"))
+        .arg(Arg::new("acc_sign").long("acc-sign").num_args(1).value_parser(["pos", "neg"]).default_value("pos").help("Sign convention of <acc> in the inputs; 'neg' flips the acceleration correction"))
+        .arg(Arg::new("dm_tol_steps").long("dm-tol-steps").num_args(1).value_name("N").help("DM gate of N times each file's DM trial step (larger of the pair); files without a parseable step use -d"))
+        .arg(Arg::new("max_snr_ratio").long("max-snr-ratio").num_args(1).value_name("R").help("Never relate two candidates whose SNRs differ by more than a factor R"))
+        .arg(Arg::new("acc_reference").long("acc-reference").num_args(1).value_parser(["self", "zero", "mean"]).default_value("self").help("Acceleration frame for period comparison: the first candidate's (self), acc = 0 (zero) or the pair's mean acc (mean)"))
+        .arg(Arg::new("distance_matrix").long("distance-matrix").num_args(1).value_name("CSV").help("Write pairwise period/DM distances for all candidates (small inputs only)"))
+        .arg(Arg::new("emit_galactic").long("emit-galactic").action(clap::ArgAction::SetTrue).help("Add galactic l and b columns (degrees) to pivots.csv, from each file's src_raj/src_dej"))
+        .arg(Arg::new("baseline").long("baseline").num_args(1).value_name("CSV").help("pivots.csv of an earlier run; adds a status column (new, persisted or changed) to pivots.csv"))
+        .arg(Arg::new("all_out").long("all-out").num_args(1).value_name("CSV").help("Write every parsed candidate with is_pivot and, for absorbed ones, pivot_uuid"))
+        .arg(Arg::new("graph").long("graph").num_args(1).value_name("DOT").help(format!("Write the relatedness graph as Graphviz DOT (refused above {GRAPH_MAX_NODES} candidates without --force-graph)")))
+        .arg(Arg::new("force_graph").long("force-graph").action(clap::ArgAction::SetTrue).help(format!("Allow --graph above {GRAPH_MAX_NODES} candidates")))
+        .arg(Arg::new("global_shortlist").long("global-shortlist").num_args(1).value_name("N").help("Write the N best pivots across all inputs, ranked, to --shortlist-out"))
+        .arg(Arg::new("shortlist_by").long("shortlist-by").num_args(1).value_name("KEY").value_parser(["snr", "snr-sum"]).default_value("snr").requires("global_shortlist").help("Rank the global shortlist by pivot SNR or by the quadrature SNR sum of its cluster"))
+        .arg(Arg::new("shortlist_out").long("shortlist-out").num_args(1).value_name("CSV").default_value("shortlist.csv").help("Path of the --global-shortlist CSV"))
+        .arg(Arg::new("cluster_stats").long("cluster-stats").num_args(1).value_name("CSV").help("Write one row per cluster with member count and SNR/period/DM ranges"))
+        .arg(Arg::new("sqlite").long("sqlite").num_args(1).value_name("DB").conflicts_with("jobs").help("Append this run's pivots to an SQLite database (created if absent)"))
+        .arg(Arg::new("sqlite_all").long("sqlite-all").action(clap::ArgAction::SetTrue).requires("sqlite").help("With --sqlite, also store every parsed candidate with its cluster"))
+        .arg(Arg::new("file_stats").long("file-stats").num_args(1).value_name("CSV").conflicts_with("json_input").help("Write one row of statistics per input XML (counts, SNR, period/DM ranges, tobs, pivots)"))
+        .arg(Arg::new("par_out").long("par-out").num_args(1).value_name("DIR").help("Write a minimal .par file (F0, F1, P0, DM, ACC) per pivot into DIR"))
+        .arg(Arg::new("force_distance_matrix").long("force-distance-matrix").action(clap::ArgAction::SetTrue).help(format!("Allow --distance-matrix above {DISTANCE_MATRIX_MAX_CANDS} candidates")))
+        .arg(Arg::new("prefer_label").long("prefer-label").num_args(1).value_name("LABEL").help("Prefer candidates with this <label> (then higher SNR) as cluster pivots"))
+        .arg(Arg::new("keep_highest_nassoc_on_tie").long("keep-highest-nassoc-on-tie").action(clap::ArgAction::SetTrue).help("Make the highest-SNR member of each group its pivot, breaking SNR ties by higher nassoc, then shorter period; same as --pivot-policy snr, the default, and overrides --pivot-policy input"))
+        .arg(Arg::new("pivot_policy").long("pivot-policy").num_args(1).value_name("POLICY").value_parser(["input", "snr", "longest-tobs"]).default_value("snr").help("Which member of each group becomes its pivot: the earliest in the input, the highest SNR, or the one from the longest observation (then highest SNR)"))
+        .arg(Arg::new("merge_harmonic_clusters").long("merge-harmonic-clusters").action(clap::ArgAction::SetTrue).help("Merge clusters whose pivots are integer harmonics of each other, keeping the shorter-period pivot"))
+        .arg(Arg::new("canonicalize_periods").long("canonicalize-periods").action(clap::ArgAction::SetTrue).help("Cluster on each candidate's estimated fundamental period instead of the detected one"))
+        .arg(Arg::new("top_k").long("top-k").num_args(1).value_name("N").help("Only output the N highest-SNR pivots (deterministic)"))
+        .arg(Arg::new("limit").long("limit").num_args(1).value_name("N").help("Only output a random subset of N pivots, reproducible via --seed"))
+        .arg(Arg::new("seed").long("seed").num_args(1).default_value("0").help("Seed for --limit and --rejected-sample stratified sampling"))
+        .arg(Arg::new("sort_by").long("sort-by").num_args(1).value_parser(["keep-order", "snr", "period", "cluster-size"]).default_value("keep-order").help("Row order of the pivots in the CSV: input order, SNR, period, or number of related candidates (ties by SNR)"))
+        .arg(Arg::new("picked_template").long("picked-template").num_args(1).default_value("{dir}/{stem}_picked.{ext}").help("Output path for picked XMLs; placeholders {dir}, {stem}, {ext}"))
+        .arg(Arg::new("rejected_template").long("rejected-template").num_args(1).default_value("{dir}/{stem}_rejected.{ext}").help("Output path for rejected XMLs; placeholders {dir}, {stem}, {ext}"))
+        .arg(Arg::new("outdir").long("outdir").visible_alias("out-dir").num_args(1).value_name("DIR").help("Use DIR for {dir} in the output templates and write a relative --csv-out below it"))
+        .arg(Arg::new("preserve_tree").long("preserve-tree").action(clap::ArgAction::SetTrue).requires("outdir").help("Mirror each input's directory relative to --input-root below --outdir"))
+        .arg(Arg::new("input_root").long("input-root").num_args(1).value_name("DIR").default_value(".").help("Root of the input tree for --preserve-tree"))
+        .arg(Arg::new("max_rejected").long("max-rejected").num_args(1).value_name("N").help("Write at most N rejected candidates per file"))
+        .arg(Arg::new("rejected_sample").long("rejected-sample").num_args(1).value_parser(["topn", "stratified"]).default_value("topn").help("How --max-rejected chooses: highest SNR, or an even spread over SNR deciles (uses --seed)"))
+        .arg(Arg::new("attr_quote").long("attr-quote").num_args(1).value_parser(["single", "double", "preserve"]).default_value("preserve").help("Quote style for attributes in the picked/rejected XMLs; preserve keeps the input's"))
+        .arg(Arg::new("verify").long("verify").action(clap::ArgAction::SetTrue).conflicts_with("max_rejected").help("Check that every input candidate was written to exactly one of picked/rejected"))
+        .arg(Arg::new("no_csv").long("no-csv").action(clap::ArgAction::SetTrue).help("Do not write pivots.csv"))
+        .arg(Arg::new("no_xml").long("no-xml").action(clap::ArgAction::SetTrue).conflicts_with("verify").help("Do not write the picked/rejected XMLs"))
+        .arg(Arg::new("emit_snr_sum").long("emit-snr-sum").action(clap::ArgAction::SetTrue).conflicts_with("jobs").help("Add a snr_quadrature_sum column to the CSV: sqrt of the summed squared SNRs of each pivot and its members"))
+        .arg(Arg::new("flatten_harmonics").long("flatten-harmonics").action(clap::ArgAction::SetTrue).help("Add a fundamental_period column to the pivots CSV: the period the pivot is likely a harmonic of, inferred from its cluster"))
+        .arg(Arg::new("embed_provenance").long("embed-provenance").action(clap::ArgAction::SetTrue).help("Write a '# provenance:' comment line (tool, version, UTC time, command line) above the header of pivots.csv and the second-stage output"))
+        .arg(Arg::new("csv_out").long("csv-out").num_args(1).value_name("CSV").default_value("pivots.csv").help("Path of the pivots CSV"))
+        .arg(Arg::new("report_field_coverage").long("report-field-coverage").num_args(0..=1).require_equals(true).value_name("SCOPE").value_parser(["all", "per-file"]).default_missing_value("all").help("After parsing, print how many candidates carry each optional field (uuid, label, ddm ratios, nassoc), over all inputs or also per file; exits there with --dry-run"))
+        .arg(Arg::new("report_comparisons").long("report-comparisons").action(clap::ArgAction::SetTrue).help("After clustering, print the pairs compared versus the brute-force n*(n-1)/2"))
+        .arg(Arg::new("dry_run").long("dry-run").action(clap::ArgAction::SetTrue).help("Cluster and report only; write no output files"))
+        .arg(Arg::new("strict").long("strict").action(clap::ArgAction::SetTrue).help("Fail on recoverable input problems (e.g. invalid UTF-8) instead of warning"))
+        .arg(Arg::new("mmap").long("mmap").action(clap::ArgAction::SetTrue).help("Memory-map the XML inputs instead of reading them into memory (inputs must not change during the run)"))
+        .arg(Arg::new("timeout").long("timeout").num_args(1).value_name("SECONDS").help("Abort with an error if clustering takes longer than SECONDS"))
+        .arg(Arg::new("second_stage_ptol").long("second-stage-ptol").num_args(1).value_name("S").conflicts_with("jobs").help("Cluster the pivots again as csv_candypicker would, with this absolute period tolerance (s)"))
+        .arg(Arg::new("second_stage_out").long("second-stage-out").num_args(1).value_name("CSV").default_value("pivots_merged.csv").requires("second_stage_ptol").help("Output of the second stage"))
+        .arg(Arg::new("second_stage_dmtol").long("second-stage-dmtol").num_args(1).value_name("DM").requires("second_stage_ptol").help("Optional |ΔDM| gate for the second stage"))
+        .arg(Arg::new("second_stage_acctol").long("second-stage-acctol").num_args(1).value_name("ACC").requires("second_stage_ptol").help("Optional |ΔACC| gate for the second stage"))
+        .arg(Arg::new("second_stage_no_harmonics").long("second-stage-no-harmonics").action(clap::ArgAction::SetTrue).requires("second_stage_ptol").help("Disable harmonic matching in the second stage"))
+        .arg(Arg::new("second_stage_tobs").long("second-stage-tobs").num_args(1).value_name("S").requires("second_stage_ptol").help("TOBS (s) for the second stage's acceleration correction [default: the run's effective tobs]"))
+        .arg(Arg::new("second_stage_period_split").long("second-stage-period-split").action(clap::ArgAction::SetTrue).requires("second_stage_ptol").help("Split the second-stage output by period decade"))
+        .arg(Arg::new("read_retries").long("read-retries").num_args(1).value_name("N").default_value("0").help("Retry input reads up to N times with exponential backoff on transient IO errors"))
+        .arg(Arg::new("estimate").long("estimate").action(clap::ArgAction::SetTrue).conflicts_with_all(["jobs", "json_input"]).help("Parse the inputs, estimate the clustering cost with and without --bin-dm, then exit"))
+        .arg(Arg::new("dump_parsed").long("dump-parsed").num_args(1).value_name("CSV").help("Write every parsed candidate with its extracted fields to CSV, then exit without clustering"))
+        .arg(Arg::new("require_version").long("require-version").num_args(1).value_name("X.Y").help("Fail unless every input's <misc_info><version> is X.Y"))
+        .arg(Arg::new("validate_xml").long("validate-xml").action(clap::ArgAction::SetTrue).help("Only check that the inputs are well-formed and have the required sections, then exit"))
+        .arg(Arg::new("overmerge_warn_frac").long("overmerge-warn-frac").num_args(1).value_name("F").default_value("0.5").help("Warn when the largest cluster holds more than this fraction of all candidates"))
+        .arg(Arg::new("snr_percentile").long("snr-percentile").num_args(1).value_name("P").help("Before clustering drop candidates below the P-th SNR percentile of their own file (files with fewer than 10 candidates keep all)"))
+        .arg(Arg::new("min_acc_spread").long("min-acc-spread").num_args(1).value_name("M_S2").help("Only output pivots whose cluster spans at least this acc range (m/s^2); a binary-pulsar heuristic"))
+        .arg(Arg::new("protect").long("protect").num_args(1).value_name("XML").help("Candidates XML of confirmed sources; a cluster matching one is pivoted on the matching member, whatever its SNR"))
+        .arg(Arg::new("min_related").long("min-related").num_args(1).value_name("K").default_value("0").help("Only output pivots with at least K related candidates; the others are written to the rejected XML"))
         .get_matches();
 
-    let period_thresh: f64 = matches.get_one::<String>("period_thresh").unwrap().parse()?;
-    let dm_thresh: Option<f64> = matches.get_one::<String>("dm_thresh").map(|s| s.parse().unwrap());
     let ncpus: usize = matches.get_one::<String>("ncpus").unwrap().parse()?;
-    let bin_dm: bool = matches.get_flag("bin_dm");
-    let xml_files: Vec<String> = matches.get_many::<String>("xml_files").unwrap().map(|s| s.to_string()).collect();
-    let birdies_vec: Option<Vec<Birdie>> = matches.get_one::<String>("birdies").map(|p| parse_birdies(p)).transpose()?; // Option<Result<_>> -> Result<Option<_>>
-    let birdie_harmonics: u32 = matches.get_one::<String>("birdie_harmonics").unwrap().parse()?;
-    let scale_birdie_width: bool = matches.get_flag("scale_birdie_width");
+    let xml_files: Vec<PathBuf> = matches.get_many::<String>("xml_files").map(|v| v.map(PathBuf::from).collect()).unwrap_or_default();
+    let read_retries: u32 = matches.get_one::<String>("read_retries").unwrap().parse()?;
+    if matches.get_flag("validate_xml") {
+        return validate_xml_files(&xml_files, matches.get_flag("strict"), read_retries);
+    }
+    // -p is only optional with --ptol-auto (which ignores params.period_thresh) and --jobs
+    // (where a job may set it; run_job takes this Option instead).
+    let period_thresh: Option<f64> = matches.get_one::<String>("period_thresh").map(|s| s.parse()).transpose()?;
+    let params = PickParams {
+        period_thresh: period_thresh.unwrap_or_default(),
+        ptol_auto: matches.get_one::<String>("ptol_auto").map(|s| s.parse()).transpose()?,
+        dm_thresh: matches.get_one::<String>("dm_thresh").map(|s| s.parse()).transpose()?,
+        dm_tol_steps: matches.get_one::<String>("dm_tol_steps").map(|s| s.parse()).transpose()?,
+        acc_sign: if matches.get_one::<String>("acc_sign").unwrap() == "neg" { -1.0 } else { 1.0 },
+        acc_reference: match matches.get_one::<String>("acc_reference").unwrap().as_str() {
+            "zero" => AccReference::Zero,
+            "mean" => AccReference::Mean,
+            _ => AccReference::SelfFrame,
+        },
+        bin_dm: matches.get_flag("bin_dm"),
+        max_snr_ratio: matches.get_one::<String>("max_snr_ratio").map(|s| s.parse()).transpose()?,
+        max_modulo_harmonic: matches.get_one::<String>("max_modulo_harmonic").map(|s| s.parse()).transpose()?,
+        birdies: matches.get_one::<String>("birdies").map(parse_birdies).transpose()?, // Option<Result<_>> -> Result<Option<_>>
+        birdie_harmonics: matches.get_one::<String>("birdie_harmonics").unwrap().parse()?,
+        scale_birdie_width: matches.get_flag("scale_birdie_width"),
+        rfi_lines: matches.get_one::<String>("rfi_lines").map(parse_rfi_lines).transpose()?,
+        filter: matches.get_one::<String>("filter").map(|e| CandidateFilter::parse(e)).transpose()?,
+        snr_percentile: matches
+            .get_one::<String>("snr_percentile")
+            .map(|s| {
+                let p: f64 = s.parse()?;
+                if !(0.0..=100.0).contains(&p) {
+                    return Err(anyhow!("--snr-percentile must be between 0 and 100, got {s}"));
+                }
+                Ok(p)
+            })
+            .transpose()?,
+        collapse_period_ms: matches.get_one::<String>("collapse_by_period_ms").map(|s| s.parse()).transpose()?,
+        min_dm_sharpness: matches.get_one::<String>("min_dm_sharpness").map(|s| s.parse()).transpose()?,
+        uuid_prefix_len: matches.get_one::<String>("group_by_uuid_prefix").map(|s| s.parse()).transpose()?,
+        exclude_missing_uuid: matches.get_one::<String>("missing_uuid").unwrap() == "exclude",
+        id_range: matches.get_one::<String>("id_range").map(|s| parse_id_range(s)).transpose()?,
+        veto: matches.get_one::<String>("veto").map(parse_veto).transpose()?,
+        normalize_uuid: matches.get_one::<String>("normalize_uuid").map(|p| UuidNormalizer::parse(p)).transpose()?,
+        prefer_label: matches.get_one::<String>("prefer_label").cloned(),
+        pivot_policy: match matches.get_one::<String>("pivot_policy").unwrap().as_str() {
+            "longest-tobs" => PivotPolicy::LongestTobs,
+            "snr" => PivotPolicy::Priority,
+            _ if matches.get_flag("keep_highest_nassoc_on_tie") => PivotPolicy::Priority,
+            _ => PivotPolicy::InputOrder,
+        },
+        merge_harmonic_clusters: matches.get_flag("merge_harmonic_clusters"),
+        canonicalize_periods: matches.get_flag("canonicalize_periods"),
+        min_related: matches.get_one::<String>("min_related").unwrap().parse()?,
+        protect: matches
+            .get_one::<String>("protect")
+            .map(|p| parse_xml_file(p, matches.get_flag("strict"), read_retries, false).map(|xf| xf.candidates))
+            .transpose()?,
+        report_comparisons: matches.get_flag("report_comparisons"),
+        min_acc_spread: matches.get_one::<String>("min_acc_spread").map(|s| s.parse()).transpose()?,
+        overmerge_warn_frac: matches.get_one::<String>("overmerge_warn_frac").unwrap().parse()?,
+        top_k: matches.get_one::<String>("top_k").map(|s| s.parse()).transpose()?,
+        limit: matches.get_one::<String>("limit").map(|s| s.parse()).transpose()?,
+        seed: matches.get_one::<String>("seed").unwrap().parse()?,
+        sort_by: match matches.get_one::<String>("sort_by").unwrap().as_str() {
+            "snr" => PivotOrder::Snr,
+            "period" => PivotOrder::Period,
+            "cluster-size" => PivotOrder::ClusterSize,
+            _ => PivotOrder::KeepOrder,
+        },
+        strict: matches.get_flag("strict"),
+        require_version: matches.get_one::<String>("require_version").cloned(),
+        mmap: matches.get_flag("mmap"),
+        read_retries,
+        relate_on: if matches.get_one::<String>("relate_on").unwrap() == "frequency" {
+            RelateDomain::Frequency
+        } else {
+            RelateDomain::Period
+        },
+        harmonics: matches.get_flag("harmonics"),
+        hmax: matches.get_one::<String>("hmax").unwrap().parse()?,
+        symmetric_relate: matches.get_flag("symmetric_relate"),
+        timeout: matches
+            .get_one::<String>("timeout")
+            .map(|s| {
+                let secs: f64 = s.parse()?;
+                std::time::Duration::try_from_secs_f64(secs).map_err(|_| anyhow!("--timeout must be a non-negative number of seconds, got {s}"))
+            })
+            .transpose()?,
+    };
+    let provenance = matches.get_flag("embed_provenance").then(|| provenance_line("candy_picker_rs"));
+    let xml_opts = XmlOutputOptions {
+        picked_template: matches.get_one::<String>("picked_template").unwrap(),
+        rejected_template: matches.get_one::<String>("rejected_template").unwrap(),
+        out_dir: matches.get_one::<String>("outdir").map(std::path::Path::new),
+        input_root: matches
+            .get_flag("preserve_tree")
+            .then(|| matches.get_one::<String>("input_root").map(std::path::Path::new).unwrap()),
+        max_rejected: matches.get_one::<String>("max_rejected").map(|s| s.parse()).transpose()?,
+        rejected_sample: if matches.get_one::<String>("rejected_sample").unwrap() == "stratified" {
+            RejectedSample::Stratified
+        } else {
+            RejectedSample::TopN
+        },
+        seed: params.seed,
+        attr_quote: match matches.get_one::<String>("attr_quote").unwrap().as_str() {
+            "single" => Some('\''),
+            "double" => Some('"'),
+            _ => None,
+        },
+    };
+    let verify: bool = matches.get_flag("verify");
+    let no_csv: bool = matches.get_flag("no_csv");
+    let no_xml: bool = matches.get_flag("no_xml");
+    let csv_out = pivots_csv_path(matches.get_one::<String>("csv_out").unwrap(), xml_opts.out_dir);
+    let dry_run: bool = matches.get_flag("dry_run");
+    let distance_matrix: Option<&String> = matches.get_one::<String>("distance_matrix");
+    let force_distance_matrix: bool = matches.get_flag("force_distance_matrix");
+    let cluster_stats: Option<&String> = matches.get_one::<String>("cluster_stats");
+    let all_out: Option<&String> = matches.get_one::<String>("all_out");
+    let graph: Option<&String> = matches.get_one::<String>("graph");
+    let force_graph: bool = matches.get_flag("force_graph");
+    let par_out: Option<&String> = matches.get_one::<String>("par_out");
 
-    println!("[INFO] Settings: period_thresh={period_thresh}, dm_thresh={:?}, workers={ncpus}, bin_dm={bin_dm}", dm_thresh);
+    if let Some(path) = matches.get_one::<String>("dump_parsed") {
+        let files = xml_files
+            .iter()
+            .map(|f| parse_xml_file(&f.to_string_lossy(), params.strict, params.read_retries, params.mmap))
+            .collect::<Result<Vec<_>>>()?;
+        return save_parsed_csv(&files, path);
+    }
+    if matches.get_flag("estimate") {
+        let files = xml_files
+            .iter()
+            .map(|f| parse_xml_file(&f.to_string_lossy(), params.strict, params.read_retries, params.mmap))
+            .collect::<Result<Vec<_>>>()?;
+        print_estimate(&files, &params, ncpus);
+        return Ok(());
+    }
+    println!(
+        "[INFO] Settings: period_thresh={}, ptol_auto={:?}, dm_thresh={:?}, workers={ncpus}, bin_dm={}",
+        params.period_thresh, params.ptol_auto, params.dm_thresh, params.bin_dm
+    );
     rayon::ThreadPoolBuilder::new().num_threads(ncpus).build_global().unwrap();
+    cancel::install_handler()?;
 
-    let mut xml_file_objects = Vec::new();
-    let mut all_candidates = Vec::new();
-    for f in &xml_files {
-        let xf = parse_xml_file(f)?;
-        all_candidates.extend(xf.candidates.clone());
-        xml_file_objects.push(xf);
+    if let Some(path) = matches.get_one::<String>("jobs") {
+        return run_jobs(path, &params, period_thresh, &xml_opts, matches.get_flag("parallel_jobs"), provenance.as_deref());
     }
-    if all_candidates.is_empty() {
-        return Err(anyhow!("No candidates found"));
+
+    let json_input: Option<Vec<PathBuf>> = matches.get_many::<String>("json_input").map(|v| v.map(PathBuf::from).collect());
+    let parsed = match &json_input {
+        Some(files) => {
+            let tobs: f64 = matches.get_one::<String>("json_tobs").unwrap().parse()?;
+            parse_json_inputs(files, tobs, &params)?
+        }
+        None => match parse_inputs(&xml_files, &params) {
+            Err(_) if cancelled() => exit_cancelled("no partial outputs written"),
+            r => r?,
+        },
+    };
+    if let Some(mode) = matches.get_one::<String>("report_field_coverage") {
+        print_field_coverage(&parsed.candidates, mode == "per-file");
+        if dry_run {
+            return Ok(());
+        }
     }
-    if xml_file_objects.len() > 1 {
-        for xf in &xml_file_objects[1..] {
-            if xf.fft_size != xml_file_objects[0].fft_size || xf.tsamp != xml_file_objects[0].tsamp {
-                return Err(anyhow!("fft size and tsamp differ across files"));
-            }
+    let picked = pick_parsed(parsed, &params);
+    let result = match picked {
+        Err(_) if cancelled() => exit_cancelled("no partial outputs written"),
+        r => r?,
+    };
+    if cancelled() {
+        exit_cancelled("no partial outputs written");
+    }
+
+    if dry_run {
+        println!(
+            "[INFO] Dry run: {} candidates, {} pivots; no outputs written.",
+            result.candidates.len(),
+            result.pivots.len()
+        );
+        return Ok(());
+    }
+
+    // Every complete output so far, for the cancellation message.
+    let mut written: Vec<String> = Vec::new();
+    if let Some(path) = distance_matrix {
+        if result.candidates.len() > DISTANCE_MATRIX_MAX_CANDS && !force_distance_matrix {
+            return Err(anyhow!(
+                "--distance-matrix refused for {} candidates (limit {DISTANCE_MATRIX_MAX_CANDS}); pass --force-distance-matrix to override",
+                result.candidates.len()
+            ));
         }
+        let rel = AccelerationRelatedness::from_params(&params, result.period_thresh, result.tobs_over_c);
+        save_distance_matrix(&result.candidates, &rel, path)?;
+        written.push(path.to_string());
     }
-    let effective_tobs = xml_file_objects[0].fft_size as f64 * xml_file_objects[0].tsamp;
-    let tobs_over_c = effective_tobs / SPEED_OF_LIGHT;
-    println!("[INFO] Effective TOBS: {effective_tobs} s");
 
-    cluster_candidates(&mut all_candidates, period_thresh, dm_thresh, tobs_over_c, bin_dm);
-    let pivots = shortlist_candidates(&mut all_candidates, birdies_vec.as_deref(), birdie_harmonics, scale_birdie_width,);
-    save_candidates_csv(&all_candidates, &pivots, "pivots.csv")?;
+    let statuses = match matches.get_one::<String>("baseline") {
+        Some(path) => {
+            let (statuses, unseen) = result.baseline_statuses(&read_baseline_pivots(path)?, &params);
+            let count = |s: PivotStatus| statuses.iter().filter(|&&x| x == s).count();
+            println!(
+                "[INFO] Against baseline {path}: {} new, {} persisted, {} changed; {unseen} baseline pivots not seen",
+                count(PivotStatus::New),
+                count(PivotStatus::Persisted),
+                count(PivotStatus::Changed)
+            );
+            Some(statuses)
+        }
+        None => None,
+    };
+    if !no_csv {
+        let extras = PivotExtras {
+            snr_sum: matches.get_flag("emit_snr_sum"),
+            uuid_raw: params.normalize_uuid.is_some(),
+            galactic: matches.get_flag("emit_galactic").then_some(result.files.as_slice()),
+            status: statuses.as_deref(),
+            fundamental: matches.get_flag("flatten_harmonics").then_some((result.period_thresh, params.hmax)),
+            provenance: provenance.as_deref(),
+        };
+        save_candidates_csv(&result.candidates, &result.pivots, &csv_out, &extras)?;
+        written.push(csv_out.clone());
+    }
+    if let Some(path) = cluster_stats {
+        save_cluster_stats(&result, path)?;
+        written.push(path.to_string());
+    }
+    if let Some(n) = matches.get_one::<String>("global_shortlist") {
+        save_global_shortlist(
+            &result.candidates,
+            &result.pivots,
+            n.parse()?,
+            matches.get_one::<String>("shortlist_by").unwrap() == "snr-sum",
+            matches.get_one::<String>("shortlist_out").unwrap(),
+            provenance.as_deref(),
+        )?;
+        written.push(matches.get_one::<String>("shortlist_out").unwrap().to_string());
+    }
+    if let Some(path) = matches.get_one::<String>("sqlite") {
+        save_sqlite(&result, path, json_input.as_ref().unwrap_or(&xml_files), params.dm_thresh, matches.get_flag("sqlite_all"))?;
+        written.push(path.to_string());
+    }
+    if let Some(path) = matches.get_one::<String>("file_stats") {
+        save_file_stats(&result, path)?;
+        written.push(path.to_string());
+    }
+    if let Some(path) = all_out {
+        save_all_candidates_csv(&result, path, params.normalize_uuid.is_some())?;
+        written.push(path.to_string());
+    }
+    if let Some(path) = graph {
+        if result.candidates.len() > GRAPH_MAX_NODES && !force_graph {
+            return Err(anyhow!(
+                "--graph refused for {} candidates (limit {GRAPH_MAX_NODES}); pass --force-graph to override",
+                result.candidates.len()
+            ));
+        }
+        save_graph(&result, path)?;
+        written.push(path.to_string());
+    }
+    if let Some(dir) = par_out {
+        save_par_files(&result.candidates, &result.pivots, dir)?;
+        written.push(format!("{dir}/*.par"));
+    }
+    if let Some(ptol) = matches.get_one::<String>("second_stage_ptol") {
+        // csv_candypicker on the pivots, without the intermediate pivots.csv.
+        let header: Vec<String> = PIVOTS_HEADER.iter().map(|h| h.to_string()).collect();
+        cluster_csv_rows(
+            &header,
+            &pivot_rows(&result.candidates, &result.pivots),
+            matches.get_one::<String>("second_stage_out").unwrap(),
+            ptol.parse()?,
+            matches.get_one::<String>("second_stage_dmtol").map(|s| s.parse()).transpose()?,
+            matches.get_one::<String>("second_stage_acctol").map(|s| s.parse()).transpose()?,
+            !matches.get_flag("second_stage_no_harmonics"),
+            Some(match matches.get_one::<String>("second_stage_tobs") {
+                Some(t) => t.parse()?,
+                None => result.tobs_over_c * SPEED_OF_LIGHT,
+            }),
+            matches.get_flag("second_stage_period_split"),
+            provenance.as_deref(),
+        )?;
+        written.push(matches.get_one::<String>("second_stage_out").unwrap().to_string());
+    }
 
-    let mut pivot_map: HashMap<(String,i32), bool> = HashMap::new();
-    for &i in &pivots {
-        pivot_map.insert((all_candidates[i].xml_file.clone(), all_candidates[i].candidate_id), true);
+    if no_xml || json_input.is_some() {
+        println!("[INFO] All done.");
+        return Ok(());
     }
-    for xf in &xml_file_objects {
-        write_updated_xmls(xf, &all_candidates, &pivot_map)?;
+    let pivot_map = result.pivot_map();
+    for (k, (xf, cands)) in result.files.iter().zip(result.file_candidates()).enumerate() {
+        if cancelled() {
+            exit_cancelled(&format!(
+                "after writing {k} of {} XML pairs; complete outputs: {}; no partial outputs written",
+                result.files.len(),
+                if written.is_empty() { "none".to_string() } else { written.join(", ") }
+            ));
+        }
+        let (picked, rejected) = write_updated_xmls(xf, &cands, &pivot_map, &xml_opts)?;
+        written.extend([picked.display().to_string(), rejected.display().to_string()]);
+        if verify {
+            verify_split(xf, &picked, &rejected, &params)?;
+        }
     }
     println!("[INFO] All done.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candy_picker_rs::candidates::assign_cluster_ids;
+    use candy_picker_rs::{cluster_candidates, shortlist_candidates};
+
+    fn cand(file: &str, id: i32, snr: f64, period: f64) -> Candidate {
+        Candidate::new(snr, period, 10.0, 0.0, 2, 0.5, 0.8, 0, None, String::new(), file.into(), id, String::new())
+    }
+
+    // Cluster (p = 1 ms, no DM gate), shortlist and number the clusters, as pick_parsed does.
+    fn pick(cands: &mut [Candidate]) -> Vec<usize> {
+        let rel = AccelerationRelatedness::from_params(&PickParams::default(), 1e-3, 0.0);
+        cluster_candidates(cands, &rel, None, false, None).unwrap();
+        let pivots = shortlist_candidates(cands, None, 0, false);
+        assign_cluster_ids(cands, &pivots);
+        pivots
+    }
+
+    // A fresh directory for one test's fixtures and outputs.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("candy_picker_rs_main_test_{}_{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A peasoup XML (tsamp 64 us, fft size 2^23) holding (id, period, snr) candidates at DM 10.
+    fn write_search_xml(dir: &std::path::Path, name: &str, cands: &[(i32, f64, f64)]) -> PathBuf {
+        let blocks: String = cands
+            .iter()
+            .map(|(id, period, snr)| {
+                format!(
+                    "<candidate id='{id}'>\n<period>{period}</period>\n<dm>10.0</dm>\n<acc>0.0</acc>\n<nh>2</nh>\n\
+                     <snr>{snr}</snr>\n</candidate>\n"
+                )
+            })
+            .collect();
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!(
+                "<?xml version='1.0' encoding='ISO-8859-1'?>\n<peasoup_search>\n<header_parameters>\n<tsamp>6.4e-05</tsamp>\n\
+                 </header_parameters>\n<search_parameters>\n<size>8388608</size>\n</search_parameters>\n\
+                 <candidates>\n{blocks}</candidates>\n</peasoup_search>\n"
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    fn xml_opts(out_dir: &std::path::Path) -> XmlOutputOptions<'_> {
+        XmlOutputOptions {
+            picked_template: "{dir}/{stem}_picked.{ext}",
+            rejected_template: "{dir}/{stem}_rejected.{ext}",
+            out_dir: Some(out_dir),
+            input_root: None,
+            max_rejected: None,
+            rejected_sample: RejectedSample::TopN,
+            seed: 0,
+            attr_quote: None,
+        }
+    }
+
+    // One pivots.csv column, by header name.
+    fn column(rows: &[Vec<String>], name: &str) -> Vec<String> {
+        let k = PIVOTS_HEADER.iter().position(|&h| h == name).unwrap();
+        rows.iter().map(|r| r[k].clone()).collect()
+    }
+
+    #[test]
+    fn num_files_counts_distinct_files_of_the_cluster() {
+        let mut cands =
+            vec![cand("a.xml", 0, 10.0, 0.5), cand("a.xml", 1, 8.0, 0.5001), cand("b.xml", 0, 9.0, 0.5002), cand("b.xml", 1, 9.0, 0.3)];
+        let pivots = pick(&mut cands);
+        let rows = pivot_rows(&cands, &pivots);
+        assert_eq!(column(&rows, "candidate_id"), ["0", "1"]);
+        assert_eq!(column(&rows, "xml_file"), ["a.xml", "b.xml"]);
+        assert_eq!(column(&rows, "num_files"), ["2", "1"]);
+    }
+
+    #[test]
+    fn period_ms_keeps_millisecond_fraction() {
+        let mut cands = vec![cand("a.xml", 0, 10.0, 0.0015)];
+        let pivots = pick(&mut cands);
+        let rows = pivot_rows(&cands, &pivots);
+        assert_eq!(column(&rows, "period_ms"), ["1.500000"]);
+        assert_eq!(column(&rows, "period"), ["0.00150000000000000"]);
+    }
+
+    #[test]
+    fn verify_catches_duplicate_candidate_ids() {
+        let dir = test_dir("verify");
+        let params = PickParams { period_thresh: 1e-3, ..Default::default() };
+        let ok = write_search_xml(&dir, "ok.xml", &[(0, 0.5, 10.0), (1, 0.3, 9.0)]);
+        let dup = write_search_xml(&dir, "dup.xml", &[(0, 0.5, 10.0), (0, 0.3, 9.0)]);
+        for (input, duplicated) in [(ok, false), (dup, true)] {
+            let result = pick_candidates(&[input], &params).unwrap();
+            let (xf, cands) = (&result.files[0], &result.file_candidates()[0]);
+            let (picked, rejected) = write_updated_xmls(xf, cands, &result.pivot_map(), &xml_opts(&dir)).unwrap();
+            let verified = verify_split(xf, &picked, &rejected, &params);
+            if duplicated {
+                assert!(verified.unwrap_err().to_string().contains("duplicate candidate id 0"));
+            } else {
+                verified.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn pivots_csv_is_identical_for_any_thread_count() {
+        let dir = test_dir("threads");
+        // Families of near-equal periods and their harmonics, with SNR ties across files.
+        let family = |offset: i32| -> Vec<(i32, f64, f64)> {
+            (0..40).map(|i| (i, 0.01 * f64::from(1 + i % 7) * (1.0 + 1e-5 * f64::from(i + offset)), f64::from(5 + i % 4))).collect()
+        };
+        let inputs = [write_search_xml(&dir, "a.xml", &family(0)), write_search_xml(&dir, "b.xml", &family(3))];
+        let params = PickParams { period_thresh: 1e-3, dm_thresh: Some(1.0), bin_dm: true, ..Default::default() };
+        let csv = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let result = pool.install(|| pick_candidates(&inputs, &params)).unwrap();
+            let path = dir.join(format!("pivots_{threads}.csv"));
+            save_candidates_csv(&result.candidates, &result.pivots, &path.to_string_lossy(), &PivotExtras::default()).unwrap();
+            fs::read(path).unwrap()
+        };
+        assert_eq!(csv(1), csv(8));
+    }
+
+    #[test]
+    fn missing_ratio_and_nassoc_fields_write_empty_cells() {
+        let dir = test_dir("partial");
+        // write_search_xml emits no ddm_count_ratio, ddm_snr_ratio or nassoc.
+        let input = write_search_xml(&dir, "old.xml", &[(0, 0.5, 10.0)]);
+        let params = PickParams { period_thresh: 1e-3, ..Default::default() };
+        let result = pick_candidates(&[input], &params).unwrap();
+        let c = &result.candidates[0];
+        assert!(c.ddm_count_ratio.is_nan() && c.ddm_snr_ratio.is_nan());
+        assert_eq!(c.nassoc, MISSING_NASSOC);
+        let rows = pivot_rows(&result.candidates, &result.pivots);
+        for name in ["ddm_count_ratio", "ddm_snr_ratio", "nassoc", "dm_sharpness"] {
+            assert_eq!(column(&rows, name), [""], "{name}");
+        }
+    }
+
+    #[test]
+    fn double_attr_quote_is_consistent_across_the_document() {
+        let dir = test_dir("attr_quote");
+        // Candidate blocks are sliced verbatim with peasoup's single-quoted ids.
+        let input = write_search_xml(&dir, "q.xml", &[(0, 0.5, 10.0), (1, 0.5001, 9.0), (2, 0.5002, 8.0)]);
+        let params = PickParams { period_thresh: 1e-3, ..Default::default() };
+        let result = pick_candidates(&[input], &params).unwrap();
+        let opts = XmlOutputOptions { attr_quote: Some('"'), ..xml_opts(&dir) };
+        let (picked, rejected) =
+            write_updated_xmls(&result.files[0], &result.file_candidates()[0], &result.pivot_map(), &opts).unwrap();
+        for (path, id) in [(picked, "0"), (rejected, "1")] {
+            let xml = fs::read_to_string(path).unwrap();
+            assert!(!xml.contains("='"), "{xml}");
+            assert!(xml.contains(&format!("<candidate id=\"{id}\">")), "{xml}");
+        }
+    }
+
+    #[test]
+    fn file_stats_has_one_row_per_input() {
+        let dir = test_dir("file_stats");
+        let inputs = [
+            write_search_xml(&dir, "a.xml", &[(0, 0.5, 10.0), (1, 0.3, 9.0)]),
+            write_search_xml(&dir, "b.xml", &[(0, 0.5001, 8.0)]),
+            write_search_xml(&dir, "c.xml", &[(0, 0.7, 7.0), (1, 0.11, 6.0), (2, 0.13, 5.0), (3, 0.5002, 4.0)]),
+        ];
+        let params = PickParams { period_thresh: 1e-3, ..Default::default() };
+        let result = pick_candidates(&inputs, &params).unwrap();
+        let path = dir.join("stats.csv");
+        save_file_stats(&result, &path.to_string_lossy()).unwrap();
+        let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&path).unwrap().records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), inputs.len());
+        let counts: Vec<(&str, &str)> = rows.iter().map(|r| (&r[1], &r[11])).collect();
+        assert_eq!(counts, [("2", "2"), ("1", "0"), ("4", "3")]);
+    }
+
+    #[test]
+    fn vetoed_candidates_are_absent_from_all_outputs() {
+        let dir = test_dir("veto");
+        // Candidate 1 would be the pivot of the 0.5 s cluster.
+        let input = write_search_xml(&dir, "v.xml", &[(0, 0.5, 10.0), (1, 0.5001, 12.0), (2, 0.3, 9.0)]);
+        let veto_csv = dir.join("veto.csv");
+        fs::write(&veto_csv, "xml_file,candidate_id\nv.xml,1\n").unwrap();
+        let params = PickParams { period_thresh: 1e-3, veto: Some(parse_veto(&veto_csv).unwrap()), ..Default::default() };
+        let result = pick_candidates(&[input], &params).unwrap();
+        assert!(result.candidates.iter().chain(&result.removed).all(|c| c.candidate_id != 1));
+
+        let rows = pivot_rows(&result.candidates, &result.pivots);
+        assert_eq!(column(&rows, "candidate_id"), ["0", "2"]);
+        assert_eq!(column(&rows, "num_related"), ["0", "0"]);
+        let (picked, rejected) =
+            write_updated_xmls(&result.files[0], &result.file_candidates()[0], &result.pivot_map(), &xml_opts(&dir)).unwrap();
+        for path in [picked, rejected] {
+            assert!(!fs::read_to_string(path).unwrap().contains("id='1'"));
+        }
+    }
+
+    #[test]
+    fn fundamental_of_a_third_harmonic_pivot() {
+        // The 0.1 s pivot is the 3rd harmonic of the weaker 0.3 s member; 0.77 s stands alone.
+        let mut cands =
+            vec![cand("a.xml", 0, 20.0, 0.1), cand("a.xml", 1, 8.0, 0.30002), cand("a.xml", 2, 9.0, 0.77), cand("b.xml", 0, 12.0, 0.10001)];
+        let pivots = pick(&mut cands);
+        assert_eq!(pivots, [0, 2]);
+        assert!((fundamental_period(&cands, &cands[0], 1e-3, 16) - 0.3).abs() < 1e-12);
+        assert_eq!(fundamental_period(&cands, &cands[2], 1e-3, 16), 0.77);
+        // --hmax 2 rules out k = 3.
+        assert_eq!(fundamental_period(&cands, &cands[0], 1e-3, 2), 0.1);
+    }
+
+    #[test]
+    fn global_shortlist_ranks_and_truncates() {
+        let dir = test_dir("shortlist");
+        let mut cands = vec![
+            cand("a.xml", 0, 10.0, 0.3),
+            cand("a.xml", 1, 8.0, 0.5),
+            cand("b.xml", 0, 7.0, 0.5001),
+            cand("c.xml", 0, 7.0, 0.5002),
+            cand("b.xml", 1, 9.0, 0.7),
+            cand("c.xml", 1, 5.0, 0.11),
+        ];
+        let pivots = pick(&mut cands);
+        assert_eq!(pivots.len(), 4);
+        let shortlist = |by_snr_sum: bool| {
+            let path = dir.join(format!("shortlist_{by_snr_sum}.csv"));
+            save_global_shortlist(&cands, &pivots, 2, by_snr_sum, &path.to_string_lossy(), None).unwrap();
+            let mut rdr = csv::Reader::from_path(&path).unwrap();
+            rdr.records().map(Result::unwrap).map(|r| (r[0].to_string(), r[1].to_string())).collect::<Vec<_>>()
+        };
+        // (rank, snr): by SNR the 10 and 9 pivots; by quadrature sum the 8 pivot (sqrt(162)) leads.
+        assert_eq!(shortlist(false), [("1".into(), "10".into()), ("2".into(), "9".into())]);
+        assert_eq!(shortlist(true), [("1".into(), "8".into()), ("2".into(), "10".into())]);
+    }
+
+    #[test]
+    fn distance_matrix_related_column_honours_symmetric_relate() {
+        let dir = test_dir("distance_matrix");
+        let one_way = AccelerationRelatedness::from_params(&PickParams::default(), 1e-6, 600.0 / SPEED_OF_LIGHT);
+        // A 0.5 s signal seen at 0 and at 100 m/s^2, related in b's frame only.
+        let x = 100.0 * one_way.tobs_over_c;
+        let (a, b) = (-30..=30)
+            .map(|k| {
+                let mut b = cand("a.xml", 1, 9.0, 0.5 * (1.0 - x) + f64::from(k) * 1e-7);
+                b.acc = 100.0;
+                (cand("a.xml", 0, 10.0, 0.5), b)
+            })
+            .find(|(a, b)| !one_way.related(a, b) && one_way.related(b, a))
+            .expect("a pair related in one direction only");
+        let related = |rel: &AccelerationRelatedness| {
+            let path = dir.join("distances.csv");
+            save_distance_matrix(&[a.clone(), b.clone()], rel, &path.to_string_lossy()).unwrap();
+            let mut rdr = csv::Reader::from_path(&path).unwrap();
+            rdr.records().map(|r| r.unwrap()[7].to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(related(&one_way), ["false"]);
+        assert_eq!(related(&AccelerationRelatedness { symmetric: true, ..one_way }), ["true"]);
+    }
+}
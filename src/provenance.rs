@@ -0,0 +1,87 @@
+// src/provenance.rs
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+// The comment line written above the header with --embed-provenance. Readers only skip a
+// line with exactly this prefix, so a header whose first name starts with `#` (e.g.
+// `#period,dm,snr`) is still read as the header.
+pub const PROVENANCE_PREFIX: &str = "# provenance:";
+
+// `# provenance: tool=<tool> version=<crate version> time=<UTC ISO 8601> command=<argv>`
+// for the running process. The command line comes last since it contains spaces;
+// arguments with whitespace or quotes are single-quoted as a POSIX shell would need.
+pub fn provenance_line(tool: &str) -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let command: Vec<String> = std::env::args().map(|a| shell_quote(&a)).collect();
+    format!(
+        "{PROVENANCE_PREFIX} tool={tool} version={} time={} command={}",
+        env!("CARGO_PKG_VERSION"),
+        utc_timestamp(secs),
+        command.join(" ")
+    )
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg.chars().all(|c| !c.is_whitespace() && !matches!(c, '\'' | '"' | '\\' | '$' | '`' | '*' | '?' | ';' | '&' | '|'));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+// Seconds since the epoch as `YYYY-MM-DDTHH:MM:SSZ` (proleptic Gregorian, days-to-civil).
+fn utc_timestamp(secs: u64) -> String {
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+// Create `path` for a CSV writer, first writing `provenance` (a `provenance_line`) on
+// its own line when given.
+pub fn create_csv_writer<P: AsRef<Path>>(path: P, provenance: Option<&str>) -> Result<csv::Writer<File>> {
+    let path = path.as_ref();
+    let mut file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    if let Some(line) = provenance {
+        writeln!(file, "{line}")?;
+    }
+    Ok(csv::Writer::from_writer(file))
+}
+
+// Consume a leading provenance line from `reader`, returning it (without the newline).
+// Anything else, including a `#`-prefixed header, is left unread.
+pub fn skip_provenance<R: BufRead>(reader: &mut R) -> std::io::Result<Option<String>> {
+    if !reader.fill_buf()?.starts_with(PROVENANCE_PREFIX.as_bytes()) {
+        return Ok(None);
+    }
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// Open a CSV for reading positioned after any leading provenance line.
+pub fn open_csv<P: AsRef<Path>>(path: P) -> Result<std::io::BufReader<File>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut reader = std::io::BufReader::new(file);
+    skip_provenance(&mut reader)?;
+    Ok(reader)
+}
@@ -0,0 +1,48 @@
+// src/retry.rs
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const BASE_BACKOFF_MS: u64 = 100;
+
+// EIO, ETIMEDOUT and friends from flaky network filesystems; not-found, permission and
+// parse errors are never retried.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) || matches!(e.raw_os_error(), Some(5) | Some(110)) // EIO, ETIMEDOUT
+}
+
+// `op` with up to `retries` extra attempts on transient IO errors, waiting
+// 100 ms, 200 ms, 400 ms, ... between attempts.
+fn with_retries<T>(path: &Path, retries: u32, op: impl Fn(&Path) -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op(path) {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                let wait = BASE_BACKOFF_MS << (attempt - 1).min(16);
+                eprintln!(
+                    "[WARN] Transient error reading {} ({e}); retry {attempt}/{retries} in {wait} ms",
+                    path.display()
+                );
+                thread::sleep(Duration::from_millis(wait));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// fs::read with retries as above. retries = 0 is a plain fs::read.
+pub fn read_with_retries<P: AsRef<Path>>(path: P, retries: u32) -> io::Result<Vec<u8>> {
+    with_retries(path.as_ref(), retries, |p| std::fs::read(p))
+}
+
+// File::open with retries, for inputs read incrementally. Only opening is retried; errors
+// in later reads surface to the caller.
+pub fn open_with_retries<P: AsRef<Path>>(path: P, retries: u32) -> io::Result<std::fs::File> {
+    with_retries(path.as_ref(), retries, |p| std::fs::File::open(p))
+}